@@ -4,6 +4,8 @@ uniform vec3 u_LightPosition_WorldSpace;
 uniform vec3 u_LightColor;
 uniform float u_LightPower;
 uniform sampler2D u_TextureDiffuse;
+// rgb is the color, a is how strongly to blend it in.
+uniform vec4 u_DebugColor;
 
 in vec3 out_ColorAmbient;
 in vec3 out_ColorDiffuse;
@@ -36,4 +38,5 @@ void main() {
             out_ColorDiffuse * u_LightColor * u_LightPower * cosTheta / (distance * distance)
         ) +
         out_ColorSpecular * u_LightColor * u_LightPower * pow(cosAlpha, out_SpecularExponent) / (distance * distance);
+    color = mix(color, u_DebugColor.rgb * (0.3 + 0.7 * cosTheta), u_DebugColor.a);
 }
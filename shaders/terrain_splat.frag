@@ -0,0 +1,54 @@
+#version 410
+
+uniform vec3 u_LightPosition_WorldSpace;
+uniform vec3 u_LightColor;
+uniform float u_LightPower;
+uniform sampler2D u_TextureDiffuse;
+uniform sampler2DArray u_DetailTextures;
+uniform int u_LayerCount;
+// rgb is the color, a is how strongly to blend it in.
+uniform vec4 u_DebugColor;
+
+in vec3 out_ColorAmbient;
+in vec3 out_ColorDiffuse;
+in vec3 out_ColorSpecular;
+in float out_SpecularExponent;
+in vec3 out_VertexPosition_WorldSpace;
+in vec3 out_EyeDirection_CameraSpace;
+in vec3 out_LightDirection_CameraSpace;
+in vec3 out_VertexNormal_CameraSpace;
+in vec2 out_VertexUv;
+in vec2 out_DetailUv;
+in vec4 out_SplatWeights0;
+in vec4 out_SplatWeights1;
+
+out vec3 color;
+
+void main() {
+    vec3 normal_VertexNormal = normalize(out_VertexNormal_CameraSpace);
+    vec3 normal_LightDirection = normalize(out_LightDirection_CameraSpace);
+    vec3 normal_EyeDirection = normalize(out_EyeDirection_CameraSpace);
+    float weights[8] = float[8](
+        out_SplatWeights0.x, out_SplatWeights0.y, out_SplatWeights0.z, out_SplatWeights0.w,
+        out_SplatWeights1.x, out_SplatWeights1.y, out_SplatWeights1.z, out_SplatWeights1.w);
+    vec3 detail = vec3(0, 0, 0);
+    for (int i = 0; i < u_LayerCount; i++) {
+        detail += weights[i] * texture(u_DetailTextures, vec3(out_DetailUv, float(i))).rgb;
+    }
+    vec3 texture_ColorDiffuse = detail * texture(u_TextureDiffuse, out_VertexUv).rgb;
+
+    float cosTheta = clamp(dot(normal_VertexNormal, normal_LightDirection), 0, 1);
+
+    vec3 normal_Reflect_EyeDirection = reflect(-normal_LightDirection, normal_VertexNormal);
+    float cosAlpha = clamp(dot(normal_EyeDirection, normal_Reflect_EyeDirection), 0, 1);
+
+    float distance = length(u_LightPosition_WorldSpace - out_VertexPosition_WorldSpace);
+    color =
+        // Might make more sense to only multiply by the diffuse, per http://paulbourke.net/dataformats/mtl/ under map_Kd.
+        texture_ColorDiffuse * (
+            out_ColorAmbient +
+            out_ColorDiffuse * u_LightColor * u_LightPower * cosTheta / (distance * distance)
+        ) +
+        out_ColorSpecular * u_LightColor * u_LightPower * pow(cosAlpha, out_SpecularExponent) / (distance * distance);
+    color = mix(color, u_DebugColor.rgb * (0.3 + 0.7 * cosTheta), u_DebugColor.a);
+}
@@ -0,0 +1,57 @@
+#version 410
+
+uniform mat4 u_MatMvp;
+uniform mat4 u_MatV;
+uniform mat4 u_MatM;
+uniform vec3 u_LightPosition_WorldSpace;
+// Detail texture repeats per world unit.
+uniform float u_DetailScale;
+
+in vec3 in_VertexPosition;
+in vec3 in_VertexNormal;
+in vec2 in_VertexUv;
+in vec3 in_ColorAmbient;
+in vec3 in_ColorDiffuse;
+in vec3 in_ColorSpecular;
+in float in_SpecularExponent;
+// How much of each detail texture to use, layers 0-3 then 4-7.
+in vec4 in_SplatWeights0;
+in vec4 in_SplatWeights1;
+
+out vec3 out_ColorAmbient;
+out vec3 out_ColorDiffuse;
+out vec3 out_ColorSpecular;
+out float out_SpecularExponent;
+out vec3 out_VertexPosition_WorldSpace;
+out vec3 out_EyeDirection_CameraSpace;
+out vec3 out_LightDirection_CameraSpace;
+out vec3 out_VertexNormal_CameraSpace;
+out vec2 out_VertexUv;
+out vec2 out_DetailUv;
+out vec4 out_SplatWeights0;
+out vec4 out_SplatWeights1;
+
+void main() {
+    gl_Position = u_MatMvp * vec4(in_VertexPosition, 1.0);
+
+    out_VertexPosition_WorldSpace = (u_MatM * vec4(in_VertexPosition, 1.0)).xyz;
+
+    vec3 VertexPosition_CameraSpace = (u_MatV * u_MatM * vec4(in_VertexPosition, 1)).xyz;
+	  out_EyeDirection_CameraSpace = vec3(0, 0, 0) - VertexPosition_CameraSpace;
+
+    vec3 LightPosition_CameraSpace = (u_MatV* vec4(u_LightPosition_WorldSpace, 1)).xyz;
+	  out_LightDirection_CameraSpace = LightPosition_CameraSpace + out_EyeDirection_CameraSpace;
+
+    // N.B.: Not correct if scaling is in use.
+    out_VertexNormal_CameraSpace = (u_MatV * u_MatM * vec4(in_VertexNormal, 0)).xyz;
+
+    out_VertexUv = in_VertexUv;
+    // Tiled in world space, so the detail is the same size everywhere regardless of the mesh.
+    out_DetailUv = out_VertexPosition_WorldSpace.xz * u_DetailScale;
+    out_SplatWeights0 = in_SplatWeights0;
+    out_SplatWeights1 = in_SplatWeights1;
+    out_ColorAmbient = in_ColorAmbient;
+    out_ColorDiffuse = in_ColorDiffuse;
+    out_ColorSpecular = in_ColorSpecular;
+    out_SpecularExponent = in_SpecularExponent;
+}
@@ -0,0 +1,71 @@
+#version 410
+
+// phong.vert, with the surface moved by waves. Pairs with phong.frag.
+
+uniform mat4 u_MatMvp;
+uniform mat4 u_MatV;
+uniform mat4 u_MatM;
+uniform vec3 u_LightPosition_WorldSpace;
+uniform float u_Time;
+uniform float u_WaveHeight;
+uniform float u_WaveLength;
+uniform float u_WaveSpeed;
+
+in vec3 in_VertexPosition;
+in vec3 in_VertexNormal;
+in vec2 in_VertexUv;
+in vec3 in_ColorAmbient;
+in vec3 in_ColorDiffuse;
+in vec3 in_ColorSpecular;
+in float in_SpecularExponent;
+
+out vec3 out_ColorAmbient;
+out vec3 out_ColorDiffuse;
+out vec3 out_ColorSpecular;
+out float out_SpecularExponent;
+out vec3 out_VertexPosition_WorldSpace;
+out vec3 out_EyeDirection_CameraSpace;
+out vec3 out_LightDirection_CameraSpace;
+out vec3 out_VertexNormal_CameraSpace;
+out vec2 out_VertexUv;
+
+// Two sine waves crossing at an angle. Returns the height offset at `position`, and its slope along x and z.
+// The second is 1.7 times shorter than the first; src/terrain/coast.rs sizes the sheet's grid to suit it.
+float waves(vec2 position, out vec2 slope) {
+    const vec2 first = vec2(0.958, 0.287);
+    const vec2 second = vec2(-0.371, 0.928);
+    float k = 6.2831853 / u_WaveLength;
+
+    float phase_first = k * (dot(first, position) - u_WaveSpeed * u_Time);
+    float phase_second = 1.7 * k * (dot(second, position) - 0.8 * u_WaveSpeed * u_Time);
+    float height_second = 0.5 * u_WaveHeight;
+
+    slope = u_WaveHeight * k * cos(phase_first) * first + height_second * 1.7 * k * cos(phase_second) * second;
+    return u_WaveHeight * sin(phase_first) + height_second * sin(phase_second);
+}
+
+void main() {
+    vec2 slope;
+    vec3 position = in_VertexPosition + vec3(0, waves(in_VertexPosition.xz, slope), 0);
+    // The sheet is flat, so tilting its normal by the slope gives the wave's.
+    vec3 normal = normalize(in_VertexNormal + vec3(-slope.x, 0, -slope.y));
+
+    gl_Position = u_MatMvp * vec4(position, 1.0);
+
+    out_VertexPosition_WorldSpace = (u_MatM * vec4(position, 1.0)).xyz;
+
+    vec3 VertexPosition_CameraSpace = (u_MatV * u_MatM * vec4(position, 1)).xyz;
+    out_EyeDirection_CameraSpace = vec3(0, 0, 0) - VertexPosition_CameraSpace;
+
+    vec3 LightPosition_CameraSpace = (u_MatV * vec4(u_LightPosition_WorldSpace, 1)).xyz;
+    out_LightDirection_CameraSpace = LightPosition_CameraSpace + out_EyeDirection_CameraSpace;
+
+    // N.B.: Not correct if scaling is in use.
+    out_VertexNormal_CameraSpace = (u_MatV * u_MatM * vec4(normal, 0)).xyz;
+
+    out_VertexUv = in_VertexUv;
+    out_ColorAmbient = in_ColorAmbient;
+    out_ColorDiffuse = in_ColorDiffuse;
+    out_ColorSpecular = in_ColorSpecular;
+    out_SpecularExponent = in_SpecularExponent;
+}
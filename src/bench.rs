@@ -0,0 +1,74 @@
+use std::time::{ Duration, Instant };
+
+use terrain::Heightmap;
+use terrain::erosion::{ thermal_erode, ThermalErosionSettings };
+use terrain::noise::{ heightmap_from_noise_parallel, Fractal, FractalSettings };
+use terrain::parallel;
+
+const THERMAL_ITERATIONS: u32 = 10;
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+fn time<T, F: FnOnce() -> T>(f: F) -> (T, f64) {
+    let start = Instant::now();
+    let result = f();
+    (result, seconds(start.elapsed()))
+}
+
+fn same_bits(a: &Heightmap, b: &Heightmap) -> bool {
+    a.heights().iter().zip(b.heights().iter()).all(|(x, y)| x.to_bits() == y.to_bits())
+}
+
+// 1, 2, 4, ... up to the number of cores, plus the number of cores itself if it isn't a power of two.
+fn thread_counts() -> Vec<usize> {
+    let cores = parallel::default_threads();
+    let mut counts = vec![];
+    let mut count = 1;
+    while count < cores {
+        counts.push(count);
+        count *= 2;
+    }
+    counts.push(cores);
+    counts
+}
+
+// Times noise generation and thermal erosion over a size x size grid at every thread count, and checks that each
+// matches the single-threaded result bit for bit.
+pub fn run(size: usize) {
+    let noise = Fractal::new(FractalSettings::default());
+    println!("{}x{} grid, {} thermal erosion iterations", size, size, THERMAL_ITERATIONS);
+    println!("{:>8} {:>12} {:>8} {:>12} {:>8} {:>10}",
+        "threads", "noise (s)", "speedup", "thermal (s)", "speedup", "identical");
+
+    // The single-threaded noise and eroded heightmaps, and how long each took.
+    let mut baseline: Option<(Heightmap, Heightmap, f64, f64)> = None;
+    for threads in thread_counts() {
+        let (generated, noise_time) = time(|| {
+            heightmap_from_noise_parallel(&noise, size, size, 1.0, (0.0, 0.0), 20.0, threads)
+        });
+        let mut eroded = generated.clone();
+        let settings = ThermalErosionSettings {
+            iterations: THERMAL_ITERATIONS,
+            threads: threads,
+            ..Default::default()
+        };
+        let (_, thermal_time) = time(|| thermal_erode(&mut eroded, &settings));
+
+        let (identical, noise_speedup, thermal_speedup) = match baseline {
+            Some((ref base_generated, ref base_eroded, base_noise, base_thermal)) => (
+                same_bits(base_generated, &generated) && same_bits(base_eroded, &eroded),
+                base_noise / noise_time,
+                base_thermal / thermal_time,
+            ),
+            None => (true, 1.0, 1.0),
+        };
+        println!("{:>8} {:>12.3} {:>7.2}x {:>12.3} {:>7.2}x {:>10}",
+            threads, noise_time, noise_speedup, thermal_time, thermal_speedup, if identical { "yes" } else { "NO" });
+
+        if baseline.is_none() {
+            baseline = Some((generated, eroded, noise_time, thermal_time));
+        }
+    }
+}
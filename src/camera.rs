@@ -9,6 +9,7 @@ pub struct Camera {
     azimuth: f32,
     inclination: f32,
     field_of_view: f32,
+    far_plane: f32,
 }
 
 pub enum TranslateDirection {
@@ -29,9 +30,22 @@ impl Camera {
             azimuth: PI, // Look at -Z (into the screen).
             inclination: FRAC_PI_2, // Look at the horizon.
             field_of_view: FRAC_PI_4,
+            far_plane: 100.0,
         }
     }
 
+    pub fn position(&self) -> glm::Vec3 {
+        self.pos
+    }
+
+    pub fn set_position(&mut self, pos: glm::Vec3) {
+        self.pos = pos;
+    }
+
+    pub fn set_far_plane(&mut self, far_plane: f32) {
+        self.far_plane = far_plane;
+    }
+
     pub fn look(&mut self, dir: LookDirection, amount: f32) {
         match dir {
             LookDirection::Vertical   => {
@@ -52,7 +66,7 @@ impl Camera {
         )
     }
 
-    fn right(&self) -> glm::Vec3 {
+    pub fn right(&self) -> glm::Vec3 {
         let rotated_azimuth = self.azimuth - FRAC_PI_2;
         glm::vec3(
             rotated_azimuth.sin(),
@@ -61,6 +75,11 @@ impl Camera {
         )
     }
 
+    // The direction with its vertical component dropped, for moving along the ground.
+    pub fn heading(&self) -> glm::Vec3 {
+        glm::vec3(self.azimuth.sin(), 0.0, self.azimuth.cos())
+    }
+
     pub fn up(&self) -> glm::Vec3 {
         glm::cross(self.right(), self.direction())
     }
@@ -80,7 +99,7 @@ impl Camera {
     }
 
     pub fn projection_mat(&self, aspect_ratio: f32) -> glm::Mat4 {
-        glm::ext::perspective(self.field_of_view, aspect_ratio, 0.1, 100.0)
+        glm::ext::perspective(self.field_of_view, aspect_ratio, 0.1, self.far_plane)
     }
 
     pub fn view_mat(&self) -> glm::Mat4 {
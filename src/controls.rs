@@ -1,5 +1,7 @@
 use glfw;
+use glm;
 use camera;
+use terrain;
 
 const LOOK_SPEED: f32 = 0.05;
 const MOVE_SPEED: f32 = 6.0;
@@ -17,7 +19,20 @@ pub fn init_window_controls(window: &mut glfw::Window) {
     window.set_cursor_pos(half_width, half_height);
 }
 
-pub fn move_camera_from_inputs(camera: &mut camera::Camera, window: &mut glfw::Window, delta_t: f32) {
+fn is_pressed(window: &glfw::Window, key: glfw::Key) -> bool {
+    window.get_key(key) == glfw::Action::Press
+}
+
+// There is a concept of "modifiers", but that's for event pushing, not for state polling, so we check both shift keys.
+fn is_fast(window: &glfw::Window) -> bool {
+    is_pressed(window, glfw::Key::LeftShift) || is_pressed(window, glfw::Key::RightShift)
+}
+
+pub fn is_control_held(window: &glfw::Window) -> bool {
+    is_pressed(window, glfw::Key::LeftControl) || is_pressed(window, glfw::Key::RightControl)
+}
+
+fn look_from_mouse(camera: &mut camera::Camera, window: &mut glfw::Window, delta_t: f32) {
     let (half_width, half_height) = get_half_dimensions(window);
 
     let (mouse_x, mouse_y) = window.get_cursor_pos();
@@ -25,30 +40,201 @@ pub fn move_camera_from_inputs(camera: &mut camera::Camera, window: &mut glfw::W
 
     camera.look(camera::LookDirection::Horizontal, LOOK_SPEED * delta_t * (half_width - mouse_x) as f32);
     camera.look(camera::LookDirection::Vertical, LOOK_SPEED * delta_t * (half_height - mouse_y) as f32);
+}
+
+// Frees the cursor for pointing at things while editing, or captures it again for mouse look.
+pub fn set_cursor_captured(window: &mut glfw::Window, captured: bool) {
+    if captured {
+        init_window_controls(window);
+    } else {
+        window.set_cursor_mode(glfw::CursorMode::Normal);
+    }
+}
 
-    // There is a concept of "modifiers", but that's for event pushing, not for state polling, so we check both shift keys.
-    let speed =
-        if window.get_key(glfw::Key::LeftShift) == glfw::Action::Press || window.get_key(glfw::Key::RightShift) == glfw::Action::Press
-        { MOVE_SPEED_FAST }
-        else
-        { MOVE_SPEED };
+// The world-space ray from the camera through the cursor, as (origin, direction).
+pub fn cursor_ray(camera: &camera::Camera, window: &glfw::Window, aspect_ratio: f32) -> (glm::Vec3, glm::Vec3) {
+    let (width, height) = window.get_size();
+    let (mouse_x, mouse_y) = window.get_cursor_pos();
+    let ndc_x = (2.0 * mouse_x / width as f64 - 1.0) as f32;
+    let ndc_y = (1.0 - 2.0 * mouse_y / height as f64) as f32;
 
-    if window.get_key(glfw::Key::W) == glfw::Action::Press {
+    let inverse = glm::inverse(&(camera.projection_mat(aspect_ratio) * camera.view_mat()));
+    let unproject = |depth: f32| {
+        let point = inverse * glm::vec4(ndc_x, ndc_y, depth, 1.0);
+        glm::vec3(point.x / point.w, point.y / point.w, point.z / point.w)
+    };
+    let near = unproject(-1.0);
+    let far = unproject(1.0);
+    (near, glm::normalize(far - near))
+}
+
+pub fn move_camera_from_inputs(camera: &mut camera::Camera, window: &mut glfw::Window, delta_t: f32) {
+    look_from_mouse(camera, window, delta_t);
+    move_camera_from_keys(camera, window, delta_t);
+}
+
+// Flying without mouse look, for when the cursor is free.
+pub fn move_camera_from_keys(camera: &mut camera::Camera, window: &glfw::Window, delta_t: f32) {
+    let speed = if is_fast(window) { MOVE_SPEED_FAST } else { MOVE_SPEED };
+
+    if is_pressed(window, glfw::Key::W) {
         camera.translate(camera::TranslateDirection::Forward, delta_t * speed);
     }
-    if window.get_key(glfw::Key::S) == glfw::Action::Press {
+    if is_pressed(window, glfw::Key::S) {
         camera.translate(camera::TranslateDirection::Forward, -delta_t * speed);
     }
-    if window.get_key(glfw::Key::A) == glfw::Action::Press {
+    if is_pressed(window, glfw::Key::A) {
         camera.translate(camera::TranslateDirection::Side, -delta_t * speed);
     }
-    if window.get_key(glfw::Key::D) == glfw::Action::Press {
+    if is_pressed(window, glfw::Key::D) {
         camera.translate(camera::TranslateDirection::Side, delta_t * speed);
     }
-    if window.get_key(glfw::Key::R) == glfw::Action::Press {
+    if is_pressed(window, glfw::Key::R) {
         camera.translate(camera::TranslateDirection::Altitude, delta_t * speed);
     }
-    if window.get_key(glfw::Key::F) == glfw::Action::Press {
+    if is_pressed(window, glfw::Key::F) {
         camera.translate(camera::TranslateDirection::Altitude, -delta_t * speed);
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct WalkSettings {
+    // Of the camera above the ground, in world units.
+    pub eye_height: f32,
+    pub gravity: f32,
+    // Upward speed at the start of a jump.
+    pub jump_speed: f32,
+    // Steepest slope, in degrees, that can be walked up. Anything can be walked (or fallen) down.
+    pub max_slope: f32,
+    pub speed: f32,
+    pub speed_fast: f32,
+}
+
+impl Default for WalkSettings {
+    fn default() -> WalkSettings {
+        WalkSettings {
+            eye_height: 1.7,
+            gravity: 20.0,
+            jump_speed: 7.0,
+            max_slope: 40.0,
+            speed: 4.0,
+            speed_fast: 9.0,
+        }
+    }
+}
+
+// State for walking on a terrain between frames: how fast the camera is moving vertically, and whether it's standing.
+#[derive(Debug, Clone)]
+pub struct Walker {
+    settings: WalkSettings,
+    vertical_speed: f32,
+    grounded: bool,
+}
+
+impl Walker {
+    pub fn new(settings: WalkSettings) -> Walker {
+        Walker {
+            settings: settings,
+            vertical_speed: 0.0,
+            grounded: false,
+        }
+    }
+
+    fn ground_at(terrain: &terrain::RenderableTerrain, x: f32, z: f32) -> f32 {
+        terrain.height_at(x, z, terrain::query::Interpolation::Bilinear)
+    }
+
+    // Whether the feet can move from `from` to `to`. On the ground, they can't climb anything steeper than the maximum
+    // slope; in the air, they can't move over ground higher than they are, so cliffs can't be climbed by jumping.
+    fn can_step(&self, terrain: &terrain::RenderableTerrain, from: glm::Vec3, to: glm::Vec3) -> bool {
+        let run = glm::length(glm::vec3(to.x - from.x, 0.0, to.z - from.z));
+        if run == 0.0 {
+            return true;
+        }
+        let ground = Walker::ground_at(terrain, to.x, to.z);
+        if !self.grounded {
+            return ground <= from.y;
+        }
+        let rise = ground - Walker::ground_at(terrain, from.x, from.z);
+        rise / run <= self.settings.max_slope.to_radians().tan()
+    }
+}
+
+// Walks the camera over `terrain`: WASD moves along the ground, space jumps, and gravity does the rest. The camera
+// never goes below eye height above the surface, nor off its edges.
+pub fn walk_camera_from_inputs(
+    camera: &mut camera::Camera,
+    window: &mut glfw::Window,
+    delta_t: f32,
+    walker: &mut Walker,
+    terrain: &terrain::RenderableTerrain
+) {
+    look_from_mouse(camera, window, delta_t);
+
+    let forward = camera.heading();
+    let right = camera.right();
+    let mut direction = glm::vec3(0.0, 0.0, 0.0);
+    if is_pressed(window, glfw::Key::W) {
+        direction = direction + forward;
+    }
+    if is_pressed(window, glfw::Key::S) {
+        direction = direction - forward;
+    }
+    if is_pressed(window, glfw::Key::A) {
+        direction = direction - right;
+    }
+    if is_pressed(window, glfw::Key::D) {
+        direction = direction + right;
+    }
+
+    let feet = camera.position() - glm::vec3(0.0, walker.settings.eye_height, 0.0);
+    let mut next = feet;
+    if glm::length(direction) > 0.0 {
+        let speed = if is_fast(window) { walker.settings.speed_fast } else { walker.settings.speed };
+        let step = glm::normalize(direction) * speed * delta_t;
+
+        // Clamped to the terrain's edges.
+        let origin = terrain.origin();
+        let (extent_x, extent_z) = terrain.heightmap().extent();
+        let target = glm::vec3(
+            (feet.x + step.x).max(origin.x).min(origin.x + extent_x),
+            feet.y,
+            (feet.z + step.z).max(origin.z).min(origin.z + extent_z));
+
+        // Slide along whichever axis is still walkable when the full step is too steep.
+        let along_x = glm::vec3(target.x, feet.y, feet.z);
+        let along_z = glm::vec3(feet.x, feet.y, target.z);
+        if walker.can_step(terrain, feet, target) {
+            next = target;
+        } else if walker.can_step(terrain, feet, along_x) {
+            next = along_x;
+        } else if walker.can_step(terrain, feet, along_z) {
+            next = along_z;
+        }
+    }
+
+    if walker.grounded && is_pressed(window, glfw::Key::Space) {
+        walker.vertical_speed = walker.settings.jump_speed;
+        walker.grounded = false;
+    }
+    walker.vertical_speed -= walker.settings.gravity * delta_t;
+    next.y += walker.vertical_speed * delta_t;
+
+    let ground = Walker::ground_at(terrain, next.x, next.z);
+    // Stays grounded when walking downhill, rather than stepping off into a short fall every frame.
+    let snap = if walker.grounded {
+        walker.settings.max_slope.to_radians().tan() * walker.settings.speed_fast * delta_t
+    } else {
+        0.0
+    };
+    // In the air, can_step only lets the feet move over ground below them, so landing never lifts them onto a ledge.
+    if next.y <= ground + snap && walker.vertical_speed <= 0.0 {
+        next.y = ground;
+        walker.vertical_speed = 0.0;
+        walker.grounded = true;
+    } else {
+        walker.grounded = false;
+    }
+
+    camera.set_position(next + glm::vec3(0.0, walker.settings.eye_height, 0.0));
+}
@@ -13,26 +13,62 @@ extern crate wavefront_obj;
 extern crate lazy_static;
 extern crate multimap;
 extern crate image;
+extern crate png;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate scoped_threadpool;
+extern crate num_cpus;
 
+mod bench;
 mod shaders;
 mod controls;
 mod camera;
 mod util;
 mod file;
 mod objects;
+mod terrain;
 
-use std::ptr;
+use std::{ env, path, ptr };
+use std::sync::Arc;
 use std::os::raw::{ c_void, c_char };
 use std::ffi::CStr;
 use std::sync::mpsc::Receiver;
 use glfw::Context;
 use gl::types::*;
 use util::assert_no_gl_error;
+use objects::Renderable;
+use terrain::voxel::{ Csg, Shape };
+use terrain::Filter;
 
 const WIDTH: u32 = 800;
 const HEIGHT: u32 = 600;
 const ASPECT_RATIO: f32 = (WIDTH as f32) / (HEIGHT as f32);
 
+const DEFAULT_SEED: u64 = 1;
+const EXPORT_DIRECTORY: &str = "./exports";
+
+const BIOME_TABLE_FILE: &str = "./biomes.json";
+
+const BENCH_SIZE: usize = 2049;
+
+// Of terrains 1 and 3, which are islands.
+const SEA_LEVEL: f32 = -1.0;
+
+// Brush radius and strength change by this factor per key press.
+const BRUSH_STEP: f32 = 1.25;
+const MIN_BRUSH_RADIUS: f32 = 0.5;
+
+const OBJECT_FILES: [&str; 6] = [
+    "./objects/cube.obj",
+    "./objects/cube-2.obj",
+    "./objects/icosahedron.obj",
+    "./objects/dodecahedron.obj",
+    "./objects/shuttle.obj",
+    "./objects/cessna.obj",
+];
+
 extern "system" fn gl_debug_message(
     source: GLenum,
     type_: GLenum,
@@ -56,6 +92,14 @@ extern "system" fn gl_debug_message(
 fn main() {
     env_logger::init().unwrap();
 
+    // `terrain-generator bench [size]` times parallel generation instead of opening a window.
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "bench" {
+        let size = args.get(2).map_or(BENCH_SIZE, |s| s.parse().expect("benchmark size must be an unsigned integer"));
+        bench::run(size);
+        return;
+    }
+
     let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
 
     info!("successfully initialized GLFW");
@@ -102,6 +146,219 @@ fn main() {
     render(&mut glfw, &mut window, events);
 }
 
+fn terrain_seed() -> u64 {
+    match env::var("TERRAIN_SEED") {
+        Ok(value) => value.parse().expect("TERRAIN_SEED must be an unsigned integer"),
+        Err(_) => DEFAULT_SEED,
+    }
+}
+
+// The island's coastline: "radial" (the default) or "square" falloff, or otherwise a grayscale image to trace.
+fn terrain_mask() -> terrain::coast::MaskShape {
+    match env::var("TERRAIN_MASK") {
+        Ok(ref value) if value == "radial" => terrain::coast::MaskShape::Radial,
+        Ok(ref value) if value == "square" => terrain::coast::MaskShape::Square,
+        Ok(filename) => match terrain::coast::load_image_mask(path::Path::new(&filename)) {
+            Ok(mask) => {
+                info!("loaded coastline mask from {}", filename);
+                mask
+            },
+            Err(e) => {
+                error!("couldn't load coastline mask from {}: {}", filename, e);
+                terrain::coast::MaskShape::Radial
+            },
+        },
+        Err(_) => terrain::coast::MaskShape::Radial,
+    }
+}
+
+fn build_renderables<'a>(
+    program: &'a shaders::Program,
+    splat_program: &'a shaders::Program,
+    water_program: &'a shaders::Program,
+    seed: u64,
+    chunk_settings: &terrain::chunks::ChunkSettings
+) -> Vec<Box<Renderable + 'a>> {
+    let noise = Arc::new(terrain::noise::Fractal::new(terrain::noise::FractalSettings {
+        seed: seed,
+        ..Default::default()
+    }));
+    let mut hills = terrain::noise::heightmap_from_noise_parallel(
+        &*noise, 257, 257, 0.5, (0.0, 0.0), 12.0, terrain::parallel::default_threads());
+    // An island, with the sea floor well below sea level.
+    terrain::coast::FalloffMask {
+        shape: terrain_mask(),
+        floor: SEA_LEVEL - 4.0,
+        noise: terrain::noise::FractalSettings {
+            seed: seed.wrapping_add(2),
+            ..terrain::coast::FalloffMask::default().noise
+        },
+        ..Default::default()
+    }.apply(&mut hills);
+
+    let midpoint_displaced = terrain::diamond_square::generate(&terrain::diamond_square::DiamondSquareSettings {
+        seed: seed,
+        size_exponent: 8,
+        spacing: 0.5,
+        amplitude: 8.0,
+        ..Default::default()
+    });
+
+    let eroded = terrain::erosion::hydraulic_erode(&hills, &terrain::erosion::HydraulicErosionSettings {
+        seed: seed,
+        ..Default::default()
+    });
+    let erosion_colors = terrain::erosion::erosion_colors(&eroded, terrain::TERRAIN_MATERIAL.color_diffuse);
+
+    // Rivers are carved after erosion, which starts from the uncarved hills.
+    let river_settings = terrain::hydrology::RiverSettings::default();
+    let rivers = terrain::hydrology::generate_rivers(&mut hills, &river_settings);
+    let lakes = terrain::lakes::detect_lakes(&hills, &Default::default());
+    let lake_water = terrain::lakes::lake_mesh(&hills, &lakes);
+
+    let mut scree = midpoint_displaced.clone();
+    terrain::erosion::ThermalErosionSettings::default().apply(&mut scree);
+
+    let biome_table = match terrain::biomes::BiomeTable::load(path::Path::new(BIOME_TABLE_FILE)) {
+        Ok(table) => Some(table),
+        Err(e) => {
+            error!("{}; terrain will be a single color", e);
+            None
+        },
+    };
+    let climate_settings = terrain::biomes::ClimateSettings {
+        seed: seed,
+        ..Default::default()
+    };
+    // Boulders on the steep slopes and shrubs in the woods and fields of the hills, which need the hills' biomes. None
+    // of them go in the sea.
+    let hills_climate = terrain::biomes::ClimateSettings {
+        sea_level: Some(SEA_LEVEL),
+        ..climate_settings.clone()
+    };
+    let hills_biomes = biome_table.as_ref().map(|table| terrain::biomes::classify(&hills, table, &hills_climate));
+    let boulders = terrain::scatter::scatter(&hills, None, &terrain::scatter::ScatterSettings {
+        seed: seed,
+        sea_level: Some(SEA_LEVEL),
+        min_distance: 3.0,
+        slope: (25.0, 60.0),
+        scale: (0.3, 0.8),
+        lift: -0.3,
+        ..Default::default()
+    });
+    let shrubs = match (biome_table.as_ref(), hills_biomes.as_ref()) {
+        (Some(table), Some(biomes)) => {
+            let settings = terrain::scatter::ScatterSettings {
+                seed: seed.wrapping_add(1),
+                sea_level: Some(SEA_LEVEL),
+                min_distance: 2.5,
+                slope: (0.0, 25.0),
+                biomes: table.indices_of(&["forest", "grassland"]),
+                scale: (0.2, 0.4),
+                ..Default::default()
+            };
+            terrain::scatter::scatter(&hills, Some(biomes.as_slice()), &settings)
+        },
+        _ => vec![],
+    };
+
+    let with_biomes = |terrain: terrain::RenderableTerrain<'a>| {
+        match biome_table {
+            Some(ref table) => {
+                let settings = terrain::biomes::ClimateSettings {
+                    sea_level: terrain.sea_level(),
+                    ..climate_settings.clone()
+                };
+                let biomes = terrain::biomes::classify(terrain.heightmap(), table, &settings);
+                terrain.with_vertex_materials(table.vertex_materials(&biomes))
+            },
+            None => terrain,
+        }
+    };
+
+    let sea_settings = terrain::coast::SeaSettings {
+        level: SEA_LEVEL,
+        ..Default::default()
+    };
+
+    // Terrains come first so that they're on the low number keys.
+    let mut renderables: Vec<Box<Renderable + 'a>> = vec![];
+    renderables.push(Box::new(with_biomes(
+        terrain::RenderableTerrain::centered(hills, program)
+            .with_seed(seed)
+            .with_sea(water_program, sea_settings.clone())
+            .with_rivers(rivers, &river_settings)
+            .with_overlay(lake_water)
+            .with_scattered(objects::RenderableObject::new("./objects/icosahedron.obj", program), &boulders)
+            .with_scattered(objects::RenderableObject::new("./objects/dodecahedron.obj", program), &shrubs))));
+    renderables.push(Box::new(with_biomes(
+        terrain::RenderableTerrain::centered(midpoint_displaced, program).with_seed(seed))));
+    renderables.push(Box::new(
+        terrain::RenderableTerrain::centered(eroded.heightmap, program)
+            .with_seed(seed)
+            .with_sea(water_program, sea_settings.clone())
+            .with_diffuse_colors(erosion_colors)));
+    let splatting = terrain::splat::Splatting::new(splat_program, terrain::splat::SplatSettings {
+        seed: seed,
+        ..Default::default()
+    });
+    renderables.push(Box::new(
+        terrain::RenderableTerrain::centered(scree, program)
+            .with_seed(seed)
+            .with_splatting(splatting)));
+    renderables.push(Box::new(terrain::chunks::ChunkedTerrain::new(noise, chunk_settings.clone(), program)));
+
+    // A tunnel through the hillside, an arch standing over the valley it comes out in and a boulder beside it, all
+    // rounded off into an island so the volume's edges don't show as straight walls.
+    let mut voxels = terrain::voxel::generate(&terrain::voxel::VoxelSettings {
+        seed: seed,
+        ..Default::default()
+    });
+    let operations = [
+        Csg::Subtract(Shape::Capsule {
+            start: glm::vec3(0.0, 7.0, 20.0),
+            end: glm::vec3(32.0, 7.0, 20.0),
+            radius: 2.0,
+        }),
+        Csg::Union(Shape::Box { min: glm::vec3(6.0, 0.0, 6.0), max: glm::vec3(12.0, 14.0, 9.0) }),
+        Csg::Subtract(Shape::Capsule {
+            start: glm::vec3(9.0, 5.0, 5.0),
+            end: glm::vec3(9.0, 5.0, 10.0),
+            radius: 2.5,
+        }),
+        Csg::Union(Shape::Sphere { center: glm::vec3(16.0, 9.0, 8.0), radius: 2.5 }),
+        Csg::Intersect(Shape::Sphere { center: glm::vec3(16.0, -8.0, 16.0), radius: 24.0 }),
+    ];
+    for operation in operations.iter() {
+        voxels.apply(operation);
+    }
+    renderables.push(Box::new(terrain::voxel::RenderableVoxels::centered(voxels, program)));
+
+    if let Ok(filename) = env::var("TERRAIN_HEIGHTMAP") {
+        match terrain::import::load_heightmap(path::Path::new(&filename), &Default::default()) {
+            Ok(heightmap) => {
+                info!("loaded {}x{} heightmap from {}", heightmap.width(), heightmap.depth(), filename);
+                renderables.push(Box::new(terrain::RenderableTerrain::centered(heightmap, program)));
+            },
+            Err(e) => error!("couldn't load heightmap from {}: {}", filename, e),
+        }
+    }
+    if let Ok(filename) = env::var("TERRAIN_GRAPH") {
+        match terrain::graph::evaluate_file(path::Path::new(&filename), seed) {
+            Ok(heightmap) => {
+                info!("generated {}x{} heightmap from graph {}", heightmap.width(), heightmap.depth(), filename);
+                renderables.push(Box::new(with_biomes(
+                    terrain::RenderableTerrain::centered(heightmap, program).with_seed(seed))));
+            },
+            Err(e) => error!("couldn't generate terrain from graph {}: {}", filename, e),
+        }
+    }
+    for filename in OBJECT_FILES.iter() {
+        renderables.push(Box::new(objects::RenderableObject::new(filename, program)));
+    }
+    renderables
+}
+
 fn render(glfw: &mut glfw::Glfw, window: &mut glfw::Window, events: Receiver<(f64, glfw::WindowEvent)>) {
     glfw.poll_events();
     controls::init_window_controls(window);
@@ -121,23 +378,34 @@ fn render(glfw: &mut glfw::Glfw, window: &mut glfw::Window, events: Receiver<(f6
     let fs_phong = shaders::compile_shader("./shaders/phong.frag", gl::FRAGMENT_SHADER);
     let program_phong = shaders::Program::new(vs_phong, fs_phong);
 
+    let vs_splat = shaders::compile_shader("./shaders/terrain_splat.vert", gl::VERTEX_SHADER);
+    let fs_splat = shaders::compile_shader("./shaders/terrain_splat.frag", gl::FRAGMENT_SHADER);
+    let program_splat = shaders::Program::new(vs_splat, fs_splat);
+
+    let vs_water = shaders::compile_shader("./shaders/water.vert", gl::VERTEX_SHADER);
+    let fs_water = shaders::compile_shader("./shaders/phong.frag", gl::FRAGMENT_SHADER);
+    let program_water = shaders::Program::new(vs_water, fs_water);
+
     info!("successfully created shaders/program");
 
-    let mut renderables = vec![
-        objects::RenderableObject::new("./objects/cube.obj", &program_phong),
-        objects::RenderableObject::new("./objects/cube-2.obj", &program_phong),
-        objects::RenderableObject::new("./objects/icosahedron.obj", &program_phong),
-        objects::RenderableObject::new("./objects/dodecahedron.obj", &program_phong),
-        objects::RenderableObject::new("./objects/shuttle.obj", &program_phong),
-        objects::RenderableObject::new("./objects/cessna.obj", &program_phong),
-    ];
+    let seed = terrain_seed();
+    info!("generating terrain with seed {}", seed);
+    let chunk_settings = terrain::chunks::ChunkSettings::default();
+    let mut renderables = build_renderables(&program_phong, &program_splat, &program_water, seed, &chunk_settings);
     info!("successfully initialized static data");
 
     let mut object_to_render = 1;
 
     let mut last_time = glfw.get_time() as f32;
+    let mut debug_mode = false;
+    let mut walker: Option<controls::Walker> = None;
+    let mut editing = false;
+    let mut brush = terrain::sculpt::Brush::default();
+    let mut stroking = false;
     let mut camera = camera::Camera::new();
+    camera.set_far_plane(chunk_settings.visible_distance());
     camera.translate(camera::TranslateDirection::Forward, -25.0);
+    camera.translate(camera::TranslateDirection::Altitude, 10.0);
 
     info!("beginning event loop");
     while !window.should_close() {
@@ -145,7 +413,39 @@ fn render(glfw: &mut glfw::Glfw, window: &mut glfw::Window, events: Receiver<(f6
         let delta_t = t - last_time;
         last_time = t;
 
-        controls::move_camera_from_inputs(&mut camera, window, delta_t);
+        let index_to_render = object_to_render - 1;
+        {
+            // Walking needs a single heightmap to walk on; anything else is flown over.
+            let terrain = renderables.get(index_to_render).and_then(|r| r.terrain());
+            match (walker.as_mut(), terrain) {
+                _ if editing => controls::move_camera_from_keys(&mut camera, window, delta_t),
+                (Some(walker), Some(terrain)) =>
+                    controls::walk_camera_from_inputs(&mut camera, window, delta_t, walker, terrain),
+                _ => controls::move_camera_from_inputs(&mut camera, window, delta_t),
+            }
+        }
+
+        if editing && window.get_mouse_button(glfw::MouseButtonLeft) == glfw::Action::Press {
+            stroking = true;
+            let (origin, direction) = controls::cursor_ray(&camera, window, ASPECT_RATIO);
+            let hit = renderables
+                .get(index_to_render)
+                .and_then(|r| r.terrain())
+                .and_then(|terrain| terrain.raycast(origin, direction, chunk_settings.visible_distance()));
+            if let Some(hit) = hit {
+                renderables[index_to_render].sculpt(&brush, hit.position, delta_t);
+            }
+        } else if stroking {
+            // Each press of the button is one step in the undo history.
+            stroking = false;
+            for r in renderables.iter_mut() {
+                r.end_stroke();
+            }
+        }
+
+        if index_to_render < renderables.len() {
+            renderables[index_to_render].update(&camera);
+        }
 
         let view = camera.view_mat();
         let projection = camera.projection_mat(ASPECT_RATIO);
@@ -153,7 +453,6 @@ fn render(glfw: &mut glfw::Glfw, window: &mut glfw::Window, events: Receiver<(f6
         unsafe {
             gl::ClearColor(0.1, 0.1, 0.1, 1.0);
             gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-            let index_to_render = object_to_render - 1;
             if index_to_render < renderables.len() {
                 renderables[index_to_render].render(view, projection);
             }
@@ -169,6 +468,107 @@ fn render(glfw: &mut glfw::Glfw, window: &mut glfw::Window, events: Receiver<(f6
                     info!("received esc key, will close window");
                     window.set_should_close(true);
                 },
+                glfw::WindowEvent::Key(glfw::Key::L, _, glfw::Action::Press, _) => {
+                    debug_mode = !debug_mode;
+                    info!("debug mode {}", if debug_mode { "on" } else { "off" });
+                    for r in renderables.iter_mut() {
+                        r.set_debug_mode(debug_mode);
+                    }
+                },
+                glfw::WindowEvent::Key(glfw::Key::E, _, glfw::Action::Press, _) => {
+                    editing = !editing;
+                    controls::set_cursor_captured(window, !editing);
+                    info!("edit mode {}", if editing { "on" } else { "off" });
+                },
+                glfw::WindowEvent::Key(glfw::Key::B, _, glfw::Action::Press, _) => {
+                    brush.kind = brush.kind.next();
+                    info!("brush: {:?}", brush.kind);
+                },
+                glfw::WindowEvent::Key(glfw::Key::V, _, glfw::Action::Press, _) => {
+                    brush.falloff = brush.falloff.next();
+                    info!("brush falloff: {:?}", brush.falloff);
+                },
+                glfw::WindowEvent::Key(glfw::Key::LeftBracket, _, glfw::Action::Press, _) => {
+                    brush.radius = (brush.radius / BRUSH_STEP).max(MIN_BRUSH_RADIUS);
+                    info!("brush radius: {}", brush.radius);
+                },
+                glfw::WindowEvent::Key(glfw::Key::RightBracket, _, glfw::Action::Press, _) => {
+                    brush.radius *= BRUSH_STEP;
+                    info!("brush radius: {}", brush.radius);
+                },
+                glfw::WindowEvent::Key(glfw::Key::Minus, _, glfw::Action::Press, _) => {
+                    brush.strength /= BRUSH_STEP;
+                    info!("brush strength: {}", brush.strength);
+                },
+                glfw::WindowEvent::Key(glfw::Key::Equal, _, glfw::Action::Press, _) => {
+                    brush.strength *= BRUSH_STEP;
+                    info!("brush strength: {}", brush.strength);
+                },
+                glfw::WindowEvent::Key(glfw::Key::Z, _, glfw::Action::Press, _)
+                    if controls::is_control_held(window) => {
+                    match renderables.get_mut(index_to_render).and_then(|r| r.undo()) {
+                        Some(label) => info!("undid {}", label),
+                        None => info!("nothing to undo"),
+                    }
+                },
+                glfw::WindowEvent::Key(glfw::Key::Y, _, glfw::Action::Press, _)
+                    if controls::is_control_held(window) => {
+                    match renderables.get_mut(index_to_render).and_then(|r| r.redo()) {
+                        Some(label) => info!("redid {}", label),
+                        None => info!("nothing to redo"),
+                    }
+                },
+                glfw::WindowEvent::Key(glfw::Key::T, _, glfw::Action::Press, _) => {
+                    if let Some(r) = renderables.get_mut(index_to_render) {
+                        r.apply_filter("thermal erosion", &terrain::erosion::ThermalErosionSettings::default());
+                    }
+                },
+                glfw::WindowEvent::Key(glfw::Key::H, _, glfw::Action::Press, _) => {
+                    let settings = terrain::erosion::HydraulicErosionSettings {
+                        seed: seed,
+                        ..Default::default()
+                    };
+                    if let Some(r) = renderables.get_mut(index_to_render) {
+                        r.apply_filter("hydraulic erosion", &settings);
+                    }
+                },
+                glfw::WindowEvent::Key(glfw::Key::G, _, glfw::Action::Press, _) => {
+                    walker = match walker {
+                        Some(_) => None,
+                        None => Some(controls::Walker::new(Default::default())),
+                    };
+                    info!("walk mode {}", if walker.is_some() { "on" } else { "off" });
+                },
+                glfw::WindowEvent::Key(glfw::Key::P, _, glfw::Action::Press, _) => {
+                    match renderables.get(object_to_render - 1).and_then(|r| r.terrain()) {
+                        Some(terrain) => {
+                            let stem = format!("terrain-{}", object_to_render);
+                            let directory = path::Path::new(EXPORT_DIRECTORY);
+                            if let Err(e) = terrain::export::export_all(terrain.heightmap(), directory, &stem, terrain.seed()) {
+                                error!("couldn't export terrain: {}", e);
+                            }
+                            if let Some(rivers) = terrain.rivers() {
+                                let geojson = directory.join(format!("{}-rivers.geojson", stem));
+                                if let Err(e) = terrain::hydrology::export_geojson(terrain.heightmap(), rivers, &geojson) {
+                                    error!("couldn't export rivers: {}", e);
+                                }
+                            }
+                        },
+                        None => warn!("nothing to export; the current view isn't a single heightmap"),
+                    }
+                },
+                glfw::WindowEvent::Key(glfw::Key::O, _, glfw::Action::Press, _) => {
+                    match renderables.get(object_to_render - 1).and_then(|r| r.terrain()) {
+                        Some(terrain) => {
+                            let stem = format!("terrain-{}", object_to_render);
+                            let directory = path::Path::new(EXPORT_DIRECTORY);
+                            if let Err(e) = terrain::obj_export::export_obj(terrain.heightmap(), terrain.diffuse_colors(), directory, &stem) {
+                                error!("couldn't export terrain mesh: {}", e);
+                            }
+                        },
+                        None => warn!("nothing to export; the current view isn't a single heightmap"),
+                    }
+                },
                 // TODO: There's got to be some idiomatic way to do this.
                 glfw::WindowEvent::Key(glfw::Key::Num1, _, glfw::Action::Press, _) => {
                     object_to_render = 1;
@@ -200,6 +600,13 @@ fn render(glfw: &mut glfw::Glfw, window: &mut glfw::Window, events: Receiver<(f6
                 glfw::WindowEvent::Key(glfw::Key::Num0, _, glfw::Action::Press, _) => {
                     object_to_render = 10;
                 },
+                // There are more renderables than number keys, so comma and period step through all of them.
+                glfw::WindowEvent::Key(glfw::Key::Comma, _, glfw::Action::Press, _) => {
+                    object_to_render = if object_to_render > 1 { object_to_render - 1 } else { renderables.len() };
+                },
+                glfw::WindowEvent::Key(glfw::Key::Period, _, glfw::Action::Press, _) => {
+                    object_to_render = object_to_render % renderables.len() + 1;
+                },
                 _ => {}
             }
         }
@@ -12,6 +12,8 @@ use num_traits::identities::One;
 use wavefront_obj::{ obj, mtl };
 use util::assert_no_gl_error;
 use file;
+use camera;
+use terrain;
 
 use shaders;
 use util;
@@ -48,6 +50,17 @@ impl Flattenable for glm::Vec3 {
     }
 }
 
+impl Flattenable for glm::Vec4 {
+    fn component_count() -> u32 { 4 }
+
+    fn append_components_to(&self, vector: &mut Vec<GLfloat>) {
+        vector.push(self.x);
+        vector.push(self.y);
+        vector.push(self.z);
+        vector.push(self.w);
+    }
+}
+
 impl Flattenable for mtl::Color {
     fn component_count() -> u32 { 3 }
 
@@ -75,16 +88,150 @@ lazy_static! {
 
 static BLACK: mtl::Color = mtl::Color { r: 0.0, g: 0.0, b: 0.0 };
 
+static WHITE: mtl::Color = mtl::Color { r: 1.0, g: 1.0, b: 1.0 };
+
+// Everything needed to build a VAO for the phong program, before any of it has been sent to the GPU.
+pub struct MeshData {
+    pub positions: Vec<glm::Vec3>,
+    pub normals: Vec<glm::Vec3>,
+    pub uvs: Vec<glm::Vec2>,
+    pub colors_ambient: Vec<mtl::Color>,
+    pub colors_diffuse: Vec<mtl::Color>,
+    pub colors_specular: Vec<mtl::Color>,
+    pub specular_exponents: Vec<GLfloat>,
+    pub indices: Vec<GLuint>,
+}
+
+impl MeshData {
+    pub fn with_material(
+        positions: Vec<glm::Vec3>,
+        normals: Vec<glm::Vec3>,
+        uvs: Vec<glm::Vec2>,
+        indices: Vec<GLuint>,
+        material: &mtl::Material
+    ) -> MeshData {
+        let mut mesh = MeshData {
+            positions: positions,
+            normals: normals,
+            uvs: uvs,
+            colors_ambient: vec![],
+            colors_diffuse: vec![],
+            colors_specular: vec![],
+            specular_exponents: vec![],
+            indices: indices,
+        };
+        mesh.set_material(material);
+        mesh
+    }
+
+    pub fn set_material(&mut self, material: &mtl::Material) {
+        self.colors_ambient.clear();
+        self.colors_diffuse.clear();
+        self.colors_specular.clear();
+        self.specular_exponents.clear();
+
+        // TODO: Can do some kind of "repeat" instead of this silliness?
+        for _ in 0..self.positions.len() {
+            match material.illumination {
+                mtl::Illumination::Ambient => {
+                    self.colors_ambient.push(material.color_ambient);
+                    self.colors_diffuse.push(BLACK);
+                    self.colors_specular.push(BLACK);
+                    self.specular_exponents.push(1.0);
+                },
+                mtl::Illumination::AmbientDiffuse => {
+                    self.colors_ambient.push(material.color_ambient);
+                    self.colors_diffuse.push(material.color_diffuse);
+                    self.colors_specular.push(BLACK);
+                    self.specular_exponents.push(1.0);
+                },
+                mtl::Illumination::AmbientDiffuseSpecular => {
+                    self.colors_ambient.push(material.color_ambient);
+                    self.colors_diffuse.push(material.color_diffuse);
+                    self.colors_specular.push(material.color_specular);
+                    self.specular_exponents.push(material.specular_coefficient as GLfloat);
+                },
+            }
+        }
+    }
+}
+
 pub struct LoadedMesh {
     vao: GLuint,
+    buffer_names: Vec<GLuint>,
     texture_name: GLuint,
     index_count: GLint
 }
 
+impl LoadedMesh {
+    // Frees everything this mesh put on the GPU. Only needed for meshes that don't live as long as the program.
+    pub fn delete(self) {
+        unsafe {
+            gl::DeleteBuffers(self.buffer_names.len() as GLsizei, self.buffer_names.as_ptr());
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteTextures(1, &self.texture_name);
+            assert_no_gl_error();
+        }
+    }
+
+    // Overwrites the positions and normals of `positions.len()` vertices starting at `first_vertex`, in place, for
+    // meshes that change after they're uploaded.
+    pub fn update_vertices(&self, first_vertex: usize, positions: Vec<glm::Vec3>, normals: Vec<glm::Vec3>) {
+        assert_eq!(positions.len(), normals.len(), "need a normal for every position");
+        // In the order upload_mesh_with_attributes creates them.
+        update_array_buffer(self.buffer_names[0], first_vertex, positions);
+        update_array_buffer(self.buffer_names[1], first_vertex, normals);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub position: glm::Vec3,
+    pub color: glm::Vec3,
+    pub power: f32,
+}
+
+lazy_static! {
+    static ref DEFAULT_LIGHT: Light = Light {
+        position: glm::vec3(3.0, 4.0, 15.0),
+        color: glm::vec3(1.0, 1.0, 1.0),
+        power: 80.0,
+    };
+}
+
+pub trait Renderable {
+    // Called once per frame before rendering, for anything that depends on where the viewer is.
+    fn update(&mut self, _camera: &camera::Camera) {}
+
+    // Toggles whatever debug visualization this renderable has, if any.
+    fn set_debug_mode(&mut self, _enabled: bool) {}
+
+    // For renderables backed by a single heightmap, so it can be exported, queried, etc.
+    fn terrain(&self) -> Option<&terrain::RenderableTerrain> { None }
+
+    // For renderables with a heightmap that can be edited in place; `at` is in world space.
+    fn sculpt(&mut self, _brush: &terrain::sculpt::Brush, _at: glm::Vec3, _delta_t: f32) {}
+
+    // Closes the brush stroke in progress, so that it's undone as one step.
+    fn end_stroke(&mut self) {}
+
+    // Runs a filter over the whole heightmap as one undoable step.
+    fn apply_filter(&mut self, _label: &str, _filter: &terrain::Filter) {}
+
+    // These return the label of the step undone or redone, if there was one.
+    fn undo(&mut self) -> Option<String> { None }
+
+    fn redo(&mut self) -> Option<String> { None }
+
+    fn render(&mut self, view: glm::Mat4, projection: glm::Mat4);
+}
+
 pub struct RenderableObject<'a> {
     filename: String,
     program: &'a shaders::Program,
     meshes: Option<Vec<LoadedMesh>>,
+    // The model is drawn once with each of these as its model matrix.
+    instances: Vec<glm::Mat4>,
 }
 
 fn load_obj_file<'a>(path: &path::Path) -> obj::ObjSet {
@@ -99,50 +246,39 @@ fn mapify_mtl(mtl_set: mtl::MtlSet) -> HashMap<String, mtl::Material> {
     map
 }
 
+impl <'a> Renderable for RenderableObject<'a> {
+    fn render(&mut self, view: glm::Mat4, projection: glm::Mat4) {
+        self.draw(glm::Mat4::one(), view, projection, &DEFAULT_LIGHT);
+    }
+}
+
 impl <'a> RenderableObject<'a> {
     pub fn new(filename: &str, program: &'a shaders::Program) -> RenderableObject<'a> {
         RenderableObject {
             filename: filename.to_owned(),
             program: program,
             meshes: Option::None,
+            instances: vec![glm::Mat4::one()],
         }
     }
 
-    pub fn render(&mut self, view: glm::Mat4, projection: glm::Mat4) {
+    // Replaces the single copy at the origin with one copy per model matrix.
+    pub fn with_instances(mut self, instances: Vec<glm::Mat4>) -> RenderableObject<'a> {
+        self.instances = instances;
+        self
+    }
+
+    // Draws every instance, placed relative to `parent`, e.g. the model matrix of the terrain they're scattered over.
+    pub fn draw(&mut self, parent: glm::Mat4, view: glm::Mat4, projection: glm::Mat4, light: &Light) {
         if self.meshes.is_none() {
             self.meshes = Some(self.load_meshes());
         }
 
-        let model = glm::Mat4::one();
-        let model_view_projection = projection * view * model;
-
-        let v_array = util::arrayify_mat4(view);
-        let m_array = util::arrayify_mat4(model);
-        let mvp_array = util::arrayify_mat4(model_view_projection);
-        let light_position = [3f32, 4f32, 15f32];
-        let light_color = [1f32, 1f32, 1f32];
-        let light_power = 80f32;
-
-        unsafe {
-            gl::UseProgram(self.program.name);
-            gl::UniformMatrix4fv(self.program.get_uniform("u_MatMvp"), 1, gl::FALSE, &*mvp_array as *const f32);
-            gl::UniformMatrix4fv(self.program.get_uniform("u_MatV"), 1, gl::FALSE, &*v_array as *const f32);
-            gl::UniformMatrix4fv(self.program.get_uniform("u_MatM"), 1, gl::FALSE, &*m_array as *const f32);
-            gl::Uniform3f(self.program.get_uniform("u_LightPosition_WorldSpace"), light_position[0], light_position[1], light_position[2]);
-            gl::Uniform3f(self.program.get_uniform("u_LightColor"), light_color[0], light_color[1], light_color[2]);
-            gl::Uniform1f(self.program.get_uniform("u_LightPower"), light_power);
-            assert_no_gl_error();
-
+        for instance in self.instances.iter() {
+            use_program(self.program, parent * *instance, view, projection, light);
             for m in self.meshes.as_ref().unwrap() {
-                gl::ActiveTexture(gl::TEXTURE0);
-                gl::BindTexture(gl::TEXTURE_2D, m.texture_name);
-                gl::Uniform1i(self.program.get_uniform("u_TextureDiffuse"), 0);
-                gl::BindVertexArray(m.vao);
-                gl::DrawElements(gl::TRIANGLES, m.index_count, gl::UNSIGNED_INT, ptr::null());
-                assert_no_gl_error();
+                draw_mesh(self.program, m);
             }
-
-            // TODO: Cleanup: unbind program/textures/VAOs/etc.
         }
     }
 
@@ -269,7 +405,7 @@ impl <'a> RenderableObject<'a> {
                     .collect();
 
                 let uvs: Vec<glm::Vec2>;
-                let mut texture: image::DynamicImage;
+                let texture: image::DynamicImage;
 
                 match material.uv_map.as_ref() {
                     Some(texture_name) => {
@@ -279,7 +415,9 @@ impl <'a> RenderableObject<'a> {
                             .map(|i| {
                                 // TODO: We are assuming this exist iff a texture was specified. Error checking.
                                 let t_vertex = o.tex_vertices[*i];
-                                glm::vec2(t_vertex.u as f32, t_vertex.v as f32)
+                                // OBJ counts v up from the bottom of the image, but textures are uploaded top row
+                                // first.
+                                glm::vec2(t_vertex.u as f32, 1.0 - t_vertex.v as f32)
                             })
                             .collect();
                     },
@@ -288,13 +426,7 @@ impl <'a> RenderableObject<'a> {
                             .iter()
                             .map(|_i| glm::vec2(0f32, 0f32))
                             .collect();
-                        texture = image::DynamicImage::new_rgb8(1, 1);
-                        texture.put_pixel(0, 0, image::Rgba([
-                            (material.color_diffuse.r * 255f64) as u8,
-                            (material.color_diffuse.g * 255f64) as u8,
-                            (material.color_diffuse.b * 255f64) as u8,
-                            255
-                        ]));
+                        texture = solid_color_texture(material.color_diffuse);
                     },
                 }
 
@@ -319,149 +451,269 @@ impl <'a> RenderableObject<'a> {
                     })
                     .collect();
 
-                let mut colors_ambient: Vec<mtl::Color> = vec![];
-                let mut colors_diffuse: Vec<mtl::Color> = vec![];
-                let mut colors_specular: Vec<mtl::Color> = vec![];
-                let mut specular_exponents: Vec<GLfloat> = vec![];
-
-                // TODO: Can do some kind of "repeat" instead of this silliness?
-                for _ in 0..vertices.len() {
-                    match material.illumination {
-                        mtl::Illumination::Ambient => {
-                            colors_ambient.push(material.color_ambient);
-                            colors_diffuse.push(BLACK);
-                            colors_specular.push(BLACK);
-                            specular_exponents.push(1.0);
-                        },
-                        mtl::Illumination::AmbientDiffuse => {
-                            colors_ambient.push(material.color_ambient);
-                            colors_diffuse.push(material.color_diffuse);
-                            colors_specular.push(BLACK);
-                            specular_exponents.push(1.0);
-                        },
-                        mtl::Illumination::AmbientDiffuseSpecular => {
-                            colors_ambient.push(material.color_ambient);
-                            colors_diffuse.push(material.color_diffuse);
-                            colors_specular.push(material.color_specular);
-                            specular_exponents.push(material.specular_coefficient as GLfloat);
-                        },
-                    }
-                }
+                upload_mesh(self.program, MeshData::with_material(vertices, normals, uvs, indices, material), texture)
+            })
+            .collect()
+    }
+}
 
-                let mut vao = 0;
-                unsafe {
-                    gl::GenVertexArrays(1, &mut vao);
-                    gl::BindVertexArray(vao);
-                    assert_no_gl_error();
-                }
+pub fn solid_color_texture(color: mtl::Color) -> image::DynamicImage {
+    let mut texture = image::DynamicImage::new_rgb8(1, 1);
+    texture.put_pixel(0, 0, image::Rgba([
+        (color.r * 255f64) as u8,
+        (color.g * 255f64) as u8,
+        (color.b * 255f64) as u8,
+        255
+    ]));
+    texture
+}
 
-                self.create_array_buffer("in_VertexPosition", vertices);
-                self.create_array_buffer("in_VertexNormal", normals);
-                self.create_array_buffer("in_VertexUv", uvs);
-                self.create_array_buffer("in_ColorAmbient", colors_ambient);
-                self.create_array_buffer("in_ColorDiffuse", colors_diffuse);
-                self.create_array_buffer("in_ColorSpecular", colors_specular);
-                self.create_array_buffer("in_SpecularExponent", specular_exponents);
+pub fn white_texture() -> image::DynamicImage {
+    solid_color_texture(WHITE)
+}
 
-                let index_count = indices.len();
-                self.create_element_array_buffer(indices);
+pub fn upload_mesh(program: &shaders::Program, mesh: MeshData, texture: image::DynamicImage) -> LoadedMesh {
+    upload_mesh_with_attributes(program, mesh, texture, vec![])
+}
 
-                unsafe {
-                    // TODO: Verify that this is "unbind".
-                    gl::BindVertexArray(0);
-                }
+// Like `upload_mesh`, for programs that take more per-vertex inputs than the phong program does. Each extra attribute
+// is a (name, one value per vertex) pair.
+pub fn upload_mesh_with_attributes(
+    program: &shaders::Program,
+    mesh: MeshData,
+    texture: image::DynamicImage,
+    extra_attributes: Vec<(&str, Vec<glm::Vec4>)>
+) -> LoadedMesh {
+    let mut vao = 0;
+    unsafe {
+        gl::GenVertexArrays(1, &mut vao);
+        gl::BindVertexArray(vao);
+        assert_no_gl_error();
+    }
 
-                let texture_name = self.create_texture_buffer(texture);
+    let index_count = mesh.indices.len();
+    let mut buffer_names = vec![
+        create_array_buffer(program, "in_VertexPosition", mesh.positions),
+        create_array_buffer(program, "in_VertexNormal", mesh.normals),
+        create_array_buffer(program, "in_VertexUv", mesh.uvs),
+        create_array_buffer(program, "in_ColorAmbient", mesh.colors_ambient),
+        create_array_buffer(program, "in_ColorDiffuse", mesh.colors_diffuse),
+        create_array_buffer(program, "in_ColorSpecular", mesh.colors_specular),
+        create_array_buffer(program, "in_SpecularExponent", mesh.specular_exponents),
+        create_element_array_buffer(mesh.indices),
+    ];
+    for (name, values) in extra_attributes {
+        buffer_names.push(create_array_buffer(program, name, values));
+    }
 
-                LoadedMesh {
-                    vao: vao,
-                    texture_name: texture_name,
-                    index_count: index_count as GLint,
-                }
-            })
-            .collect()
+    unsafe {
+        // TODO: Verify that this is "unbind".
+        gl::BindVertexArray(0);
     }
 
-    fn create_array_buffer<T: Flattenable>(&self, attribute_name: &str, items: Vec<T>) {
-        let mut flattened_items: Vec<GLfloat> = vec![];
-        for i in items {
-            i.append_components_to(&mut flattened_items);
-        }
+    let texture_name = create_texture_buffer(texture);
 
-        unsafe {
-            let mut array_buffer_name: GLuint = 0;
-            gl::GenBuffers(1, &mut array_buffer_name);
-            gl::BindBuffer(gl::ARRAY_BUFFER, array_buffer_name);
-            gl::BufferData(
-                gl::ARRAY_BUFFER,
-                (flattened_items.len() * size_of::<GLfloat>()) as GLsizeiptr,
-                flattened_items.as_ptr() as *const _,
-                gl::STATIC_DRAW);
-            assert_no_gl_error();
+    LoadedMesh {
+        vao: vao,
+        buffer_names: buffer_names,
+        texture_name: texture_name,
+        index_count: index_count as GLint,
+    }
+}
 
-            let attribute_location = self.program.get_attrib(attribute_name) as GLuint;
-            gl::EnableVertexAttribArray(attribute_location);
-            gl::VertexAttribPointer(
-                attribute_location,
-                T::component_count() as GLint,
-                gl::FLOAT,
-                gl::FALSE as GLboolean,
-                0,
-                ptr::null());
-            assert_no_gl_error();
-        }
+pub fn use_program(
+    program: &shaders::Program,
+    model: glm::Mat4,
+    view: glm::Mat4,
+    projection: glm::Mat4,
+    light: &Light
+) {
+    let model_view_projection = projection * view * model;
+
+    let v_array = util::arrayify_mat4(view);
+    let m_array = util::arrayify_mat4(model);
+    let mvp_array = util::arrayify_mat4(model_view_projection);
+
+    unsafe {
+        gl::UseProgram(program.name);
+        gl::UniformMatrix4fv(program.get_uniform("u_MatMvp"), 1, gl::FALSE, &*mvp_array as *const f32);
+        gl::UniformMatrix4fv(program.get_uniform("u_MatV"), 1, gl::FALSE, &*v_array as *const f32);
+        gl::UniformMatrix4fv(program.get_uniform("u_MatM"), 1, gl::FALSE, &*m_array as *const f32);
+        gl::Uniform3f(program.get_uniform("u_LightPosition_WorldSpace"), light.position.x, light.position.y, light.position.z);
+        gl::Uniform3f(program.get_uniform("u_LightColor"), light.color.x, light.color.y, light.color.z);
+        gl::Uniform1f(program.get_uniform("u_LightPower"), light.power);
+        gl::Uniform4f(program.get_uniform("u_DebugColor"), 0.0, 0.0, 0.0, 0.0);
+        assert_no_gl_error();
     }
+}
 
-    fn create_texture_buffer(&self, texture: image::DynamicImage) -> GLuint {
-        let (width, height) = texture.dimensions();
-        unsafe {
-            let mut texture_buffer_name: GLuint = 0;
-            gl::GenTextures(1, &mut texture_buffer_name);
-            gl::ActiveTexture(gl::TEXTURE0);
-            gl::BindTexture(gl::TEXTURE_2D, texture_buffer_name);
-            gl::TexImage2D(
-                gl::TEXTURE_2D,
-                0,
-                gl::RGB as GLint,
-                width as GLsizei,
-                height as GLsizei,
-                0,
-                gl::RGB,
-                gl::UNSIGNED_BYTE,
-                // TODO: Literally no idea if this is right.
-                (*(texture.to_rgb())).as_ptr() as *const _,
-            );
-
-            // nearest
-            // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
-            // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
-
-            // linear
-            // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
-            // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
-
-            // anisotropic
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as GLint);
-            gl::GenerateMipmap(gl::TEXTURE_2D);
+// Blends everything drawn until the next `use_program` toward `color`; for debug visualizations.
+pub fn set_debug_color(program: &shaders::Program, color: glm::Vec3, strength: f32) {
+    unsafe {
+        gl::Uniform4f(program.get_uniform("u_DebugColor"), color.x, color.y, color.z, strength);
+        assert_no_gl_error();
+    }
+}
 
-            assert_no_gl_error();
+pub fn draw_mesh(program: &shaders::Program, mesh: &LoadedMesh) {
+    draw_mesh_range(program, mesh, 0, mesh.index_count as usize);
+}
 
-            texture_buffer_name
-        }
+pub fn draw_mesh_range(program: &shaders::Program, mesh: &LoadedMesh, first_index: usize, index_count: usize) {
+    unsafe {
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, mesh.texture_name);
+        gl::Uniform1i(program.get_uniform("u_TextureDiffuse"), 0);
+        gl::BindVertexArray(mesh.vao);
+        gl::DrawElements(
+            gl::TRIANGLES,
+            index_count as GLsizei,
+            gl::UNSIGNED_INT,
+            (first_index * size_of::<GLuint>()) as *const _);
+        assert_no_gl_error();
+
+        // TODO: Cleanup: unbind program/textures/VAOs/etc.
     }
+}
 
-    fn create_element_array_buffer(&self, indices: Vec<u32>) {
-        unsafe {
-            let mut index_buffer_name: GLuint = 0;
-            gl::GenBuffers(1, &mut index_buffer_name);
-            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_buffer_name);
-            gl::BufferData(
-                gl::ELEMENT_ARRAY_BUFFER,
-                (indices.len() * size_of::<u32>()) as GLsizeiptr,
-                indices.as_ptr() as *const _,
-                gl::STATIC_DRAW);
-            assert_no_gl_error();
-        }
+fn create_array_buffer<T: Flattenable>(program: &shaders::Program, attribute_name: &str, items: Vec<T>) -> GLuint {
+    let mut flattened_items: Vec<GLfloat> = vec![];
+    for i in items {
+        i.append_components_to(&mut flattened_items);
+    }
+
+    unsafe {
+        let mut array_buffer_name: GLuint = 0;
+        gl::GenBuffers(1, &mut array_buffer_name);
+        gl::BindBuffer(gl::ARRAY_BUFFER, array_buffer_name);
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            (flattened_items.len() * size_of::<GLfloat>()) as GLsizeiptr,
+            flattened_items.as_ptr() as *const _,
+            gl::STATIC_DRAW);
+        assert_no_gl_error();
+
+        let attribute_location = program.get_attrib(attribute_name) as GLuint;
+        gl::EnableVertexAttribArray(attribute_location);
+        gl::VertexAttribPointer(
+            attribute_location,
+            T::component_count() as GLint,
+            gl::FLOAT,
+            gl::FALSE as GLboolean,
+            0,
+            ptr::null());
+        assert_no_gl_error();
+
+        array_buffer_name
+    }
+}
+
+fn update_array_buffer<T: Flattenable>(buffer_name: GLuint, first_item: usize, items: Vec<T>) {
+    let mut flattened_items: Vec<GLfloat> = vec![];
+    for i in items {
+        i.append_components_to(&mut flattened_items);
+    }
+
+    let item_size = T::component_count() as usize * size_of::<GLfloat>();
+    unsafe {
+        gl::BindBuffer(gl::ARRAY_BUFFER, buffer_name);
+        gl::BufferSubData(
+            gl::ARRAY_BUFFER,
+            (first_item * item_size) as GLintptr,
+            (flattened_items.len() * size_of::<GLfloat>()) as GLsizeiptr,
+            flattened_items.as_ptr() as *const _);
+        assert_no_gl_error();
+    }
+}
+
+fn create_texture_buffer(texture: image::DynamicImage) -> GLuint {
+    let (width, height) = texture.dimensions();
+    unsafe {
+        let mut texture_buffer_name: GLuint = 0;
+        gl::GenTextures(1, &mut texture_buffer_name);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, texture_buffer_name);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGB as GLint,
+            width as GLsizei,
+            height as GLsizei,
+            0,
+            gl::RGB,
+            gl::UNSIGNED_BYTE,
+            // TODO: Literally no idea if this is right.
+            (*(texture.to_rgb())).as_ptr() as *const _,
+        );
+
+        // nearest
+        // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+        // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+
+        // linear
+        // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        // gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+
+        // anisotropic
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as GLint);
+        gl::GenerateMipmap(gl::TEXTURE_2D);
+
+        assert_no_gl_error();
+
+        texture_buffer_name
+    }
+}
+
+// Every image becomes one layer of a GL_TEXTURE_2D_ARRAY, so they all have to be the same size.
+pub fn create_texture_array(images: &[image::RgbImage]) -> GLuint {
+    assert!(!images.is_empty(), "texture array needs at least one layer");
+    let (width, height) = images[0].dimensions();
+    let mut pixels: Vec<u8> = Vec::with_capacity((width * height * 3) as usize * images.len());
+    for layer in images {
+        assert_eq!(layer.dimensions(), (width, height), "texture array layers must all be the same size");
+        pixels.extend_from_slice(&**layer);
+    }
+
+    unsafe {
+        let mut texture_array_name: GLuint = 0;
+        gl::GenTextures(1, &mut texture_array_name);
+        gl::BindTexture(gl::TEXTURE_2D_ARRAY, texture_array_name);
+        gl::TexImage3D(
+            gl::TEXTURE_2D_ARRAY,
+            0,
+            gl::RGB as GLint,
+            width as GLsizei,
+            height as GLsizei,
+            images.len() as GLsizei,
+            0,
+            gl::RGB,
+            gl::UNSIGNED_BYTE,
+            pixels.as_ptr() as *const _);
+
+        gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_S, gl::REPEAT as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_T, gl::REPEAT as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as GLint);
+        gl::GenerateMipmap(gl::TEXTURE_2D_ARRAY);
+        assert_no_gl_error();
+
+        texture_array_name
+    }
+}
+
+fn create_element_array_buffer(indices: Vec<u32>) -> GLuint {
+    unsafe {
+        let mut index_buffer_name: GLuint = 0;
+        gl::GenBuffers(1, &mut index_buffer_name);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_buffer_name);
+        gl::BufferData(
+            gl::ELEMENT_ARRAY_BUFFER,
+            (indices.len() * size_of::<u32>()) as GLsizeiptr,
+            indices.as_ptr() as *const _,
+            gl::STATIC_DRAW);
+        assert_no_gl_error();
+
+        index_buffer_name
     }
 }
@@ -0,0 +1,242 @@
+use std::{ error, fmt, fs, io, path };
+use std::io::Read;
+use serde_json;
+use wavefront_obj::mtl;
+
+use terrain::{ Heightmap, VertexMaterials };
+use terrain::mesh;
+use terrain::noise::{ Fractal, FractalSettings, NoiseFn };
+use terrain::random::Rng;
+
+// Everything the classifier looks at is in [0, 1] except slope, which is in degrees.
+fn any_range() -> (f32, f32) {
+    (0.0, 1.0)
+}
+
+fn any_slope() -> (f32, f32) {
+    (0.0, 90.0)
+}
+
+fn default_specular() -> [f64; 3] {
+    [0.0, 0.0, 0.0]
+}
+
+fn default_specular_exponent() -> f32 {
+    1.0
+}
+
+// One row of the lookup table. A vertex gets the first biome whose ranges (inclusive) all contain it; ranges that are
+// left out match anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Biome {
+    pub name: String,
+    // Height between the lowest (0) and highest (1) point of the heightmap, or with a sea level, between that and the
+    // highest point.
+    #[serde(default = "any_range")]
+    pub elevation: (f32, f32),
+    // Whether the vertex has to be below the sea level (true) or above it (false). Left out, it can be either.
+    #[serde(default)]
+    pub underwater: Option<bool>,
+    #[serde(default = "any_slope")]
+    pub slope: (f32, f32),
+    #[serde(default = "any_range")]
+    pub moisture: (f32, f32),
+    #[serde(default = "any_range")]
+    pub temperature: (f32, f32),
+    pub ambient: [f64; 3],
+    pub diffuse: [f64; 3],
+    #[serde(default = "default_specular")]
+    pub specular: [f64; 3],
+    #[serde(default = "default_specular_exponent")]
+    pub specular_exponent: f32,
+}
+
+fn in_range(value: f32, range: (f32, f32)) -> bool {
+    value >= range.0 && value <= range.1
+}
+
+fn color(rgb: [f64; 3]) -> mtl::Color {
+    mtl::Color { r: rgb[0], g: rgb[1], b: rgb[2] }
+}
+
+impl Biome {
+    fn matches(&self, climate: &Climate) -> bool {
+        self.underwater.map_or(true, |underwater| underwater == climate.underwater) &&
+            in_range(climate.elevation, self.elevation) &&
+            in_range(climate.slope, self.slope) &&
+            in_range(climate.moisture, self.moisture) &&
+            in_range(climate.temperature, self.temperature)
+    }
+}
+
+// Loaded at runtime from JSON (see biomes.json at the top of the repo), so it can be tuned without recompiling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BiomeTable {
+    // In order of precedence. The last one is also used for anything nothing else matched.
+    pub biomes: Vec<Biome>,
+}
+
+#[derive(Debug)]
+pub enum BiomeTableError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    Empty,
+}
+
+impl fmt::Display for BiomeTableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BiomeTableError::Io(ref e) => write!(f, "couldn't read biome table: {}", e),
+            BiomeTableError::Parse(ref e) => write!(f, "couldn't parse biome table: {}", e),
+            BiomeTableError::Empty => write!(f, "biome table doesn't have any biomes"),
+        }
+    }
+}
+
+impl error::Error for BiomeTableError {
+    fn description(&self) -> &str {
+        match *self {
+            BiomeTableError::Io(_) => "I/O error reading biome table",
+            BiomeTableError::Parse(_) => "error parsing biome table",
+            BiomeTableError::Empty => "biome table is empty",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            BiomeTableError::Io(ref e) => Some(e),
+            BiomeTableError::Parse(ref e) => Some(e),
+            BiomeTableError::Empty => None,
+        }
+    }
+}
+
+impl From<io::Error> for BiomeTableError {
+    fn from(e: io::Error) -> BiomeTableError {
+        BiomeTableError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BiomeTableError {
+    fn from(e: serde_json::Error) -> BiomeTableError {
+        BiomeTableError::Parse(e)
+    }
+}
+
+impl BiomeTable {
+    pub fn load(path: &path::Path) -> Result<BiomeTable, BiomeTableError> {
+        let mut contents = String::new();
+        fs::File::open(path)?.read_to_string(&mut contents)?;
+        let table: BiomeTable = serde_json::from_str(&contents)?;
+        if table.biomes.is_empty() {
+            return Err(BiomeTableError::Empty);
+        }
+        debug!("loaded {} biomes from {:?}", table.biomes.len(), path);
+        Ok(table)
+    }
+
+    // Indices of the named biomes, skipping names that aren't in the table.
+    pub fn indices_of(&self, names: &[&str]) -> Vec<usize> {
+        names
+            .iter()
+            .filter_map(|name| self.biomes.iter().position(|biome| biome.name == *name))
+            .collect()
+    }
+
+    pub fn lookup(&self, climate: &Climate) -> usize {
+        self.biomes
+            .iter()
+            .position(|b| b.matches(climate))
+            .unwrap_or(self.biomes.len() - 1)
+    }
+
+    // Per-vertex colors for biome indices as returned by `classify`.
+    pub fn vertex_materials(&self, biomes: &[usize]) -> VertexMaterials {
+        VertexMaterials {
+            ambient: biomes.iter().map(|&b| color(self.biomes[b].ambient)).collect(),
+            diffuse: biomes.iter().map(|&b| color(self.biomes[b].diffuse)).collect(),
+            specular: biomes.iter().map(|&b| color(self.biomes[b].specular)).collect(),
+            specular_exponents: biomes.iter().map(|&b| self.biomes[b].specular_exponent).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Climate {
+    pub elevation: f32,
+    pub slope: f32,
+    pub moisture: f32,
+    pub temperature: f32,
+    pub underwater: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClimateSettings {
+    pub seed: u64,
+    // Of the moisture and temperature noise, in cycles per world unit.
+    pub frequency: f64,
+    // How much colder the highest point is than the lowest, on the same [0, 1] scale as temperature.
+    pub lapse_rate: f32,
+    // Heights below this are underwater. Should match the terrain's sea, if it has one.
+    pub sea_level: Option<f32>,
+}
+
+impl Default for ClimateSettings {
+    fn default() -> ClimateSettings {
+        ClimateSettings {
+            seed: 0,
+            frequency: 0.01,
+            lapse_rate: 0.5,
+            sea_level: None,
+        }
+    }
+}
+
+fn climate_noise(seed: u64, stream: u64, frequency: f64) -> Fractal {
+    Fractal::new(FractalSettings {
+        seed: Rng::derive(seed, stream).next_u64(),
+        octaves: 3,
+        frequency: frequency,
+        ..Default::default()
+    })
+}
+
+fn unit(noise: &Fractal, x: f64, z: f64) -> f32 {
+    (0.5 + 0.5 * noise.sample(x, z)).max(0.0).min(1.0) as f32
+}
+
+// Elevation and slope come from the heightmap; moisture and temperature from low-frequency noise, with temperature
+// falling off with elevation. With a sea level, elevation starts from there, so that beaches line the coast.
+pub fn climate(heightmap: &Heightmap, settings: &ClimateSettings) -> Vec<Climate> {
+    let moisture = climate_noise(settings.seed, 1, settings.frequency);
+    let temperature = climate_noise(settings.seed, 2, settings.frequency);
+    let (lowest, max) = heightmap.range();
+    let min = settings.sea_level.map_or(lowest, |level| level.min(max));
+    let spacing = heightmap.spacing() as f64;
+
+    let mut climates = Vec::with_capacity(heightmap.width() * heightmap.depth());
+    for z in 0..heightmap.depth() {
+        for x in 0..heightmap.width() {
+            let height = heightmap.get(x, z);
+            let elevation = if max > min { ((height - min) / (max - min)).max(0.0) } else { 0.0 };
+            let normal = mesh::vertex_normal(heightmap, x, z);
+            let (world_x, world_z) = (x as f64 * spacing, z as f64 * spacing);
+            climates.push(Climate {
+                elevation: elevation,
+                slope: normal.y.max(-1.0).min(1.0).acos().to_degrees(),
+                moisture: unit(&moisture, world_x, world_z),
+                temperature: (unit(&temperature, world_x, world_z) - settings.lapse_rate * elevation).max(0.0),
+                underwater: settings.sea_level.map_or(false, |level| height < level),
+            });
+        }
+    }
+    climates
+}
+
+// The index into `table.biomes` of every vertex, in the same row-major order as the heightmap.
+pub fn classify(heightmap: &Heightmap, table: &BiomeTable, settings: &ClimateSettings) -> Vec<usize> {
+    climate(heightmap, settings)
+        .iter()
+        .map(|c| table.lookup(c))
+        .collect()
+}
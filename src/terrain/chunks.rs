@@ -0,0 +1,288 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ channel, Receiver, Sender, TryRecvError };
+use std::thread;
+use glm;
+use num_traits::identities::One;
+
+use camera;
+use objects;
+use shaders;
+use terrain::TERRAIN_MATERIAL;
+use terrain::lod;
+use terrain::noise::{ heightmap_from_noise, NoiseFn };
+
+pub type ChunkCoord = (i32, i32);
+
+#[derive(Debug, Clone)]
+pub struct ChunkSettings {
+    // Cells along each side of a chunk.
+    pub chunk_size: usize,
+    pub spacing: f32,
+    pub amplitude: f32,
+    // Chunks whose centers are within this many chunks of the camera are kept loaded.
+    pub view_distance: i32,
+    // Number of geomipmap levels; chunk_size has to be divisible by 2^(lod_levels - 1).
+    pub lod_levels: usize,
+    // Chunks closer than this are drawn at full detail, and detail halves every time the distance doubles.
+    pub lod_distance: f32,
+    // How far the crack-hiding skirts hang below the chunk edges.
+    pub skirt_depth: f32,
+    pub worker_threads: usize,
+    // Caps GL work per frame so that a burst of finished chunks can't cause a hitch.
+    pub max_uploads_per_frame: usize,
+}
+
+impl Default for ChunkSettings {
+    fn default() -> ChunkSettings {
+        ChunkSettings {
+            chunk_size: 64,
+            spacing: 0.5,
+            amplitude: 12.0,
+            view_distance: 8,
+            lod_levels: 5,
+            lod_distance: 48.0,
+            skirt_depth: 4.0,
+            worker_threads: 2,
+            max_uploads_per_frame: 2,
+        }
+    }
+}
+
+impl ChunkSettings {
+    pub fn chunk_extent(&self) -> f32 {
+        self.chunk_size as f32 * self.spacing
+    }
+
+    pub fn chunk_containing(&self, position: glm::Vec3) -> ChunkCoord {
+        let extent = self.chunk_extent();
+        ((position.x / extent).floor() as i32, (position.z / extent).floor() as i32)
+    }
+
+    pub fn chunk_origin(&self, coord: ChunkCoord) -> glm::Vec3 {
+        let extent = self.chunk_extent();
+        glm::vec3(coord.0 as f32 * extent, 0.0, coord.1 as f32 * extent)
+    }
+
+    // Far enough to see the farthest loaded chunk, for the camera's far plane.
+    pub fn visible_distance(&self) -> f32 {
+        (self.view_distance + 1) as f32 * self.chunk_extent()
+    }
+}
+
+struct GeneratedChunk {
+    coord: ChunkCoord,
+    mesh: lod::LodMesh,
+}
+
+struct LoadedChunk {
+    mesh: objects::LoadedMesh,
+    lod_ranges: Vec<(usize, usize)>,
+}
+
+fn generate_chunk(noise: &(NoiseFn + Send + Sync), settings: &ChunkSettings, coord: ChunkCoord) -> GeneratedChunk {
+    // One extra vertex on every side so that normals along chunk edges agree with the neighboring chunk's.
+    let origin = settings.chunk_origin(coord);
+    let padded = heightmap_from_noise(
+        noise,
+        settings.chunk_size + 3,
+        settings.chunk_size + 3,
+        settings.spacing,
+        ((origin.x - settings.spacing) as f64, (origin.z - settings.spacing) as f64),
+        settings.amplitude);
+
+    GeneratedChunk {
+        coord: coord,
+        mesh: lod::build_lod_mesh(&padded, 1, settings.lod_levels, settings.skirt_depth, &TERRAIN_MATERIAL),
+    }
+}
+
+// Infinite terrain, generated a chunk at a time on worker threads as the camera moves around. Only uploading to (and
+// freeing from) the GPU happens on the render thread.
+pub struct ChunkedTerrain<'a> {
+    settings: ChunkSettings,
+    program: &'a shaders::Program,
+    loaded: HashMap<ChunkCoord, LoadedChunk>,
+    pending: HashSet<ChunkCoord>,
+    wanted: HashSet<ChunkCoord>,
+    requests: Option<Sender<ChunkCoord>>,
+    results: Receiver<GeneratedChunk>,
+    workers: Vec<thread::JoinHandle<()>>,
+    camera_position: glm::Vec3,
+    debug_mode: bool,
+}
+
+impl <'a> ChunkedTerrain<'a> {
+    pub fn new(noise: Arc<NoiseFn + Send + Sync>, settings: ChunkSettings, program: &'a shaders::Program) -> ChunkedTerrain<'a> {
+        assert!(settings.worker_threads > 0, "chunked terrain needs at least one worker thread");
+        assert!(
+            settings.chunk_size.is_power_of_two() && (1 << (settings.lod_levels - 1)) <= settings.chunk_size,
+            "chunk size must be a power of two with room for {} levels of detail", settings.lod_levels);
+
+        let (request_sender, request_receiver) = channel::<ChunkCoord>();
+        let (result_sender, result_receiver) = channel::<GeneratedChunk>();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+
+        let workers = (0..settings.worker_threads)
+            .map(|i| {
+                let noise = noise.clone();
+                let settings = settings.clone();
+                let requests = request_receiver.clone();
+                let results = result_sender.clone();
+                thread::Builder::new()
+                    .name(format!("chunk-worker-{}", i))
+                    .spawn(move || {
+                        loop {
+                            // Hold the lock only long enough to take one request.
+                            let request = requests.lock().unwrap().recv();
+                            match request {
+                                Ok(coord) => {
+                                    if results.send(generate_chunk(&*noise, &settings, coord)).is_err() {
+                                        break;
+                                    }
+                                },
+                                // The terrain was dropped.
+                                Err(_) => break,
+                            }
+                        }
+                    })
+                    .expect("couldn't spawn chunk worker thread")
+            })
+            .collect();
+
+        ChunkedTerrain {
+            settings: settings,
+            program: program,
+            loaded: HashMap::new(),
+            pending: HashSet::new(),
+            wanted: HashSet::new(),
+            requests: Some(request_sender),
+            results: result_receiver,
+            workers: workers,
+            camera_position: glm::vec3(0.0, 0.0, 0.0),
+            debug_mode: false,
+        }
+    }
+
+    pub fn settings(&self) -> &ChunkSettings {
+        &self.settings
+    }
+
+    fn chunks_in_range(&self, center: ChunkCoord) -> Vec<ChunkCoord> {
+        let radius = self.settings.view_distance;
+        let mut coords = vec![];
+        for dz in -radius..(radius + 1) {
+            for dx in -radius..(radius + 1) {
+                if dx * dx + dz * dz <= radius * radius {
+                    coords.push((center.0 + dx, center.1 + dz));
+                }
+            }
+        }
+        // Nearest first, so the ground under the camera shows up before the horizon does.
+        coords.sort_by_key(|&(x, z)| (x - center.0) * (x - center.0) + (z - center.1) * (z - center.1));
+        coords
+    }
+
+    fn request_missing_chunks(&mut self, center: ChunkCoord) {
+        let in_range = self.chunks_in_range(center);
+        self.wanted = in_range.iter().cloned().collect();
+
+        for coord in in_range {
+            if !self.loaded.contains_key(&coord) && !self.pending.contains(&coord) {
+                self.requests.as_ref().unwrap().send(coord).expect("chunk workers died");
+                self.pending.insert(coord);
+            }
+        }
+    }
+
+    fn evict_distant_chunks(&mut self) {
+        let evicted: Vec<ChunkCoord> = self.loaded
+            .keys()
+            .filter(|coord| !self.wanted.contains(*coord))
+            .cloned()
+            .collect();
+
+        for coord in evicted {
+            debug!("evicting terrain chunk {:?}", coord);
+            self.loaded.remove(&coord).unwrap().mesh.delete();
+        }
+    }
+
+    fn upload_finished_chunks(&mut self) {
+        for _ in 0..self.settings.max_uploads_per_frame {
+            match self.results.try_recv() {
+                Ok(chunk) => {
+                    self.pending.remove(&chunk.coord);
+                    // We may have moved on while this was being generated.
+                    if self.wanted.contains(&chunk.coord) {
+                        debug!("uploading terrain chunk {:?}", chunk.coord);
+                        let mesh = objects::upload_mesh(self.program, chunk.mesh.data, objects::white_texture());
+                        self.loaded.insert(chunk.coord, LoadedChunk {
+                            mesh: mesh,
+                            lod_ranges: chunk.mesh.ranges,
+                        });
+                    }
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => panic!("chunk workers died"),
+            }
+        }
+    }
+
+    fn light(&self) -> objects::Light {
+        // Hover a light well above the camera so that nearby terrain is always lit.
+        let height = self.settings.amplitude + self.settings.chunk_extent() * self.settings.view_distance as f32;
+        objects::Light {
+            position: self.camera_position + glm::vec3(0.0, height, 0.0),
+            color: glm::vec3(1.0, 1.0, 1.0),
+            power: height * height * 1.5,
+        }
+    }
+}
+
+impl <'a> objects::Renderable for ChunkedTerrain<'a> {
+    fn update(&mut self, camera: &camera::Camera) {
+        self.camera_position = camera.position();
+        let center = self.settings.chunk_containing(self.camera_position);
+        self.request_missing_chunks(center);
+        self.evict_distant_chunks();
+        self.upload_finished_chunks();
+    }
+
+    fn set_debug_mode(&mut self, enabled: bool) {
+        self.debug_mode = enabled;
+    }
+
+    fn render(&mut self, view: glm::Mat4, projection: glm::Mat4) {
+        let light = self.light();
+        let half_extent = self.settings.chunk_extent() / 2.0;
+        for (&coord, chunk) in self.loaded.iter() {
+            let origin = self.settings.chunk_origin(coord);
+            let to_center = origin + glm::vec3(half_extent, 0.0, half_extent) - self.camera_position;
+            let distance = (to_center.x * to_center.x + to_center.z * to_center.z).sqrt();
+            let level = lod::select_level(distance, self.settings.lod_distance, chunk.lod_ranges.len());
+            let (first_index, index_count) = chunk.lod_ranges[level];
+
+            let model = glm::ext::translate(&glm::Mat4::one(), origin);
+            objects::use_program(self.program, model, view, projection, &light);
+            if self.debug_mode {
+                let (r, g, b) = lod::lod_debug_color(level);
+                objects::set_debug_color(self.program, glm::vec3(r, g, b), 0.8);
+            }
+            objects::draw_mesh_range(self.program, &chunk.mesh, first_index, index_count);
+        }
+    }
+}
+
+impl <'a> Drop for ChunkedTerrain<'a> {
+    fn drop(&mut self) {
+        // Closing the request channel tells the workers to stop.
+        self.requests = None;
+        for worker in self.workers.drain(..) {
+            worker.join().expect("chunk worker panicked");
+        }
+        for (_, chunk) in self.loaded.drain() {
+            chunk.mesh.delete();
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::path;
+use std::time::Instant;
+use gl;
+use glm;
+
+use objects;
+use shaders;
+use terrain::{ Filter, Heightmap };
+use terrain::import::{ self, ImportError, ImportSettings };
+use terrain::lakes::LAKE_MATERIAL;
+use terrain::mesh;
+use terrain::noise::{ Fractal, FractalSettings, NoiseFn };
+use util::assert_no_gl_error;
+
+#[derive(Debug, Clone)]
+pub enum MaskShape {
+    // Falls off with the distance from the center, for round islands.
+    Radial,
+    // Falls off toward the nearest edge, for islands that fill the map.
+    Square,
+    // A grayscale image stretched over the whole heightmap, white for land and black for sea; see load_image_mask.
+    Image(Heightmap),
+}
+
+// Sinks the heightmap toward `floor` away from its center, so it becomes an island (or, with an image, any coastline)
+// instead of a piece of an endless field. Heights are blended as floor + (height - floor) * mask.
+#[derive(Debug, Clone)]
+pub struct FalloffMask {
+    pub shape: MaskShape,
+    // Distances from the center as fractions of the way to the edge: land is untouched inside `inner`, and has sunk
+    // all the way to `floor` by `outer`. Not used for images.
+    pub inner: f32,
+    pub outer: f32,
+    pub floor: f32,
+    // How far noise pushes the coast in and out, on the same scale as `inner` and `outer`. Not used for images.
+    pub roughness: f32,
+    pub noise: FractalSettings,
+}
+
+impl Default for FalloffMask {
+    fn default() -> FalloffMask {
+        FalloffMask {
+            shape: MaskShape::Radial,
+            inner: 0.3,
+            outer: 0.9,
+            floor: 0.0,
+            roughness: 0.2,
+            noise: FractalSettings {
+                octaves: 4,
+                frequency: 0.02,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+// Loads an image as a MaskShape::Image, with black as 0 and white as 1.
+pub fn load_image_mask(path: &path::Path) -> Result<MaskShape, ImportError> {
+    let settings = ImportSettings {
+        vertical_scale: 1.0,
+        vertical_offset: 0.0,
+        spacing: 1.0,
+    };
+    import::load_heightmap(path, &settings).map(MaskShape::Image)
+}
+
+impl FalloffMask {
+    // In [0, 1] for vertex (x, z): 1 keeps the height as it is, 0 sinks it to the floor.
+    fn value(&self, heightmap: &Heightmap, noise: &Fractal, x: usize, z: usize) -> f32 {
+        let u = x as f32 / (heightmap.width() - 1) as f32;
+        let v = z as f32 / (heightmap.depth() - 1) as f32;
+        let (cx, cz) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+        let distance = match self.shape {
+            MaskShape::Radial => (cx * cx + cz * cz).sqrt(),
+            MaskShape::Square => cx.abs().max(cz.abs()),
+            MaskShape::Image(ref image) => {
+                let (extent_x, extent_z) = image.extent();
+                return image.height_at(u * extent_x, v * extent_z).max(0.0).min(1.0);
+            },
+        };
+        let spacing = heightmap.spacing() as f64;
+        let coast = distance + self.roughness * noise.sample(x as f64 * spacing, z as f64 * spacing) as f32;
+        let t = ((coast - self.inner) / (self.outer - self.inner).max(1e-6)).max(0.0).min(1.0);
+        1.0 - t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Filter for FalloffMask {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        let noise = Fractal::new(self.noise.clone());
+        for z in 0..heightmap.depth() {
+            for x in 0..heightmap.width() {
+                let mask = self.value(heightmap, &noise, x, z);
+                let height = heightmap.get(x, z);
+                heightmap.set(x, z, self.floor + (height - self.floor) * mask);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SeaSettings {
+    // In the heightmap's units.
+    pub level: f32,
+    // How far the water reaches past the terrain's edges, as a fraction of its size, so the sea runs on past the coast.
+    pub margin: f32,
+    // The waves only move the water's vertices, so it needs several of them across each wave or the crests alias into
+    // jagged ridges. Over the terrain, the grid is made fine enough for this many along the shorter wave.
+    pub vertices_per_wave: f32,
+    // The waves are two crossing sine waves; these are the larger one's. The smaller is half as high, and shorter (see
+    // SHORT_WAVE_RATIO).
+    pub wave_height: f32,
+    pub wave_length: f32,
+    // In units per second.
+    pub wave_speed: f32,
+}
+
+impl Default for SeaSettings {
+    fn default() -> SeaSettings {
+        SeaSettings {
+            level: 0.0,
+            margin: 1.0,
+            vertices_per_wave: 8.0,
+            wave_height: 0.1,
+            wave_length: 6.0,
+            wave_speed: 1.5,
+        }
+    }
+}
+
+// How many times shorter the smaller wave is than `wave_length`. Has to match shaders/water.vert.
+const SHORT_WAVE_RATIO: f32 = 1.7;
+// Caps the fine part of the grid along each side, so that a large imported terrain doesn't get millions of vertices
+// of sea.
+const MAX_FINE_QUADS: usize = 512;
+
+// A flat sheet of water at sea level, moved by waves in its vertex shader (shaders/water.vert).
+pub struct Sea<'a> {
+    settings: SeaSettings,
+    program: &'a shaders::Program,
+    // Of the terrain it surrounds, in its local units.
+    extent: (f32, f32),
+    mesh: Option<objects::LoadedMesh>,
+    start: Instant,
+}
+
+impl <'a> Sea<'a> {
+    pub fn new(program: &'a shaders::Program, heightmap: &Heightmap, settings: SeaSettings) -> Sea<'a> {
+        Sea {
+            settings: settings,
+            program: program,
+            extent: heightmap.extent(),
+            mesh: Option::None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        self.settings.level
+    }
+
+    // Where the grid lines cross one axis of a terrain `extent` units long, from the outer edge of the margin on one
+    // side to the other. They're fine enough for vertices_per_wave over the terrain, where the camera usually is, but
+    // only one per long wave out in the margin, so that the sheet doesn't grow with the square of its margin.
+    fn grid_lines(&self, extent: f32) -> Vec<f32> {
+        let spacing = self.settings.wave_length / SHORT_WAVE_RATIO / self.settings.vertices_per_wave;
+        let fine = ((extent / spacing).ceil().max(1.0) as usize).min(MAX_FINE_QUADS);
+        let margin = extent * self.settings.margin;
+        let coarse = if margin > 0.0 { (margin / self.settings.wave_length).ceil().max(1.0) as usize } else { 0 };
+
+        let mut lines = Vec::with_capacity(fine + 2 * coarse + 1);
+        lines.extend((0..coarse).map(|i| -margin + margin * i as f32 / coarse as f32));
+        lines.extend((0..(fine + 1)).map(|i| extent * i as f32 / fine as f32));
+        lines.extend((1..(coarse + 1)).map(|i| extent + margin * i as f32 / coarse as f32));
+        lines
+    }
+
+    fn mesh_data(&self) -> objects::MeshData {
+        let (extent_x, extent_z) = self.extent;
+        let (lines_x, lines_z) = (self.grid_lines(extent_x), self.grid_lines(extent_z));
+        let (low_x, low_z) = (lines_x[0], lines_z[0]);
+        let size_x = lines_x[lines_x.len() - 1] - low_x;
+        let size_z = lines_z[lines_z.len() - 1] - low_z;
+
+        let mut positions = Vec::with_capacity(lines_x.len() * lines_z.len());
+        let mut uvs = Vec::with_capacity(lines_x.len() * lines_z.len());
+        for &z in lines_z.iter() {
+            for &x in lines_x.iter() {
+                positions.push(glm::vec3(x, self.settings.level, z));
+                uvs.push(glm::vec2((x - low_x) / size_x, (z - low_z) / size_z));
+            }
+        }
+        let normals = vec![glm::vec3(0.0, 1.0, 0.0); positions.len()];
+        let indices = mesh::grid_indices(lines_x.len(), lines_z.len());
+        objects::MeshData::with_material(positions, normals, uvs, indices, &LAKE_MATERIAL)
+    }
+
+    pub fn draw(&mut self, model: glm::Mat4, view: glm::Mat4, projection: glm::Mat4, light: &objects::Light) {
+        if self.mesh.is_none() {
+            let data = self.mesh_data();
+            self.mesh = Some(objects::upload_mesh(self.program, data, objects::white_texture()));
+        }
+
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0;
+        objects::use_program(self.program, model, view, projection, light);
+        unsafe {
+            gl::Uniform1f(self.program.get_uniform("u_Time"), time);
+            gl::Uniform1f(self.program.get_uniform("u_WaveHeight"), self.settings.wave_height);
+            gl::Uniform1f(self.program.get_uniform("u_WaveLength"), self.settings.wave_length);
+            gl::Uniform1f(self.program.get_uniform("u_WaveSpeed"), self.settings.wave_speed);
+            assert_no_gl_error();
+        }
+        objects::draw_mesh(self.program, self.mesh.as_ref().unwrap());
+    }
+}
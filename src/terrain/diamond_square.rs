@@ -0,0 +1,249 @@
+use terrain::Heightmap;
+use terrain::random::Rng;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiamondSquareSettings {
+    pub seed: u64,
+    // The grid is (2^size_exponent + 1) vertices on a side.
+    pub size_exponent: u32,
+    pub spacing: f32,
+    // Maximum displacement applied in the first pass.
+    pub amplitude: f32,
+    // Multiplier applied to the displacement after every pass; 0.5 is the classic fractal look, higher is rougher.
+    pub roughness: f32,
+    // Heights for the (0, 0), (max, 0), (0, max) and (max, max) corners. Random when unset.
+    pub corners: Option<[f32; 4]>,
+    // Treat the grid as a torus so that it tiles seamlessly. Since all four corners are then the same point, only
+    // the first entry of `corners` is used.
+    pub wrap: bool,
+}
+
+impl Default for DiamondSquareSettings {
+    fn default() -> DiamondSquareSettings {
+        DiamondSquareSettings {
+            seed: 0,
+            size_exponent: 8,
+            spacing: 1.0,
+            amplitude: 10.0,
+            roughness: 0.5,
+            corners: Option::None,
+            wrap: false,
+        }
+    }
+}
+
+struct Grid {
+    size: usize,
+    wrap: bool,
+    heights: Vec<f32>,
+}
+
+impl Grid {
+    fn get(&self, x: usize, z: usize) -> f32 {
+        self.heights[z * self.size + x]
+    }
+
+    fn set(&mut self, x: usize, z: usize, height: f32) {
+        self.heights[z * self.size + x] = height;
+    }
+
+    // Average of whichever of the four points at `offset` along the axes exist. In wrap mode they always exist.
+    fn average_of_neighbors(&self, x: usize, z: usize, offset: usize) -> f32 {
+        let last = self.size - 1;
+        let mut total = 0.0;
+        let mut count = 0;
+
+        let candidates = [
+            (x as isize - offset as isize, z as isize),
+            (x as isize + offset as isize, z as isize),
+            (x as isize, z as isize - offset as isize),
+            (x as isize, z as isize + offset as isize),
+        ];
+        for &(cx, cz) in candidates.iter() {
+            if self.wrap {
+                let period = last as isize;
+                let wx = ((cx % period) + period) % period;
+                let wz = ((cz % period) + period) % period;
+                total += self.get(wx as usize, wz as usize);
+                count += 1;
+            } else if cx >= 0 && cz >= 0 && cx <= last as isize && cz <= last as isize {
+                total += self.get(cx as usize, cz as usize);
+                count += 1;
+            }
+        }
+
+        total / count as f32
+    }
+
+    // In wrap mode we only compute the first `size - 1` rows and columns; the last ones duplicate the first.
+    fn copy_wrapped_edges(&mut self) {
+        let last = self.size - 1;
+        for i in 0..self.size {
+            let top = self.get(i, 0);
+            self.set(i, last, top);
+            let left = self.get(0, i);
+            self.set(last, i, left);
+        }
+    }
+}
+
+pub fn generate(settings: &DiamondSquareSettings) -> Heightmap {
+    assert!(settings.size_exponent >= 1, "diamond-square needs a size exponent of at least 1");
+    assert!(settings.size_exponent <= 14, "diamond-square size exponent {} is unreasonably large", settings.size_exponent);
+
+    let size = (1usize << settings.size_exponent) + 1;
+    let last = size - 1;
+    let mut rng = Rng::new(settings.seed);
+    let mut grid = Grid {
+        size: size,
+        wrap: settings.wrap,
+        heights: vec![0.0; size * size],
+    };
+
+    let corners = match settings.corners {
+        Some(corners) => corners,
+        None => {
+            let mut corners = [0.0; 4];
+            for c in corners.iter_mut() {
+                *c = rng.range(-settings.amplitude as f64, settings.amplitude as f64) as f32;
+            }
+            corners
+        },
+    };
+    if settings.wrap {
+        if settings.corners.is_some() && corners.iter().any(|&c| c != corners[0]) {
+            warn!("diamond-square in wrap mode only uses the first corner height; ignoring the others");
+        }
+        for &(x, z) in [(0, 0), (last, 0), (0, last), (last, last)].iter() {
+            grid.set(x, z, corners[0]);
+        }
+    } else {
+        grid.set(0, 0, corners[0]);
+        grid.set(last, 0, corners[1]);
+        grid.set(0, last, corners[2]);
+        grid.set(last, last, corners[3]);
+    }
+
+    let mut step = last;
+    let mut amplitude = settings.amplitude as f64;
+    while step > 1 {
+        let half = step / 2;
+
+        // Diamond step: the center of every square gets the average of its corners.
+        let mut z = half;
+        while z < last {
+            let mut x = half;
+            while x < last {
+                let average = (
+                    grid.get(x - half, z - half) +
+                    grid.get(x + half, z - half) +
+                    grid.get(x - half, z + half) +
+                    grid.get(x + half, z + half)
+                ) / 4.0;
+                grid.set(x, z, average + rng.range(-amplitude, amplitude) as f32);
+                x += step;
+            }
+            z += step;
+        }
+
+        // Square step: the midpoint of every edge gets the average of its (up to) four neighbors.
+        let mut z = 0;
+        while z <= last {
+            let mut x = if (z / half) % 2 == 0 { half } else { 0 };
+            while x <= last {
+                if !(settings.wrap && (x == last || z == last)) {
+                    let average = grid.average_of_neighbors(x, z, half);
+                    grid.set(x, z, average + rng.range(-amplitude, amplitude) as f32);
+                }
+                x += step;
+            }
+            z += half;
+        }
+
+        if settings.wrap {
+            grid.copy_wrapped_edges();
+        }
+
+        amplitude *= settings.roughness as f64;
+        step = half;
+    }
+
+    Heightmap::from_heights(size, size, settings.spacing, grid.heights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(seed: u64) -> DiamondSquareSettings {
+        DiamondSquareSettings {
+            seed: seed,
+            size_exponent: 5,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fixed_corners_keep_their_heights() {
+        let heightmap = generate(&DiamondSquareSettings {
+            corners: Some([1.0, -2.0, 3.5, 7.25]),
+            ..settings(3)
+        });
+        assert_eq!((heightmap.width(), heightmap.depth()), (33, 33));
+        assert_eq!(heightmap.get(0, 0), 1.0);
+        assert_eq!(heightmap.get(32, 0), -2.0);
+        assert_eq!(heightmap.get(0, 32), 3.5);
+        assert_eq!(heightmap.get(32, 32), 7.25);
+    }
+
+    #[test]
+    fn wrapped_terrain_tiles() {
+        let heightmap = generate(&DiamondSquareSettings {
+            wrap: true,
+            ..settings(9)
+        });
+        let last = heightmap.width() - 1;
+        for i in 0..heightmap.width() {
+            assert_eq!(heightmap.get(i, 0), heightmap.get(i, last), "column {}", i);
+            assert_eq!(heightmap.get(0, i), heightmap.get(last, i), "row {}", i);
+        }
+        // Tiling shouldn't come from the terrain being flat.
+        let (min, max) = heightmap.range();
+        assert!(max - min > 1.0);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_terrain() {
+        let bits = |seed: u64| generate(&settings(seed)).heights().iter().map(|h| h.to_bits()).collect::<Vec<u32>>();
+        assert_eq!(bits(42), bits(42));
+        assert!(bits(42) != bits(43));
+    }
+
+    #[test]
+    fn smallest_size_works() {
+        let heightmap = generate(&DiamondSquareSettings {
+            size_exponent: 1,
+            ..settings(1)
+        });
+        assert_eq!((heightmap.width(), heightmap.depth()), (3, 3));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1")]
+    fn rejects_a_zero_size_exponent() {
+        generate(&DiamondSquareSettings {
+            size_exponent: 0,
+            ..settings(1)
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "unreasonably large")]
+    fn rejects_a_huge_size_exponent() {
+        generate(&DiamondSquareSettings {
+            size_exponent: 15,
+            ..settings(1)
+        });
+    }
+}
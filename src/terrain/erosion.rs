@@ -0,0 +1,438 @@
+use std::mem;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use scoped_threadpool::Pool;
+use wavefront_obj::mtl;
+
+use terrain::{ Filter, Heightmap };
+use terrain::parallel;
+use terrain::random::Rng;
+
+// Droplet-based hydraulic erosion, after Hans Theobald Beyer's "Implementation of a method for hydraulic erosion".
+// Positions, the erosion radius and droplet movement are all measured in cells rather than world units.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HydraulicErosionSettings {
+    pub seed: u64,
+    // Number of droplets to simulate.
+    pub iterations: u32,
+    // Maximum number of steps a single droplet takes before it's discarded.
+    pub max_lifetime: u32,
+    // 0 makes droplets follow the gradient exactly, 1 makes them ignore it entirely.
+    pub inertia: f32,
+    pub sediment_capacity_factor: f32,
+    // Keeps droplets on nearly-flat ground from depositing everything they carry at once.
+    pub min_sediment_capacity: f32,
+    // Fraction of surplus sediment dropped per step.
+    pub deposit_speed: f32,
+    // Fraction of free capacity picked up per step.
+    pub erode_speed: f32,
+    // Fraction of water lost per step.
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    pub erosion_radius: f32,
+    pub initial_water: f32,
+    pub initial_speed: f32,
+}
+
+impl Default for HydraulicErosionSettings {
+    fn default() -> HydraulicErosionSettings {
+        HydraulicErosionSettings {
+            seed: 0,
+            iterations: 70_000,
+            max_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity_factor: 4.0,
+            min_sediment_capacity: 0.01,
+            deposit_speed: 0.3,
+            erode_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.0,
+            erosion_radius: 3.0,
+            initial_water: 1.0,
+            initial_speed: 1.0,
+        }
+    }
+}
+
+pub struct HydraulicErosionResult {
+    pub heightmap: Heightmap,
+    // Total material removed from each cell; never negative.
+    pub erosion: Heightmap,
+    // Total material added to each cell; never negative.
+    pub deposition: Heightmap,
+}
+
+// Offsets within the erosion radius and their (unnormalized) weights, nearest cells weighted most.
+fn erosion_brush(radius: f32) -> Vec<(isize, isize, f32)> {
+    let reach = radius.ceil() as isize;
+    let mut brush = vec![];
+    for dz in -reach..(reach + 1) {
+        for dx in -reach..(reach + 1) {
+            let distance = ((dx * dx + dz * dz) as f32).sqrt();
+            if distance < radius {
+                brush.push((dx, dz, radius - distance));
+            }
+        }
+    }
+    brush
+}
+
+// Bilinearly interpolated height and gradient at a fractional cell position that must be inside the grid.
+fn height_and_gradient(heightmap: &Heightmap, x: f32, z: f32) -> (f32, f32, f32) {
+    let cell_x = x.floor() as usize;
+    let cell_z = z.floor() as usize;
+    let u = x - cell_x as f32;
+    let v = z - cell_z as f32;
+
+    let h00 = heightmap.get(cell_x, cell_z);
+    let h10 = heightmap.get(cell_x + 1, cell_z);
+    let h01 = heightmap.get(cell_x, cell_z + 1);
+    let h11 = heightmap.get(cell_x + 1, cell_z + 1);
+
+    let spacing = heightmap.spacing();
+    let gradient_x = ((h10 - h00) * (1.0 - v) + (h11 - h01) * v) / spacing;
+    let gradient_z = ((h01 - h00) * (1.0 - u) + (h11 - h10) * u) / spacing;
+    let height = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+
+    (height, gradient_x, gradient_z)
+}
+
+fn add(heightmap: &mut Heightmap, x: usize, z: usize, amount: f32) {
+    let h = heightmap.get(x, z);
+    heightmap.set(x, z, h + amount);
+}
+
+// Each droplet runs on the terrain the previous one left behind, so unlike thermal erosion this stays on one thread:
+// splitting droplets across threads would change the result.
+pub fn hydraulic_erode(heightmap: &Heightmap, settings: &HydraulicErosionSettings) -> HydraulicErosionResult {
+    let width = heightmap.width();
+    let depth = heightmap.depth();
+    let mut result = HydraulicErosionResult {
+        heightmap: heightmap.clone(),
+        erosion: Heightmap::new(width, depth, heightmap.spacing()),
+        deposition: Heightmap::new(width, depth, heightmap.spacing()),
+    };
+
+    let brush = erosion_brush(settings.erosion_radius);
+    let mut rng = Rng::new(settings.seed);
+    let max_x = (width - 1) as f32;
+    let max_z = (depth - 1) as f32;
+
+    for _ in 0..settings.iterations {
+        let mut x = rng.next_f32() * max_x;
+        let mut z = rng.next_f32() * max_z;
+        let mut direction_x = 0.0f32;
+        let mut direction_z = 0.0f32;
+        let mut speed = settings.initial_speed;
+        let mut water = settings.initial_water;
+        let mut sediment = 0.0f32;
+
+        for _ in 0..settings.max_lifetime {
+            // Guards against next_f32 rounding up to exactly the far edge.
+            if x >= max_x || z >= max_z {
+                break;
+            }
+
+            let cell_x = x.floor() as usize;
+            let cell_z = z.floor() as usize;
+            let u = x - cell_x as f32;
+            let v = z - cell_z as f32;
+            let (height, gradient_x, gradient_z) = height_and_gradient(&result.heightmap, x, z);
+
+            direction_x = direction_x * settings.inertia - gradient_x * (1.0 - settings.inertia);
+            direction_z = direction_z * settings.inertia - gradient_z * (1.0 - settings.inertia);
+            let length = (direction_x * direction_x + direction_z * direction_z).sqrt();
+            if length == 0.0 {
+                // Perfectly flat; there's nowhere for the droplet to go.
+                break;
+            }
+            direction_x /= length;
+            direction_z /= length;
+            x += direction_x;
+            z += direction_z;
+
+            if x < 0.0 || z < 0.0 || x >= max_x || z >= max_z {
+                break;
+            }
+
+            let (new_height, _, _) = height_and_gradient(&result.heightmap, x, z);
+            let delta_height = new_height - height;
+            let capacity = (-delta_height * speed * water * settings.sediment_capacity_factor)
+                .max(settings.min_sediment_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                // Going uphill, fill in the pit we just left (but no more than we have); otherwise drop the surplus.
+                let amount = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * settings.deposit_speed
+                };
+                sediment -= amount;
+
+                let corners = [
+                    (cell_x, cell_z, (1.0 - u) * (1.0 - v)),
+                    (cell_x + 1, cell_z, u * (1.0 - v)),
+                    (cell_x, cell_z + 1, (1.0 - u) * v),
+                    (cell_x + 1, cell_z + 1, u * v),
+                ];
+                for &(cx, cz, weight) in corners.iter() {
+                    add(&mut result.heightmap, cx, cz, amount * weight);
+                    add(&mut result.deposition, cx, cz, amount * weight);
+                }
+            } else {
+                // Never dig deeper than the height difference, or we'd carve a pit behind the droplet.
+                let amount = ((capacity - sediment) * settings.erode_speed).min(-delta_height);
+
+                let cells: Vec<(usize, usize, f32)> = brush
+                    .iter()
+                    .map(|&(dx, dz, weight)| (cell_x as isize + dx, cell_z as isize + dz, weight))
+                    .filter(|&(bx, bz, _)| bx >= 0 && bz >= 0 && (bx as usize) < width && (bz as usize) < depth)
+                    .map(|(bx, bz, weight)| (bx as usize, bz as usize, weight))
+                    .collect();
+                let total_weight = cells.iter().fold(0.0, |acc, &(_, _, weight)| acc + weight);
+
+                for &(bx, bz, weight) in cells.iter() {
+                    let removed = amount * weight / total_weight;
+                    add(&mut result.heightmap, bx, bz, -removed);
+                    add(&mut result.erosion, bx, bz, removed);
+                }
+                sediment += amount;
+            }
+
+            speed = (speed * speed - delta_height * settings.gravity).max(0.0).sqrt();
+            water *= 1.0 - settings.evaporate_speed;
+        }
+    }
+
+    result
+}
+
+impl Filter for HydraulicErosionSettings {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        *heightmap = hydraulic_erode(heightmap, self).heightmap;
+    }
+}
+
+// Thermal (talus) erosion: wherever a cell is steeper than the talus angle relative to a neighbor, some of the excess
+// slides down to it. Every iteration reads only the previous iteration's heights, so the result doesn't depend on the
+// order cells are visited in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThermalErosionSettings {
+    // Steepest stable slope, in degrees from horizontal.
+    pub talus_angle: f32,
+    pub iterations: u32,
+    // Fraction of the steepest excess moved per iteration. Anything above 0.5 can oscillate.
+    pub strength: f32,
+    // Rows are split across this many threads; the result is the same for any number of them. Always the number of
+    // cores when read from JSON, since it only affects speed.
+    #[serde(skip)]
+    pub threads: usize,
+}
+
+impl Default for ThermalErosionSettings {
+    fn default() -> ThermalErosionSettings {
+        ThermalErosionSettings {
+            talus_angle: 35.0,
+            iterations: 50,
+            strength: 0.5,
+            threads: parallel::default_threads(),
+        }
+    }
+}
+
+const NEIGHBOR_OFFSETS: [(isize, isize); 8] = [
+    (-1, -1), (0, -1), (1, -1),
+    (-1, 0), (1, 0),
+    (-1, 1), (0, 1), (1, 1),
+];
+
+fn neighbor(heightmap: &Heightmap, x: usize, z: usize, offset: (isize, isize)) -> Option<(usize, usize)> {
+    let nx = x as isize + offset.0;
+    let nz = z as isize + offset.1;
+    if nx >= 0 && nz >= 0 && (nx as usize) < heightmap.width() && (nz as usize) < heightmap.depth() {
+        Some((nx as usize, nz as usize))
+    } else {
+        None
+    }
+}
+
+// How much material (x, z) sends to each of its neighbors, indexed like NEIGHBOR_OFFSETS.
+fn thermal_outflow(heightmap: &Heightmap, x: usize, z: usize, max_slope: f32, strength: f32) -> [f32; 8] {
+    let mut excess = [0.0f32; 8];
+    let mut total_excess = 0.0;
+    let mut max_excess = 0.0f32;
+    let height = heightmap.get(x, z);
+
+    for (i, &offset) in NEIGHBOR_OFFSETS.iter().enumerate() {
+        if let Some((nx, nz)) = neighbor(heightmap, x, z, offset) {
+            let steps = if offset.0 != 0 && offset.1 != 0 { ::std::f32::consts::SQRT_2 } else { 1.0 };
+            let distance = steps * heightmap.spacing();
+            let e = height - heightmap.get(nx, nz) - max_slope * distance;
+            if e > 0.0 {
+                excess[i] = e;
+                total_excess += e;
+                max_excess = max_excess.max(e);
+            }
+        }
+    }
+
+    let mut outflow = [0.0f32; 8];
+    if total_excess > 0.0 {
+        let moved = strength * max_excess;
+        for i in 0..8 {
+            outflow[i] = moved * excess[i] / total_excess;
+        }
+    }
+    outflow
+}
+
+pub fn thermal_erode(heightmap: &mut Heightmap, settings: &ThermalErosionSettings) {
+    assert!(settings.threads > 0, "thermal erosion needs at least one thread");
+    let max_slope = settings.talus_angle.to_radians().tan();
+    let width = heightmap.width();
+    let depth = heightmap.depth();
+    let mut pool = Pool::new(settings.threads as u32);
+    let mut outflows = vec![[0.0f32; 8]; width * depth];
+    let mut next = heightmap.clone();
+
+    for _ in 0..settings.iterations {
+        {
+            let current = &*heightmap;
+            parallel::fill_rows(&mut pool, &mut outflows, width, |x, z| {
+                thermal_outflow(current, x, z, max_slope, settings.strength)
+            });
+        }
+
+        let changed = AtomicBool::new(false);
+        {
+            let (current, outflows) = (&*heightmap, &outflows);
+            parallel::fill_rows(&mut pool, next.heights_mut(), width, |x, z| {
+                let own = &outflows[z * width + x];
+                let mut height = current.get(x, z) - own.iter().fold(0.0, |acc, &o| acc + o);
+
+                // The neighbor at offset i reaches us through the opposite offset, which is 7 - i.
+                for (i, &offset) in NEIGHBOR_OFFSETS.iter().enumerate() {
+                    if let Some((nx, nz)) = neighbor(current, x, z, offset) {
+                        height += outflows[nz * width + nx][7 - i];
+                    }
+                }
+
+                if height != current.get(x, z) {
+                    changed.store(true, Ordering::Relaxed);
+                }
+                height
+            });
+        }
+
+        mem::swap(heightmap, &mut next);
+        if !changed.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+}
+
+impl Filter for ThermalErosionSettings {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        thermal_erode(heightmap, self);
+    }
+}
+
+// Tints a terrain's vertices: red where material was carried away, blue where it was dropped, base color elsewhere.
+pub fn erosion_colors(result: &HydraulicErosionResult, base: mtl::Color) -> Vec<mtl::Color> {
+    let (_, max_erosion) = result.erosion.range();
+    let (_, max_deposition) = result.deposition.range();
+
+    result.erosion
+        .heights()
+        .iter()
+        .zip(result.deposition.heights().iter())
+        .map(|(&eroded, &deposited)| {
+            let net = eroded - deposited;
+            if net > 0.0 && max_erosion > 0.0 {
+                let t = (net / max_erosion).sqrt() as f64;
+                mtl::Color { r: base.r + (1.0 - base.r) * t, g: base.g * (1.0 - t), b: base.b * (1.0 - t) }
+            } else if net < 0.0 && max_deposition > 0.0 {
+                let t = (-net / max_deposition).sqrt() as f64;
+                mtl::Color { r: base.r * (1.0 - t), g: base.g * (1.0 - t), b: base.b + (1.0 - base.b) * t }
+            } else {
+                base
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    // A slope with some bumps on it, so droplets both erode and deposit.
+    fn slope() -> Heightmap {
+        Heightmap::from_fn(16, 16, 1.0, |x, z| (x + z) as f32 * 0.5 + ((x * 7 + z * 3) % 5) as f32 * 0.1)
+    }
+
+    fn settings() -> HydraulicErosionSettings {
+        HydraulicErosionSettings {
+            seed: 42,
+            iterations: 50,
+            ..Default::default()
+        }
+    }
+
+    fn bits(heightmap: &Heightmap) -> Vec<u32> {
+        heightmap.heights().iter().map(|height| height.to_bits()).collect()
+    }
+
+    #[test]
+    fn thermal_erosion_is_the_same_on_any_number_of_threads() {
+        // An odd number of rows, so they don't split evenly between threads, and spikes steep enough to slide.
+        let spiky = Heightmap::from_fn(17, 11, 1.0, |x, z| ((x * 13 + z * 7) % 11) as f32 * 0.9);
+        let erode = |threads| {
+            let mut heightmap = spiky.clone();
+            thermal_erode(&mut heightmap, &ThermalErosionSettings {
+                iterations: 20,
+                threads: threads,
+                ..Default::default()
+            });
+            bits(&heightmap)
+        };
+        let single = erode(1);
+        assert!(single != bits(&spiky), "nothing eroded");
+        for &threads in [2, 3, 7].iter() {
+            assert_eq!(erode(threads), single, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn thermal_erosion_threads_are_not_read_from_json() {
+        let settings: ThermalErosionSettings = serde_json::from_str(r#"{ "threads": 0, "iterations": 3 }"#).unwrap();
+        assert_eq!(settings.iterations, 3);
+        assert_eq!(settings.threads, parallel::default_threads());
+    }
+
+    #[test]
+    fn hydraulic_erosion_is_deterministic() {
+        let first = hydraulic_erode(&slope(), &settings());
+        let second = hydraulic_erode(&slope(), &settings());
+        assert_eq!(bits(&first.heightmap), bits(&second.heightmap));
+        assert_eq!(bits(&first.erosion), bits(&second.erosion));
+        assert_eq!(bits(&first.deposition), bits(&second.deposition));
+    }
+
+    // Pins the output, so that any change to the algorithm (and so to everyone's terrain for a given seed) is noticed.
+    #[test]
+    fn hydraulic_erosion_matches_stored_values() {
+        let result = hydraulic_erode(&slope(), &settings());
+        assert_eq!(result.heightmap.get(3, 4), 1.9210906);
+        assert_eq!(result.erosion.get(3, 4), 2.5805104);
+        assert_eq!(result.deposition.get(3, 4), 0.7016011);
+        assert_eq!(result.heightmap.get(8, 8), 5.827043);
+        assert_eq!(result.erosion.get(8, 8), 2.2615025);
+        assert_eq!(result.deposition.get(8, 8), 0.088546775);
+        assert_eq!(result.heightmap.get(12, 5), 8.714866);
+        assert_eq!(result.erosion.get(12, 5), 0.18513583);
+        assert_eq!(result.deposition.get(12, 5), 0.0);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{ error, fmt, fs, io, path };
+use std::io::Write;
+use image;
+use serde_json;
+
+use terrain::Heightmap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HeightmapFormat {
+    // 16-bit grayscale PNG, heights quantized between vertical_offset and vertical_offset + vertical_scale.
+    Png16,
+    // Headerless little-endian u16, quantized like Png16.
+    RawU16,
+    // Headerless little-endian f32, unquantized; vertical_offset and vertical_scale are informational.
+    RawF32,
+}
+
+// Written next to every export as <filename>.json, so whoever reads the file back doesn't have to guess.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeightmapMetadata {
+    pub format: HeightmapFormat,
+    pub width: usize,
+    pub depth: usize,
+    pub spacing: f32,
+    pub vertical_offset: f32,
+    pub vertical_scale: f32,
+    // Size along x and z in world units.
+    pub world_extent: (f32, f32),
+    // The seed the terrain was generated from, if it was generated at all.
+    pub seed: Option<u64>,
+}
+
+impl HeightmapMetadata {
+    fn for_heightmap(heightmap: &Heightmap, format: HeightmapFormat, seed: Option<u64>) -> HeightmapMetadata {
+        let (min, max) = heightmap.range();
+        HeightmapMetadata {
+            format: format,
+            width: heightmap.width(),
+            depth: heightmap.depth(),
+            spacing: heightmap.spacing(),
+            vertical_offset: min,
+            vertical_scale: max - min,
+            world_extent: heightmap.extent(),
+            seed: seed,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Metadata(serde_json::Error),
+    // export_raw was asked for a format that isn't RAW.
+    NotRaw(HeightmapFormat),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExportError::Io(ref e) => write!(f, "couldn't write export: {}", e),
+            ExportError::Metadata(ref e) => write!(f, "couldn't serialize export: {}", e),
+            ExportError::NotRaw(format) => write!(f, "{:?} isn't a RAW format; use its own export function", format),
+        }
+    }
+}
+
+impl error::Error for ExportError {
+    fn description(&self) -> &str {
+        match *self {
+            ExportError::Io(_) => "I/O error writing export",
+            ExportError::Metadata(_) => "error serializing export",
+            ExportError::NotRaw(_) => "not a RAW format",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ExportError::Io(ref e) => Some(e),
+            ExportError::Metadata(ref e) => Some(e),
+            ExportError::NotRaw(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> ExportError {
+        ExportError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(e: serde_json::Error) -> ExportError {
+        ExportError::Metadata(e)
+    }
+}
+
+pub fn sidecar_path(path: &path::Path) -> path::PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".json");
+    path::PathBuf::from(sidecar)
+}
+
+fn write_metadata(path: &path::Path, metadata: &HeightmapMetadata) -> Result<(), ExportError> {
+    let mut file = fs::File::create(sidecar_path(path))?;
+    file.write_all(serde_json::to_string_pretty(metadata)?.as_bytes())?;
+    Ok(())
+}
+
+fn quantize(heightmap: &Heightmap, metadata: &HeightmapMetadata) -> Vec<u16> {
+    heightmap
+        .heights()
+        .iter()
+        .map(|&h| {
+            let normalized = if metadata.vertical_scale > 0.0 {
+                (h - metadata.vertical_offset) / metadata.vertical_scale
+            } else {
+                0.0
+            };
+            (normalized.max(0.0).min(1.0) * 65535.0).round() as u16
+        })
+        .collect()
+}
+
+pub fn export_png16(heightmap: &Heightmap, path: &path::Path, seed: Option<u64>) -> Result<HeightmapMetadata, ExportError> {
+    let metadata = HeightmapMetadata::for_heightmap(heightmap, HeightmapFormat::Png16, seed);
+
+    // PNG stores 16-bit samples big-endian.
+    let mut bytes = Vec::with_capacity(heightmap.heights().len() * 2);
+    for sample in quantize(heightmap, &metadata) {
+        bytes.push((sample >> 8) as u8);
+        bytes.push((sample & 0xFF) as u8);
+    }
+
+    let file = io::BufWriter::new(fs::File::create(path)?);
+    image::png::PNGEncoder::new(file).encode(
+        &bytes,
+        heightmap.width() as u32,
+        heightmap.depth() as u32,
+        image::ColorType::Gray(16))?;
+
+    write_metadata(path, &metadata)?;
+    info!("exported {}x{} heightmap to {:?}", heightmap.width(), heightmap.depth(), path);
+    Ok(metadata)
+}
+
+pub fn export_raw(
+    heightmap: &Heightmap,
+    path: &path::Path,
+    format: HeightmapFormat,
+    seed: Option<u64>
+) -> Result<HeightmapMetadata, ExportError> {
+    let metadata = HeightmapMetadata::for_heightmap(heightmap, format, seed);
+
+    let mut bytes = vec![];
+    match format {
+        HeightmapFormat::RawU16 => {
+            for sample in quantize(heightmap, &metadata) {
+                bytes.push((sample & 0xFF) as u8);
+                bytes.push((sample >> 8) as u8);
+            }
+        },
+        HeightmapFormat::RawF32 => {
+            for &h in heightmap.heights() {
+                let bits = h.to_bits();
+                for shift in 0..4 {
+                    bytes.push((bits >> (shift * 8) & 0xFF) as u8);
+                }
+            }
+        },
+        HeightmapFormat::Png16 => return Err(ExportError::NotRaw(format)),
+    }
+
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    file.write_all(&bytes)?;
+
+    write_metadata(path, &metadata)?;
+    info!("exported {}x{} heightmap to {:?}", heightmap.width(), heightmap.depth(), path);
+    Ok(metadata)
+}
+
+// Writes the 16-bit PNG and both RAW flavors side by side as <stem>.png, <stem>.r16 and <stem>.r32.
+pub fn export_all(heightmap: &Heightmap, directory: &path::Path, stem: &str, seed: Option<u64>) -> Result<(), ExportError> {
+    fs::create_dir_all(directory)?;
+    export_png16(heightmap, &directory.join(format!("{}.png", stem)), seed)?;
+    export_raw(heightmap, &directory.join(format!("{}.r16", stem)), HeightmapFormat::RawU16, seed)?;
+    export_raw(heightmap, &directory.join(format!("{}.r32", stem)), HeightmapFormat::RawF32, seed)?;
+    Ok(())
+}
@@ -0,0 +1,655 @@
+use std::{ error, fmt, fs, io, path };
+use std::collections::{ BTreeMap, HashMap };
+use std::io::Read;
+use std::sync::Arc;
+use serde_json;
+
+use terrain::{ Filter, Heightmap };
+use terrain::diamond_square::{ self, DiamondSquareSettings };
+use terrain::erosion::{ HydraulicErosionSettings, ThermalErosionSettings };
+use terrain::import::{ self, ImportError, ImportSettings };
+use terrain::lakes::DepressionFill;
+use terrain::noise::{ heightmap_from_noise_parallel, DomainWarp, Fractal, FractalSettings, NoiseFn };
+use terrain::noise::{ Worley, WorleySettings };
+use terrain::parallel;
+use terrain::random::Rng;
+use terrain::shaping::{ Clamp, Curve, Normalize, Power, Terrace };
+
+// A terrain pipeline read from JSON: named nodes that each produce either a field (noise that can be sampled
+// anywhere) or a heightmap (the graph's size), and the name of the node whose heightmap is the result. For example:
+//
+//   {
+//     "width": 257, "depth": 257, "spacing": 0.5, "output": "eroded",
+//     "nodes": {
+//       "hills": { "type": "Noise", "settings": { "frequency": 0.01 } },
+//       "raster": { "type": "Sample", "input": "hills", "amplitude": 12 },
+//       "eroded": { "type": "HydraulicErosion", "input": "raster" }
+//     }
+//   }
+//
+// See graphs/ for more.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TerrainGraph {
+    pub width: usize,
+    pub depth: usize,
+    pub spacing: f32,
+    pub output: String,
+    pub nodes: BTreeMap<String, Node>,
+}
+
+fn default_amplitude() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum Node {
+    // Generators. Every `seed` in a node's settings picks a stream off the seed the graph is evaluated with.
+    Noise {
+        #[serde(default)]
+        settings: FractalSettings,
+    },
+    Worley {
+        #[serde(default)]
+        settings: WorleySettings,
+    },
+    // The graph's spacing and size override whatever the settings say; the size has to be 2^n + 1.
+    DiamondSquare {
+        #[serde(default)]
+        settings: DiamondSquareSettings,
+    },
+    // Relative to the graph file. Has to be the graph's size; the graph's spacing overrides the settings'.
+    Image {
+        path: String,
+        #[serde(default)]
+        settings: ImportSettings,
+    },
+
+    // Field operators.
+    DomainWarp {
+        source: String,
+        warp: String,
+        strength: f64,
+    },
+    // Turns a field into a heightmap: offset + amplitude * field, with vertex (0, 0) at `origin`.
+    Sample {
+        input: String,
+        #[serde(default = "default_amplitude")]
+        amplitude: f32,
+        #[serde(default)]
+        offset: f32,
+        #[serde(default)]
+        origin: (f64, f64),
+    },
+
+    // Combiners.
+    Add {
+        inputs: Vec<String>,
+    },
+    Multiply {
+        inputs: Vec<String>,
+    },
+    Max {
+        inputs: Vec<String>,
+    },
+    // a where the mask is 0 (or less), b where it's 1 (or more).
+    Lerp {
+        a: String,
+        b: String,
+        mask: String,
+    },
+
+    // Filters.
+    HydraulicErosion {
+        input: String,
+        #[serde(default)]
+        settings: HydraulicErosionSettings,
+    },
+    ThermalErosion {
+        input: String,
+        #[serde(default)]
+        settings: ThermalErosionSettings,
+    },
+    Terrace {
+        input: String,
+        #[serde(default)]
+        settings: Terrace,
+    },
+    Clamp {
+        input: String,
+        settings: Clamp,
+    },
+    Curve {
+        input: String,
+        settings: Curve,
+    },
+    Power {
+        input: String,
+        settings: Power,
+    },
+    Normalize {
+        input: String,
+        settings: Normalize,
+    },
+    FillDepressions {
+        input: String,
+        #[serde(default)]
+        settings: DepressionFill,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Field,
+    Heightmap,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValueType::Field => write!(f, "field"),
+            ValueType::Heightmap => write!(f, "heightmap"),
+        }
+    }
+}
+
+impl Node {
+    pub fn output_type(&self) -> ValueType {
+        match *self {
+            Node::Noise { .. } | Node::Worley { .. } | Node::DomainWarp { .. } => ValueType::Field,
+            _ => ValueType::Heightmap,
+        }
+    }
+
+    // Every node this one reads from, with the type it expects of each.
+    pub fn inputs(&self) -> Vec<(&str, ValueType)> {
+        match *self {
+            Node::Noise { .. } | Node::Worley { .. } | Node::DiamondSquare { .. } | Node::Image { .. } => vec![],
+            Node::DomainWarp { ref source, ref warp, .. } =>
+                vec![(source.as_str(), ValueType::Field), (warp.as_str(), ValueType::Field)],
+            Node::Sample { ref input, .. } => vec![(input.as_str(), ValueType::Field)],
+            Node::Add { ref inputs } | Node::Multiply { ref inputs } | Node::Max { ref inputs } =>
+                inputs.iter().map(|i| (i.as_str(), ValueType::Heightmap)).collect(),
+            Node::Lerp { ref a, ref b, ref mask } => vec![
+                (a.as_str(), ValueType::Heightmap),
+                (b.as_str(), ValueType::Heightmap),
+                (mask.as_str(), ValueType::Heightmap),
+            ],
+            Node::HydraulicErosion { ref input, .. } |
+            Node::ThermalErosion { ref input, .. } |
+            Node::Terrace { ref input, .. } |
+            Node::Clamp { ref input, .. } |
+            Node::Curve { ref input, .. } |
+            Node::Power { ref input, .. } |
+            Node::Normalize { ref input, .. } |
+            Node::FillDepressions { ref input, .. } => vec![(input.as_str(), ValueType::Heightmap)],
+        }
+    }
+
+    // Catches the settings that generators and filters would otherwise assert on, or that make no sense, before
+    // anything runs. The error says what's wrong.
+    fn check_settings(&self) -> Result<(), String> {
+        match *self {
+            Node::Noise { ref settings } if settings.octaves == 0 =>
+                Err("noise needs at least one octave".to_owned()),
+            Node::Worley { ref settings } if settings.jitter < 0.0 || settings.jitter > 1.0 =>
+                Err(format!("Worley jitter has to be between 0 and 1, got {}", settings.jitter)),
+            Node::DiamondSquare { ref settings } if settings.size_exponent < 1 || settings.size_exponent > 14 =>
+                Err(format!("diamond-square size exponent has to be between 1 and 14, got {}", settings.size_exponent)),
+            Node::HydraulicErosion { ref settings, .. } if settings.erosion_radius <= 0.0 =>
+                Err(format!("erosion radius has to be positive, got {}", settings.erosion_radius)),
+            Node::Terrace { ref settings, .. } if settings.steps == 0 =>
+                Err("terracing needs at least one step".to_owned()),
+            Node::Clamp { ref settings, .. } if settings.min > settings.max =>
+                Err(format!("clamp min {} is above its max {}", settings.min, settings.max)),
+            Node::Curve { ref settings, .. } if settings.points.is_empty() =>
+                Err("curve needs at least one point".to_owned()),
+            Node::Curve { ref settings, .. } if settings.points.windows(2).any(|pair| pair[1].0 <= pair[0].0) =>
+                Err("curve points have to be in order of strictly increasing input".to_owned()),
+            Node::Power { ref settings, .. } if settings.exponent <= 0.0 =>
+                Err(format!("power exponent has to be positive, got {}", settings.exponent)),
+            Node::Normalize { ref settings, .. } if settings.min > settings.max =>
+                Err(format!("normalize min {} is above its max {}", settings.min, settings.max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GraphError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    // `node` reads from `input`, which isn't in the graph.
+    UnknownNode { node: String, input: String },
+    // The path around the cycle, starting and ending at the same node.
+    Cycle(Vec<String>),
+    TypeMismatch { node: String, input: String, expected: ValueType, found: ValueType },
+    // A node that needs at least one input doesn't have any.
+    NoInputs(String),
+    // A heightmap that isn't the graph's size.
+    WrongSize { node: String, expected: (usize, usize), actual: (usize, usize) },
+    Import { node: String, error: ImportError },
+    // The graph's own size or spacing can't make a heightmap.
+    BadDimensions { width: usize, depth: usize, spacing: f32 },
+    // A node's settings are out of range; `reason` says how.
+    InvalidSettings { node: String, reason: String },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GraphError::Io(ref e) => write!(f, "couldn't read terrain graph: {}", e),
+            GraphError::Parse(ref e) => write!(f, "couldn't parse terrain graph: {}", e),
+            GraphError::UnknownNode { ref node, ref input } =>
+                write!(f, "node '{}' reads from '{}', which doesn't exist", node, input),
+            GraphError::Cycle(ref path) => write!(f, "terrain graph has a cycle: {}", path.join(" -> ")),
+            GraphError::TypeMismatch { ref node, ref input, expected, found } =>
+                write!(f, "node '{}' needs a {} from '{}', but that's a {}", node, expected, input, found),
+            GraphError::NoInputs(ref node) => write!(f, "node '{}' needs at least one input", node),
+            GraphError::WrongSize { ref node, expected, actual } =>
+                write!(f, "node '{}' is {}x{}, but the graph is {}x{}",
+                    node, actual.0, actual.1, expected.0, expected.1),
+            GraphError::Import { ref node, ref error } => write!(f, "node '{}': {}", node, error),
+            GraphError::BadDimensions { width, depth, spacing } =>
+                write!(f, "terrain graph is {}x{} with spacing {}, but needs to be at least 2x2 with positive spacing",
+                    width, depth, spacing),
+            GraphError::InvalidSettings { ref node, ref reason } => write!(f, "node '{}': {}", node, reason),
+        }
+    }
+}
+
+impl error::Error for GraphError {
+    fn description(&self) -> &str {
+        match *self {
+            GraphError::Io(_) => "I/O error reading terrain graph",
+            GraphError::Parse(_) => "error parsing terrain graph",
+            GraphError::UnknownNode { .. } => "terrain graph refers to a node that doesn't exist",
+            GraphError::Cycle(_) => "terrain graph has a cycle",
+            GraphError::TypeMismatch { .. } => "terrain graph connects mismatched types",
+            GraphError::NoInputs(_) => "terrain graph node has no inputs",
+            GraphError::WrongSize { .. } => "terrain graph node is the wrong size",
+            GraphError::Import { .. } => "error importing heightmap for terrain graph",
+            GraphError::BadDimensions { .. } => "terrain graph has unusable dimensions",
+            GraphError::InvalidSettings { .. } => "terrain graph node has invalid settings",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            GraphError::Io(ref e) => Some(e),
+            GraphError::Parse(ref e) => Some(e),
+            GraphError::Import { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GraphError {
+    fn from(e: io::Error) -> GraphError {
+        GraphError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for GraphError {
+    fn from(e: serde_json::Error) -> GraphError {
+        GraphError::Parse(e)
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Field(Arc<NoiseFn + Send + Sync>),
+    Heightmap(Heightmap),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VisitState {
+    InProgress,
+    Done,
+}
+
+impl TerrainGraph {
+    pub fn load(path: &path::Path) -> Result<TerrainGraph, GraphError> {
+        let mut contents = String::new();
+        fs::File::open(path)?.read_to_string(&mut contents)?;
+        let graph: TerrainGraph = serde_json::from_str(&contents)?;
+        graph.validate()?;
+        Ok(graph)
+    }
+
+    // Checks the whole graph for bad dimensions or settings, dangling references, type mismatches and cycles without
+    // running anything.
+    pub fn validate(&self) -> Result<(), GraphError> {
+        if self.width < 2 || self.depth < 2 || self.spacing <= 0.0 {
+            return Err(GraphError::BadDimensions { width: self.width, depth: self.depth, spacing: self.spacing });
+        }
+        if !self.nodes.contains_key(&self.output) {
+            return Err(GraphError::UnknownNode { node: "output".to_owned(), input: self.output.clone() });
+        }
+        if self.nodes[&self.output].output_type() != ValueType::Heightmap {
+            return Err(GraphError::TypeMismatch {
+                node: "output".to_owned(),
+                input: self.output.clone(),
+                expected: ValueType::Heightmap,
+                found: ValueType::Field,
+            });
+        }
+
+        for (name, node) in self.nodes.iter() {
+            let inputs = node.inputs();
+            let needs_inputs = match *node {
+                Node::Add { .. } | Node::Multiply { .. } | Node::Max { .. } => true,
+                _ => false,
+            };
+            if needs_inputs && inputs.is_empty() {
+                return Err(GraphError::NoInputs(name.clone()));
+            }
+            node.check_settings().map_err(|reason| GraphError::InvalidSettings { node: name.clone(), reason: reason })?;
+            for (input, expected) in inputs {
+                let found = match self.nodes.get(input) {
+                    Some(n) => n.output_type(),
+                    None => return Err(GraphError::UnknownNode { node: name.clone(), input: input.to_owned() }),
+                };
+                if found != expected {
+                    return Err(GraphError::TypeMismatch {
+                        node: name.clone(),
+                        input: input.to_owned(),
+                        expected: expected,
+                        found: found,
+                    });
+                }
+            }
+        }
+
+        let mut states = HashMap::new();
+        for name in self.nodes.keys() {
+            let mut path = vec![];
+            self.find_cycle(name, &mut states, &mut path)?;
+        }
+        Ok(())
+    }
+
+    // Depth-first; `path` holds the nodes currently being visited, so meeting one of them again closes a cycle.
+    fn find_cycle<'g>(
+        &'g self,
+        name: &'g str,
+        states: &mut HashMap<&'g str, VisitState>,
+        path: &mut Vec<&'g str>
+    ) -> Result<(), GraphError> {
+        match states.get(name).cloned() {
+            Some(VisitState::Done) => return Ok(()),
+            Some(VisitState::InProgress) => {
+                let start = path.iter().position(|&n| n == name).unwrap();
+                let mut cycle: Vec<String> = path[start..].iter().map(|&n| n.to_owned()).collect();
+                cycle.push(name.to_owned());
+                return Err(GraphError::Cycle(cycle));
+            },
+            None => {},
+        }
+
+        states.insert(name, VisitState::InProgress);
+        path.push(name);
+        for (input, _) in self.nodes[name].inputs() {
+            self.find_cycle(input, states, path)?;
+        }
+        path.pop();
+        states.insert(name, VisitState::Done);
+        Ok(())
+    }
+
+    // Runs the graph. `directory` is where Image nodes' paths are relative to.
+    pub fn evaluate(&self, seed: u64, directory: &path::Path) -> Result<Heightmap, GraphError> {
+        self.validate()?;
+        let mut values = HashMap::new();
+        match self.evaluate_node(&self.output, seed, directory, &mut values)? {
+            Value::Heightmap(heightmap) => Ok(heightmap),
+            Value::Field(_) => unreachable!("validate checks that the output is a heightmap"),
+        }
+    }
+
+    fn node_seed(seed: u64, stream: u64) -> u64 {
+        Rng::derive(seed, stream).next_u64()
+    }
+
+    fn check_size(&self, name: &str, heightmap: &Heightmap) -> Result<(), GraphError> {
+        if (heightmap.width(), heightmap.depth()) != (self.width, self.depth) {
+            Err(GraphError::WrongSize {
+                node: name.to_owned(),
+                expected: (self.width, self.depth),
+                actual: (heightmap.width(), heightmap.depth()),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn field(&self, name: &str, seed: u64, directory: &path::Path, values: &mut HashMap<String, Value>)
+        -> Result<Arc<NoiseFn + Send + Sync>, GraphError> {
+        match self.evaluate_node(name, seed, directory, values)? {
+            Value::Field(field) => Ok(field),
+            Value::Heightmap(_) => unreachable!("validate checks input types"),
+        }
+    }
+
+    fn heightmap(&self, name: &str, seed: u64, directory: &path::Path, values: &mut HashMap<String, Value>)
+        -> Result<Heightmap, GraphError> {
+        match self.evaluate_node(name, seed, directory, values)? {
+            Value::Heightmap(heightmap) => Ok(heightmap),
+            Value::Field(_) => unreachable!("validate checks input types"),
+        }
+    }
+
+    fn combine<F: Fn(f32, f32) -> f32>(
+        &self,
+        inputs: &[String],
+        seed: u64,
+        directory: &path::Path,
+        values: &mut HashMap<String, Value>,
+        f: F
+    ) -> Result<Heightmap, GraphError> {
+        let mut result = self.heightmap(&inputs[0], seed, directory, values)?;
+        for input in inputs[1..].iter() {
+            let other = self.heightmap(input, seed, directory, values)?;
+            for (h, &o) in result.heights_mut().iter_mut().zip(other.heights().iter()) {
+                *h = f(*h, o);
+            }
+        }
+        Ok(result)
+    }
+
+    fn filter<F: Filter>(
+        &self,
+        input: &str,
+        filter: &F,
+        seed: u64,
+        directory: &path::Path,
+        values: &mut HashMap<String, Value>
+    ) -> Result<Heightmap, GraphError> {
+        let mut heightmap = self.heightmap(input, seed, directory, values)?;
+        filter.apply(&mut heightmap);
+        Ok(heightmap)
+    }
+
+    // Every node is evaluated once and cached, however many nodes read from it.
+    fn evaluate_node(
+        &self,
+        name: &str,
+        seed: u64,
+        directory: &path::Path,
+        values: &mut HashMap<String, Value>
+    ) -> Result<Value, GraphError> {
+        if let Some(value) = values.get(name) {
+            return Ok(value.clone());
+        }
+        debug!("evaluating terrain graph node '{}'", name);
+
+        let value = match self.nodes[name] {
+            Node::Noise { ref settings } => Value::Field(Arc::new(Fractal::new(FractalSettings {
+                seed: TerrainGraph::node_seed(seed, settings.seed),
+                ..settings.clone()
+            }))),
+            Node::Worley { ref settings } => Value::Field(Arc::new(Worley::new(WorleySettings {
+                seed: TerrainGraph::node_seed(seed, settings.seed),
+                ..settings.clone()
+            }))),
+            Node::DiamondSquare { ref settings } => {
+                let heightmap = diamond_square::generate(&DiamondSquareSettings {
+                    seed: TerrainGraph::node_seed(seed, settings.seed),
+                    spacing: self.spacing,
+                    ..settings.clone()
+                });
+                self.check_size(name, &heightmap)?;
+                Value::Heightmap(heightmap)
+            },
+            Node::Image { ref path, ref settings } => {
+                let settings = ImportSettings {
+                    spacing: self.spacing,
+                    ..settings.clone()
+                };
+                let heightmap = import::load_heightmap(&directory.join(path), &settings)
+                    .map_err(|e| GraphError::Import { node: name.to_owned(), error: e })?;
+                self.check_size(name, &heightmap)?;
+                Value::Heightmap(heightmap)
+            },
+            Node::DomainWarp { ref source, ref warp, strength } => {
+                let source = self.field(source, seed, directory, values)?;
+                let warp = self.field(warp, seed, directory, values)?;
+                Value::Field(Arc::new(DomainWarp::new(source, warp, strength)))
+            },
+            Node::Sample { ref input, amplitude, offset, origin } => {
+                let field = self.field(input, seed, directory, values)?;
+                let mut heightmap = heightmap_from_noise_parallel(
+                    &*field, self.width, self.depth, self.spacing, origin, amplitude, parallel::default_threads());
+                for h in heightmap.heights_mut() {
+                    *h += offset;
+                }
+                Value::Heightmap(heightmap)
+            },
+            Node::Add { ref inputs } =>
+                Value::Heightmap(self.combine(inputs, seed, directory, values, |a, b| a + b)?),
+            Node::Multiply { ref inputs } =>
+                Value::Heightmap(self.combine(inputs, seed, directory, values, |a, b| a * b)?),
+            Node::Max { ref inputs } =>
+                Value::Heightmap(self.combine(inputs, seed, directory, values, f32::max)?),
+            Node::Lerp { ref a, ref b, ref mask } => {
+                let mut result = self.heightmap(a, seed, directory, values)?;
+                let other = self.heightmap(b, seed, directory, values)?;
+                let mask = self.heightmap(mask, seed, directory, values)?;
+                let pairs = other.heights().iter().zip(mask.heights().iter());
+                for (h, (&target, &m)) in result.heights_mut().iter_mut().zip(pairs) {
+                    let t = m.max(0.0).min(1.0);
+                    *h += (target - *h) * t;
+                }
+                Value::Heightmap(result)
+            },
+            Node::HydraulicErosion { ref input, ref settings } => {
+                let settings = HydraulicErosionSettings {
+                    seed: TerrainGraph::node_seed(seed, settings.seed),
+                    ..settings.clone()
+                };
+                Value::Heightmap(self.filter(input, &settings, seed, directory, values)?)
+            },
+            Node::ThermalErosion { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+            Node::Terrace { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+            Node::Clamp { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+            Node::Curve { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+            Node::Power { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+            Node::Normalize { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+            Node::FillDepressions { ref input, ref settings } =>
+                Value::Heightmap(self.filter(input, settings, seed, directory, values)?),
+        };
+
+        values.insert(name.to_owned(), value.clone());
+        Ok(value)
+    }
+}
+
+// Loads and runs the graph at `path` in one go.
+pub fn evaluate_file(path: &path::Path, seed: u64) -> Result<Heightmap, GraphError> {
+    let graph = TerrainGraph::load(path)?;
+    let directory = path.parent().unwrap_or_else(|| path::Path::new("."));
+    graph.evaluate(seed, directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    // A graph that samples a noise field into "raster", plus `node`, called "node", which can read from either.
+    fn graph_with(node: &str) -> TerrainGraph {
+        serde_json::from_str(&format!(r#"{{
+            "width": 9, "depth": 9, "spacing": 1.0, "output": "raster",
+            "nodes": {{
+                "field": {{ "type": "Noise" }},
+                "raster": {{ "type": "Sample", "input": "field" }},
+                "node": {}
+            }}
+        }}"#, node)).unwrap()
+    }
+
+    fn assert_invalid_settings(node: &str) {
+        match graph_with(node).validate() {
+            Err(GraphError::InvalidSettings { ref node, .. }) if node == "node" => {},
+            other => panic!("expected invalid settings for {}, got {:?}", node, other),
+        }
+    }
+
+    #[test]
+    fn example_graph_is_valid() {
+        let graph: TerrainGraph = serde_json::from_str(include_str!("../../graphs/example.json")).unwrap();
+        graph.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_dimensions() {
+        let mut graph = graph_with(r#"{ "type": "Terrace", "input": "raster" }"#);
+        graph.validate().unwrap();
+        graph.width = 1;
+        match graph.validate() {
+            Err(GraphError::BadDimensions { width: 1, depth: 9, .. }) => {},
+            other => panic!("expected bad dimensions, got {:?}", other),
+        }
+        graph.width = 9;
+        graph.spacing = 0.0;
+        match graph.validate() {
+            Err(GraphError::BadDimensions { .. }) => {},
+            other => panic!("expected bad dimensions, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_settings_that_would_panic() {
+        assert_invalid_settings(r#"{ "type": "Noise", "settings": { "octaves": 0 } }"#);
+        assert_invalid_settings(r#"{ "type": "Worley", "settings": { "jitter": 1.5 } }"#);
+        assert_invalid_settings(r#"{ "type": "Worley", "settings": { "jitter": -0.1 } }"#);
+        assert_invalid_settings(r#"{ "type": "DiamondSquare", "settings": { "size_exponent": 0 } }"#);
+        assert_invalid_settings(r#"{ "type": "DiamondSquare", "settings": { "size_exponent": 15 } }"#);
+        assert_invalid_settings(
+            r#"{ "type": "HydraulicErosion", "input": "raster", "settings": { "erosion_radius": 0 } }"#);
+        assert_invalid_settings(r#"{ "type": "Terrace", "input": "raster", "settings": { "steps": 0 } }"#);
+        assert_invalid_settings(r#"{ "type": "Clamp", "input": "raster", "settings": { "min": 2, "max": 1 } }"#);
+        let curve = |points: &str| {
+            format!(r#"{{ "type": "Curve", "input": "raster", "settings": {{ "points": {} }} }}"#, points)
+        };
+        assert_invalid_settings(&curve("[]"));
+        // Out of order, and two points at the same input.
+        assert_invalid_settings(&curve("[[0.5, 0], [0.2, 1]]"));
+        assert_invalid_settings(&curve("[[0.5, 0], [0.5, 1]]"));
+        graph_with(&curve("[[0, 0], [0.5, 0.2], [1, 1]]")).validate().unwrap();
+        assert_invalid_settings(r#"{ "type": "Power", "input": "raster", "settings": { "exponent": 0 } }"#);
+        assert_invalid_settings(r#"{ "type": "Power", "input": "raster", "settings": { "exponent": -2 } }"#);
+        assert_invalid_settings(r#"{ "type": "Normalize", "input": "raster", "settings": { "min": 10, "max": -10 } }"#);
+        // Equal ends flatten the terrain, which is odd but well defined.
+        graph_with(r#"{ "type": "Normalize", "input": "raster", "settings": { "min": 3, "max": 3 } }"#)
+            .validate()
+            .unwrap();
+    }
+}
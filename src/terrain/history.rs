@@ -0,0 +1,261 @@
+use std::collections::{ BTreeMap, VecDeque };
+use std::mem;
+
+use terrain::Heightmap;
+use terrain::sculpt::Region;
+
+// 64 MiB of heights. A change to the whole of a 2049x2049 heightmap keeps about 16.8 MB, so that's three of them.
+pub const DEFAULT_BUDGET: usize = 64 * 1024 * 1024;
+
+// Heights are kept in square tiles this many vertices on a side, so that a long stroke only keeps the tiles it passed
+// over rather than its whole bounding box.
+const TILE_SIZE: usize = 16;
+
+fn tile_region(heightmap: &Heightmap, (tile_x, tile_z): (usize, usize)) -> Region {
+    Region {
+        min_x: tile_x * TILE_SIZE,
+        min_z: tile_z * TILE_SIZE,
+        max_x: (tile_x * TILE_SIZE + TILE_SIZE - 1).min(heightmap.width() - 1),
+        max_z: (tile_z * TILE_SIZE + TILE_SIZE - 1).min(heightmap.depth() - 1),
+    }
+}
+
+// One undoable step. It only keeps the heights of the tiles it touched, as they were on the other side of the step:
+// before it while it's waiting to be undone, after it while it's waiting to be redone. Undoing and redoing are then
+// the same thing, swapping those heights with the heightmap's.
+#[derive(Debug, Clone)]
+pub struct Change {
+    label: String,
+    // Every vertex the step may have changed.
+    region: Region,
+    // Keyed by tile coordinates; tiles on the heightmap's far edges are cut short.
+    tiles: BTreeMap<(usize, usize), Vec<f32>>,
+}
+
+impl Change {
+    fn capture(label: String, heightmap: &Heightmap, region: Region) -> Change {
+        let mut change = Change {
+            label: label,
+            region: region,
+            tiles: BTreeMap::new(),
+        };
+        change.grow(heightmap, region);
+        change
+    }
+
+    // Takes in `region` too, reading any tiles it hasn't seen yet from the heightmap. Only correct while the heightmap
+    // hasn't changed outside the tiles captured so far.
+    fn grow(&mut self, heightmap: &Heightmap, region: Region) {
+        self.region = self.region.union(&region);
+        for tile_z in (region.min_z / TILE_SIZE)..(region.max_z / TILE_SIZE + 1) {
+            for tile_x in (region.min_x / TILE_SIZE)..(region.max_x / TILE_SIZE + 1) {
+                self.tiles.entry((tile_x, tile_z)).or_insert_with(|| {
+                    let tile = tile_region(heightmap, (tile_x, tile_z));
+                    let mut heights = Vec::with_capacity(tile.width() * tile.depth());
+                    for z in tile.min_z..(tile.max_z + 1) {
+                        for x in tile.min_x..(tile.max_x + 1) {
+                            heights.push(heightmap.get(x, z));
+                        }
+                    }
+                    heights
+                });
+            }
+        }
+    }
+
+    fn swap(&mut self, heightmap: &mut Heightmap) {
+        for (&key, heights) in self.tiles.iter_mut() {
+            let tile = tile_region(heightmap, key);
+            let width = tile.width();
+            for z in tile.min_z..(tile.max_z + 1) {
+                for x in tile.min_x..(tile.max_x + 1) {
+                    let i = (z - tile.min_z) * width + (x - tile.min_x);
+                    let current = heightmap.get(x, z);
+                    heightmap.set(x, z, heights[i]);
+                    heights[i] = current;
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.tiles.values().map(|heights| heights.len() * mem::size_of::<f32>()).sum()
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+}
+
+// Undo and redo stacks for one heightmap. A change is built up with `record`, called with each region just before it's
+// modified (so a whole brush stroke can be one change), and closed with `commit`. Once the stored heights go over the
+// budget, the oldest changes are forgotten, though the latest one is always kept.
+#[derive(Debug, Clone)]
+pub struct History {
+    budget: usize,
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+    pending: Option<Change>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_BUDGET)
+    }
+}
+
+impl History {
+    // `budget` is in bytes.
+    pub fn new(budget: usize) -> History {
+        History {
+            budget: budget,
+            undo: VecDeque::new(),
+            redo: vec![],
+            pending: None,
+        }
+    }
+
+    // `label` names the change if this starts a new one, and is ignored otherwise.
+    pub fn record(&mut self, label: &str, heightmap: &Heightmap, region: Region) {
+        if let Some(ref mut pending) = self.pending {
+            pending.grow(heightmap, region);
+            return;
+        }
+        self.pending = Some(Change::capture(label.to_string(), heightmap, region));
+    }
+
+    pub fn commit(&mut self) {
+        if let Some(change) = self.pending.take() {
+            self.undo.push_back(change);
+            self.redo.clear();
+            self.enforce_budget();
+        }
+    }
+
+    fn enforce_budget(&mut self) {
+        let mut used = self.memory_used();
+        while used > self.budget && self.undo.len() > 1 {
+            if let Some(oldest) = self.undo.pop_front() {
+                used -= oldest.size();
+            }
+        }
+    }
+
+    // In bytes, for the stored heights.
+    pub fn memory_used(&self) -> usize {
+        self.undo.iter().chain(self.redo.iter()).chain(self.pending.iter()).map(|change| change.size()).sum()
+    }
+
+    // Commits anything still pending first, so undo in the middle of a stroke takes back the stroke so far. Returns
+    // the change undone, to tell what was touched.
+    pub fn undo(&mut self, heightmap: &mut Heightmap) -> Option<&Change> {
+        self.commit();
+        match self.undo.pop_back() {
+            Some(mut change) => {
+                change.swap(heightmap);
+                self.redo.push(change);
+                self.redo.last()
+            },
+            None => None,
+        }
+    }
+
+    pub fn redo(&mut self, heightmap: &mut Heightmap) -> Option<&Change> {
+        self.commit();
+        match self.redo.pop() {
+            Some(mut change) => {
+                change.swap(heightmap);
+                self.undo.push_back(change);
+                self.undo.back()
+            },
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use terrain::sculpt::{ self, Brush, BrushKind };
+    use super::*;
+
+    fn bits(heightmap: &Heightmap) -> Vec<u32> {
+        heightmap.heights().iter().map(|height| height.to_bits()).collect()
+    }
+
+    // Brushes along a diagonal line, recording each dab first, the way RenderableTerrain does.
+    fn stroke(history: &mut History, heightmap: &mut Heightmap, brush: &Brush, from: (f32, f32), to: (f32, f32)) {
+        for i in 0..20 {
+            let t = i as f32 / 19.0;
+            let (x, z) = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+            if let Some(region) = sculpt::brush_region(heightmap, brush, x, z) {
+                history.record("stroke", heightmap, region);
+                sculpt::apply_brush(heightmap, brush, x, z, 0.1);
+            }
+        }
+        history.commit();
+    }
+
+    #[test]
+    fn undo_and_redo_restore_exact_heights() {
+        let mut heightmap = Heightmap::from_fn(50, 37, 1.0, |x, z| ((x * 31 + z * 17) % 13) as f32 * 0.25);
+        let mut history = History::default();
+        let original = bits(&heightmap);
+
+        stroke(&mut history, &mut heightmap, &Brush::default(), (2.0, 3.0), (47.0, 34.0));
+        let after_raise = bits(&heightmap);
+        assert!(after_raise != original, "the stroke changed nothing");
+
+        let smooth = Brush {
+            kind: BrushKind::Smooth,
+            radius: 6.0,
+            ..Default::default()
+        };
+        stroke(&mut history, &mut heightmap, &smooth, (49.0, 0.0), (0.0, 36.0));
+        let after_smooth = bits(&heightmap);
+
+        assert_eq!(history.undo(&mut heightmap).map(|change| change.label().to_string()), Some("stroke".to_string()));
+        assert_eq!(bits(&heightmap), after_raise);
+        assert!(history.undo(&mut heightmap).is_some());
+        assert_eq!(bits(&heightmap), original);
+        assert!(history.undo(&mut heightmap).is_none());
+
+        assert!(history.redo(&mut heightmap).is_some());
+        assert_eq!(bits(&heightmap), after_raise);
+        assert!(history.redo(&mut heightmap).is_some());
+        assert_eq!(bits(&heightmap), after_smooth);
+        assert!(history.redo(&mut heightmap).is_none());
+    }
+
+    #[test]
+    fn diagonal_strokes_keep_less_than_their_bounding_box() {
+        let mut heightmap = Heightmap::new(129, 129, 1.0);
+        let mut history = History::default();
+        stroke(&mut history, &mut heightmap, &Brush::default(), (0.0, 0.0), (128.0, 128.0));
+        assert!(history.memory_used() < 129 * 129 * mem::size_of::<f32>() / 2, "kept {} bytes", history.memory_used());
+    }
+
+    #[test]
+    fn budget_forgets_the_oldest_changes() {
+        let mut heightmap = Heightmap::new(TILE_SIZE, TILE_SIZE, 1.0);
+        let whole = Region::whole(&heightmap);
+        let one_change = TILE_SIZE * TILE_SIZE * mem::size_of::<f32>();
+        let mut history = History::new(2 * one_change);
+
+        for i in 0..3 {
+            history.record(&format!("change {}", i), &heightmap, whole);
+            heightmap.set(0, 0, (i + 1) as f32);
+            history.commit();
+        }
+        assert_eq!(history.memory_used(), 2 * one_change);
+
+        assert_eq!(history.undo(&mut heightmap).map(|change| change.label().to_string()), Some("change 2".to_string()));
+        assert_eq!(history.undo(&mut heightmap).map(|change| change.label().to_string()), Some("change 1".to_string()));
+        assert!(history.undo(&mut heightmap).is_none());
+        // Change 0 is gone, so its effect stays.
+        assert_eq!(heightmap.get(0, 0), 1.0);
+    }
+}
@@ -0,0 +1,501 @@
+use std::{ fs, io, path };
+use std::f32::consts::{ FRAC_PI_4, SQRT_2 };
+use std::io::Write;
+use glm;
+use serde_json;
+use wavefront_obj::mtl;
+
+use objects::MeshData;
+use terrain::Heightmap;
+use terrain::export::ExportError;
+use terrain::lakes;
+
+// Slope given to filled depressions so that flow can find its way across them.
+const FLOW_EPSILON: f32 = 1e-4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowMethod {
+    // Everything drains to the single steepest downhill neighbor.
+    D8,
+    // Tarboton's D-infinity: flow leaves along the steepest of eight triangular facets and is split between the two
+    // neighbors bounding it, which spreads it out more naturally over planar slopes.
+    DInfinity,
+}
+
+#[derive(Debug, Clone)]
+pub struct RiverSettings {
+    pub method: FlowMethod,
+    // Route flow over a depression-filled copy of the heightmap, so that rivers run through pits instead of ending in
+    // them.
+    pub fill_depressions: bool,
+    // Upstream area, in cells, a cell needs to drain to count as a river.
+    pub threshold: f32,
+    // How deep channels are carved where they reach the threshold; bigger rivers cut deeper, up to max_carve_depth.
+    pub carve_depth: f32,
+    pub max_carve_depth: f32,
+    // Width in world units of the water overlay where a river reaches the threshold, growing downstream up to
+    // max_width.
+    pub width: f32,
+    pub max_width: f32,
+}
+
+impl Default for RiverSettings {
+    fn default() -> RiverSettings {
+        RiverSettings {
+            method: FlowMethod::DInfinity,
+            fill_depressions: true,
+            threshold: 400.0,
+            carve_depth: 0.3,
+            max_carve_depth: 1.5,
+            width: 0.4,
+            max_width: 2.0,
+        }
+    }
+}
+
+impl RiverSettings {
+    // Grows with the square root of the drained area, as real channels roughly do.
+    fn scale(&self, accumulation: f32) -> f32 {
+        (accumulation / self.threshold).max(1.0).sqrt()
+    }
+}
+
+// Counter-clockwise from east, so that consecutive pairs (cardinal, diagonal) bound the D-infinity facets.
+const NEIGHBOR_OFFSETS: [(isize, isize); 8] = [
+    (1, 0), (1, -1), (0, -1), (-1, -1),
+    (-1, 0), (-1, 1), (0, 1), (1, 1),
+];
+
+fn neighbor(heightmap: &Heightmap, x: usize, z: usize, offset: (isize, isize)) -> Option<(usize, usize)> {
+    let nx = x as isize + offset.0;
+    let nz = z as isize + offset.1;
+    if nx < 0 || nz < 0 || nx >= heightmap.width() as isize || nz >= heightmap.depth() as isize {
+        None
+    } else {
+        Some((nx as usize, nz as usize))
+    }
+}
+
+fn distance(heightmap: &Heightmap, offset: (isize, isize)) -> f32 {
+    let steps = if offset.0 != 0 && offset.1 != 0 { SQRT_2 } else { 1.0 };
+    steps * heightmap.spacing()
+}
+
+// The steepest strictly-downhill neighbor of every cell, as an index into the heightmap; None for pits and flats.
+pub fn d8_directions(heightmap: &Heightmap) -> Vec<Option<usize>> {
+    let mut directions = Vec::with_capacity(heightmap.heights().len());
+    for z in 0..heightmap.depth() {
+        for x in 0..heightmap.width() {
+            let height = heightmap.get(x, z);
+            let mut steepest = None;
+            let mut steepest_slope = 0.0;
+            for &offset in NEIGHBOR_OFFSETS.iter() {
+                if let Some((nx, nz)) = neighbor(heightmap, x, z, offset) {
+                    let slope = (height - heightmap.get(nx, nz)) / distance(heightmap, offset);
+                    if slope > steepest_slope {
+                        steepest_slope = slope;
+                        steepest = Some(nz * heightmap.width() + nx);
+                    }
+                }
+            }
+            directions.push(steepest);
+        }
+    }
+    directions
+}
+
+// Where (x, z) sends its flow, as (cell index, fraction) pairs. Along the edges, facets that would leave the map are
+// skipped.
+fn d_infinity_receivers(heightmap: &Heightmap, x: usize, z: usize) -> Vec<(usize, f32)> {
+    let height = heightmap.get(x, z);
+    let spacing = heightmap.spacing();
+    let index = |(nx, nz): (usize, usize)| nz * heightmap.width() + nx;
+
+    let mut best: Option<(f32, Vec<(usize, f32)>)> = None;
+    for facet in 0..8 {
+        // Facets alternate between starting from a cardinal and from a diagonal neighbor.
+        let (cardinal, diagonal) = if facet % 2 == 0 {
+            (NEIGHBOR_OFFSETS[facet], NEIGHBOR_OFFSETS[facet + 1])
+        } else {
+            (NEIGHBOR_OFFSETS[(facet + 1) % 8], NEIGHBOR_OFFSETS[facet])
+        };
+        let (c, d) = match (neighbor(heightmap, x, z, cardinal), neighbor(heightmap, x, z, diagonal)) {
+            (Some(c), Some(d)) => (c, d),
+            _ => continue,
+        };
+
+        let h1 = heightmap.get(c.0, c.1);
+        let h2 = heightmap.get(d.0, d.1);
+        let s1 = (height - h1) / spacing;
+        let s2 = (h1 - h2) / spacing;
+        let (angle, slope) = if s2 <= 0.0 {
+            (0.0, s1)
+        } else if s2.atan2(s1) >= FRAC_PI_4 {
+            (FRAC_PI_4, (height - h2) / (SQRT_2 * spacing))
+        } else {
+            (s2.atan2(s1), (s1 * s1 + s2 * s2).sqrt())
+        };
+        if slope <= 0.0 || best.as_ref().map_or(false, |&(best_slope, _)| best_slope >= slope) {
+            continue;
+        }
+
+        let to_diagonal = angle / FRAC_PI_4;
+        let mut receivers = vec![];
+        if to_diagonal < 1.0 {
+            receivers.push((index(c), 1.0 - to_diagonal));
+        }
+        if to_diagonal > 0.0 {
+            receivers.push((index(d), to_diagonal));
+        }
+        best = Some((slope, receivers));
+    }
+
+    best.map_or_else(Vec::new, |(_, receivers)| receivers)
+}
+
+// How many cells' worth of rain passes through every cell, counting its own, assuming every cell gets the same amount.
+// Flow that reaches a pit stops there; see lakes::priority_flood.
+pub fn flow_accumulation(heightmap: &Heightmap, method: FlowMethod) -> Vec<f32> {
+    let width = heightmap.width();
+    let count = heightmap.heights().len();
+    let d8 = if method == FlowMethod::D8 { d8_directions(heightmap) } else { vec![] };
+
+    // Highest first, so that every cell has collected all of its inflow before passing it on. Ties break by index to
+    // keep things deterministic.
+    let heights = heightmap.heights();
+    let mut order: Vec<usize> = (0..count).collect();
+    order.sort_by(|&a, &b| heights[b].partial_cmp(&heights[a]).unwrap().then(a.cmp(&b)));
+
+    let mut accumulation = vec![1.0f32; count];
+    for i in order {
+        let receivers = match method {
+            FlowMethod::D8 => d8[i].into_iter().map(|r| (r, 1.0)).collect(),
+            FlowMethod::DInfinity => d_infinity_receivers(heightmap, i % width, i / width),
+        };
+        let outflow = accumulation[i];
+        for (receiver, fraction) in receivers {
+            accumulation[receiver] += outflow * fraction;
+        }
+    }
+    accumulation
+}
+
+// A chain of cells from a source (or confluence) downstream to a confluence, pit or the edge of the map.
+#[derive(Debug, Clone)]
+pub struct River {
+    // Grid coordinates of each point, upstream first.
+    pub points: Vec<(usize, usize)>,
+    pub accumulation: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RiverNetwork {
+    pub rivers: Vec<River>,
+    pub spacing: f32,
+}
+
+// Rivers follow D8 directions whichever method the accumulation came from, since a polyline can't split.
+pub fn extract_rivers(heightmap: &Heightmap, accumulation: &[f32], threshold: f32) -> RiverNetwork {
+    let width = heightmap.width();
+    let directions = d8_directions(heightmap);
+    let is_river = |i: usize| accumulation[i] >= threshold;
+
+    let mut has_river_inflow = vec![false; accumulation.len()];
+    for (i, direction) in directions.iter().enumerate() {
+        if let Some(receiver) = *direction {
+            if is_river(i) {
+                has_river_inflow[receiver] = true;
+            }
+        }
+    }
+
+    // Trace down from every source. Anything downstream of a river is a river too, unless D-infinity accumulation
+    // dipped under the threshold along the D8 path, in which case it picks up again as a new source further down.
+    let mut visited = vec![false; accumulation.len()];
+    let mut rivers = vec![];
+    for start in (0..accumulation.len()).filter(|&i| is_river(i) && !has_river_inflow[i]) {
+        let mut points = vec![];
+        let mut accumulations = vec![];
+        let mut current = Some(start);
+        while let Some(i) = current {
+            points.push((i % width, i / width));
+            accumulations.push(accumulation[i]);
+            if visited[i] {
+                // Joined a river we already traced; the confluence ends this one.
+                break;
+            }
+            visited[i] = true;
+            current = match directions[i] {
+                Some(next) if is_river(next) => Some(next),
+                _ => None,
+            };
+        }
+        if points.len() > 1 {
+            rivers.push(River {
+                points: points,
+                accumulation: accumulations,
+            });
+        }
+    }
+
+    RiverNetwork {
+        rivers: rivers,
+        spacing: heightmap.spacing(),
+    }
+}
+
+// Digs every river into the heightmap, deepest where the most water flows, keeping each one running downhill. Depths
+// are measured from `surface`, the heights the water was routed over; where that's a depression-filled copy of the
+// heightmap, a river crossing a lake only cuts into its bed where the bed is higher than the channel. The channel does
+// cut through the lake's outlet, though, which lowers the spill point, so lakes detected afterwards are that much
+// shallower.
+pub fn carve_rivers(
+    heightmap: &mut Heightmap,
+    surface: &Heightmap,
+    network: &RiverNetwork,
+    settings: &RiverSettings
+) {
+    let width = heightmap.width();
+    let depth_at = |accumulation: f32| {
+        (settings.carve_depth * settings.scale(accumulation)).min(settings.max_carve_depth)
+    };
+
+    // The bed under every river point. A tributary can cut deeper than the river it joins, which may already have been
+    // traced past the confluence, so beds are carried downstream through confluences until none of them changes.
+    let mut beds = vec![::std::f32::INFINITY; heightmap.heights().len()];
+    loop {
+        let mut changed = false;
+        for river in network.rivers.iter() {
+            let mut bed = ::std::f32::INFINITY;
+            for (&(x, z), &accumulation) in river.points.iter().zip(river.accumulation.iter()) {
+                let i = z * width + x;
+                bed = bed.min(surface.get(x, z) - depth_at(accumulation)).min(beds[i]);
+                if bed < beds[i] {
+                    beds[i] = bed;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    for river in network.rivers.iter() {
+        for (&(x, z), &accumulation) in river.points.iter().zip(river.accumulation.iter()) {
+            let bed = beds[z * width + x];
+            let depth = depth_at(accumulation);
+            let radius = 1;
+            // Soften the banks so the channel isn't a one-cell slot.
+            for dz in -radius..(radius + 1) {
+                for dx in -radius..(radius + 1) {
+                    if let Some((nx, nz)) = neighbor(heightmap, x, z, (dx, dz)) {
+                        let bank = if dx == 0 && dz == 0 { bed } else { bed + depth * 0.5 };
+                        if heightmap.get(nx, nz) > bank {
+                            heightmap.set(nx, nz, bank);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Flow accumulation, polylines and carving in one go. Returns the network as it was extracted, which still lines up
+// with the carved heightmap.
+pub fn generate_rivers(heightmap: &mut Heightmap, settings: &RiverSettings) -> RiverNetwork {
+    let surface = if settings.fill_depressions {
+        lakes::priority_flood(heightmap, FLOW_EPSILON)
+    } else {
+        heightmap.clone()
+    };
+    let accumulation = flow_accumulation(&surface, settings.method);
+    let network = extract_rivers(&surface, &accumulation, settings.threshold);
+    carve_rivers(heightmap, &surface, &network, settings);
+    info!("carved {} rivers", network.rivers.len());
+    network
+}
+
+#[derive(Serialize)]
+struct GeoJsonGeometry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: Vec<[f32; 3]>,
+}
+
+#[derive(Serialize)]
+struct GeoJsonProperties {
+    max_accumulation: f32,
+    length: f32,
+}
+
+#[derive(Serialize)]
+struct GeoJsonFeature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: GeoJsonGeometry,
+    properties: GeoJsonProperties,
+}
+
+#[derive(Serialize)]
+struct GeoJsonFeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<GeoJsonFeature>,
+}
+
+// One LineString per river. Coordinates are [x, z, height] in the heightmap's local space, since terrain has no
+// latitude or longitude.
+pub fn export_geojson(heightmap: &Heightmap, network: &RiverNetwork, path: &path::Path) -> Result<(), ExportError> {
+    let features = network.rivers
+        .iter()
+        .map(|river| {
+            let coordinates: Vec<[f32; 3]> = river.points
+                .iter()
+                .map(|&(x, z)| [x as f32 * network.spacing, z as f32 * network.spacing, heightmap.get(x, z)])
+                .collect();
+            let length: f32 = coordinates
+                .windows(2)
+                .map(|pair| ((pair[1][0] - pair[0][0]).powi(2) + (pair[1][1] - pair[0][1]).powi(2)).sqrt())
+                .sum();
+            GeoJsonFeature {
+                kind: "Feature",
+                geometry: GeoJsonGeometry {
+                    kind: "LineString",
+                    coordinates: coordinates,
+                },
+                properties: GeoJsonProperties {
+                    max_accumulation: river.accumulation.iter().cloned().fold(0.0, f32::max),
+                    length: length,
+                },
+            }
+        })
+        .collect();
+
+    let collection = GeoJsonFeatureCollection {
+        kind: "FeatureCollection",
+        features: features,
+    };
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    file.write_all(serde_json::to_string_pretty(&collection)?.as_bytes())?;
+    info!("exported {} rivers to {:?}", network.rivers.len(), path);
+    Ok(())
+}
+
+lazy_static! {
+    pub static ref WATER_MATERIAL: mtl::Material = mtl::Material {
+        name: "water".to_owned(),
+        specular_coefficient: 40.0,
+        color_ambient: mtl::Color { r: 0.02, g: 0.06, b: 0.12 },
+        color_diffuse: mtl::Color { r: 0.1, g: 0.3, b: 0.6 },
+        color_specular: mtl::Color { r: 0.6, g: 0.6, b: 0.6 },
+        color_emissive: Option::None,
+        optical_density: Option::None,
+        alpha: 1.0,
+        illumination: mtl::Illumination::AmbientDiffuseSpecular,
+        uv_map: Option::None,
+    };
+}
+
+// A flat ribbon along every river, floating just above the carved bed, in the heightmap's local space.
+pub fn river_mesh(heightmap: &Heightmap, network: &RiverNetwork, settings: &RiverSettings) -> MeshData {
+    let up = glm::vec3(0.0, 1.0, 0.0);
+    let mut positions = vec![];
+    let mut indices = vec![];
+    for river in network.rivers.iter() {
+        let points: Vec<glm::Vec3> = river.points
+            .iter()
+            .map(|&(x, z)| glm::vec3(x as f32 * network.spacing, heightmap.get(x, z) + 0.05, z as f32 * network.spacing))
+            .collect();
+
+        for i in 0..points.len() {
+            let along = points[(i + 1).min(points.len() - 1)] - points[i.saturating_sub(1)];
+            let side = glm::normalize(glm::cross(up, glm::vec3(along.x, 0.0, along.z)));
+            let half_width = (settings.width * settings.scale(river.accumulation[i])).min(settings.max_width) / 2.0;
+
+            let first = positions.len() as u32;
+            positions.push(points[i] + side * half_width);
+            positions.push(points[i] - side * half_width);
+            if i > 0 {
+                // Wound like mesh::grid_indices, with the previous pair as the top row of the cell.
+                indices.extend_from_slice(&[first - 2, first - 1, first, first, first - 1, first + 1]);
+            }
+        }
+    }
+
+    let count = positions.len();
+    MeshData::with_material(positions, vec![up; count], vec![glm::vec2(0.0, 0.0); count], indices, &WATER_MATERIAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Downhill toward x = 0 and z = 0, more steeply along z, so that the steepest way down isn't along either axis.
+    fn tilted_plane() -> Heightmap {
+        Heightmap::from_fn(23, 17, 1.0, |x, z| x as f32 * 0.3 + z as f32 * 0.7)
+    }
+
+    #[test]
+    fn d8_accumulation_counts_the_cells_upslope() {
+        let heightmap = tilted_plane();
+        let directions = d8_directions(&heightmap);
+        let accumulation = flow_accumulation(&heightmap, FlowMethod::D8);
+
+        // Follow every cell's flow down to where it stops, counting each cell it passes through.
+        let mut expected = vec![0.0f32; directions.len()];
+        for start in 0..directions.len() {
+            let mut current = Some(start);
+            while let Some(i) = current {
+                expected[i] += 1.0;
+                current = directions[i];
+            }
+        }
+        assert_eq!(accumulation, expected);
+        assert!(accumulation.iter().any(|&a| a > 10.0), "flow never collects; the test isn't exercising much");
+    }
+
+    #[test]
+    fn d_infinity_delivers_all_the_rain_to_the_outlets() {
+        let heightmap = tilted_plane();
+        let accumulation = flow_accumulation(&heightmap, FlowMethod::DInfinity);
+
+        let (width, depth) = (heightmap.width(), heightmap.depth());
+        let mut split = false;
+        let mut delivered = 0.0;
+        for z in 0..depth {
+            for x in 0..width {
+                let receivers = d_infinity_receivers(&heightmap, x, z);
+                split |= receivers.len() == 2;
+                if receivers.is_empty() {
+                    delivered += accumulation[z * width + x];
+                }
+            }
+        }
+        assert!(split, "no flow was split between two neighbors; the test isn't exercising D-infinity");
+        let total = (width * depth) as f32;
+        assert!((delivered - total).abs() < total * 1e-5, "{} of {} cells' rain reached an outlet", delivered, total);
+    }
+
+    #[test]
+    fn carved_river_beds_never_rise_downstream() {
+        let mut heightmap = Heightmap::from_fn(64, 64, 1.0, |x, z| {
+            let (x, z) = (x as f32, z as f32);
+            x * 0.05 + z * 0.1 + (x * 0.4).sin() * (z * 0.3).cos() * 0.6
+        });
+        let settings = RiverSettings {
+            threshold: 30.0,
+            ..Default::default()
+        };
+        let network = generate_rivers(&mut heightmap, &settings);
+        assert!(network.rivers.len() > 1);
+        for river in network.rivers.iter() {
+            for pair in river.points.windows(2) {
+                let upstream = heightmap.get(pair[0].0, pair[0].1);
+                let downstream = heightmap.get(pair[1].0, pair[1].1);
+                assert!(downstream <= upstream, "bed rises from {} at {:?} to {} at {:?}",
+                        upstream, pair[0], downstream, pair[1]);
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::{ error, fmt, fs, io, path };
+use std::io::Read;
+use image;
+use image::{ ColorType, DecodingResult, ImageDecoder };
+use png;
+use png::HasParameters;
+use serde_json;
+
+use terrain::Heightmap;
+use terrain::export::{ sidecar_path, HeightmapFormat, HeightmapMetadata };
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportSettings {
+    // Height of a full-white pixel above a full-black one.
+    pub vertical_scale: f32,
+    // Height of a full-black pixel.
+    pub vertical_offset: f32,
+    // Distance between adjacent pixels in world units.
+    pub spacing: f32,
+}
+
+impl Default for ImportSettings {
+    fn default() -> ImportSettings {
+        ImportSettings {
+            vertical_scale: 50.0,
+            vertical_offset: 0.0,
+            spacing: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    Image(image::ImageError),
+    // Only PNG, TIFF and BMP are read; the value is the file extension we got instead.
+    UnsupportedFormat(String),
+    UnsupportedColorType(ColorType),
+    // An RGB(A) image whose channels differ somewhere, so there's no single obvious height per pixel.
+    NotGrayscale,
+    TooSmall(u32, u32),
+    // Dimensions whose sample count doesn't fit in memory, let alone a file.
+    TooLarge(usize, usize),
+    // Spacing has to be positive and finite.
+    InvalidSpacing(f32),
+    Metadata(serde_json::Error),
+    // A RAW file whose length doesn't match the dimensions in its metadata.
+    WrongRawSize { expected: usize, actual: usize },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::Io(ref e) => write!(f, "couldn't read heightmap: {}", e),
+            ImportError::Image(ref e) => write!(f, "couldn't decode heightmap: {}", e),
+            ImportError::UnsupportedFormat(ref extension) =>
+                write!(f, "unsupported heightmap format '{}'; expected png, tif/tiff or bmp", extension),
+            ImportError::UnsupportedColorType(color_type) =>
+                write!(f, "unsupported heightmap color type {:?}; expected 8- or 16-bit grayscale", color_type),
+            ImportError::NotGrayscale =>
+                write!(f, "heightmap has color; expected grayscale (or RGB with identical channels)"),
+            ImportError::TooSmall(width, height) =>
+                write!(f, "heightmap is {}x{}, but needs to be at least 2x2", width, height),
+            ImportError::TooLarge(width, depth) => write!(f, "heightmap is {}x{}, which is too large", width, depth),
+            ImportError::InvalidSpacing(spacing) =>
+                write!(f, "heightmap spacing is {}, but needs to be positive and finite", spacing),
+            ImportError::Metadata(ref e) => write!(f, "couldn't parse heightmap metadata: {}", e),
+            ImportError::WrongRawSize { expected, actual } =>
+                write!(f, "RAW heightmap should be {} bytes according to its metadata, but is {}", expected, actual),
+        }
+    }
+}
+
+impl error::Error for ImportError {
+    fn description(&self) -> &str {
+        match *self {
+            ImportError::Io(_) => "I/O error reading heightmap",
+            ImportError::Image(_) => "error decoding heightmap image",
+            ImportError::UnsupportedFormat(_) => "unsupported heightmap format",
+            ImportError::UnsupportedColorType(_) => "unsupported heightmap color type",
+            ImportError::NotGrayscale => "heightmap is not grayscale",
+            ImportError::TooSmall(_, _) => "heightmap is too small",
+            ImportError::TooLarge(_, _) => "heightmap is too large",
+            ImportError::InvalidSpacing(_) => "heightmap spacing is invalid",
+            ImportError::Metadata(_) => "error parsing heightmap metadata",
+            ImportError::WrongRawSize { .. } => "RAW heightmap has the wrong size",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ImportError::Io(ref e) => Some(e),
+            ImportError::Image(ref e) => Some(e),
+            ImportError::Metadata(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> ImportError {
+        ImportError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for ImportError {
+    fn from(e: image::ImageError) -> ImportError {
+        ImportError::Image(e)
+    }
+}
+
+impl From<serde_json::Error> for ImportError {
+    fn from(e: serde_json::Error) -> ImportError {
+        ImportError::Metadata(e)
+    }
+}
+
+// Every pixel as a value in [0, 1], row by row.
+fn normalized_samples(color_type: ColorType, data: DecodingResult) -> Result<Vec<f32>, ImportError> {
+    match (color_type, data) {
+        (ColorType::Gray(8), DecodingResult::U8(bytes)) =>
+            Ok(bytes.iter().map(|&b| b as f32 / 255.0).collect()),
+        (ColorType::Gray(16), DecodingResult::U16(words)) =>
+            Ok(words.iter().map(|&w| w as f32 / 65535.0).collect()),
+        // The PNG decoder hands 16-bit samples back as raw big-endian bytes.
+        (ColorType::Gray(16), DecodingResult::U8(bytes)) =>
+            Ok(bytes.chunks(2).map(|pair| ((pair[0] as u16) << 8 | pair[1] as u16) as f32 / 65535.0).collect()),
+        // BMPs always decode as RGB(A), even if they were saved as grayscale.
+        (ColorType::RGB(8), DecodingResult::U8(bytes)) => grayscale_from_color(&bytes, 3),
+        (ColorType::RGBA(8), DecodingResult::U8(bytes)) => grayscale_from_color(&bytes, 4),
+        (other, _) => Err(ImportError::UnsupportedColorType(other)),
+    }
+}
+
+fn grayscale_from_color(bytes: &[u8], channels: usize) -> Result<Vec<f32>, ImportError> {
+    bytes
+        .chunks(channels)
+        .map(|pixel| {
+            if pixel[0] == pixel[1] && pixel[1] == pixel[2] {
+                Ok(pixel[0] as f32 / 255.0)
+            } else {
+                Err(ImportError::NotGrayscale)
+            }
+        })
+        .collect()
+}
+
+fn check_spacing(spacing: f32) -> Result<(), ImportError> {
+    if spacing.is_finite() && spacing > 0.0 {
+        Ok(())
+    } else {
+        Err(ImportError::InvalidSpacing(spacing))
+    }
+}
+
+fn from_samples(
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    data: DecodingResult,
+    settings: &ImportSettings
+) -> Result<Heightmap, ImportError> {
+    if width < 2 || height < 2 {
+        return Err(ImportError::TooSmall(width, height));
+    }
+    check_spacing(settings.spacing)?;
+
+    let heights = normalized_samples(color_type, data)?
+        .into_iter()
+        .map(|s| settings.vertical_offset + s * settings.vertical_scale)
+        .collect();
+    Ok(Heightmap::from_heights(width as usize, height as usize, settings.spacing, heights))
+}
+
+fn decode<D: ImageDecoder>(mut decoder: D, settings: &ImportSettings) -> Result<Heightmap, ImportError> {
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    from_samples(width, height, color_type, decoder.read_image()?, settings)
+}
+
+// image's PNG decoder has the png crate strip 16-bit samples down to 8 bits, which would throw away most of a 16-bit
+// heightmap's precision, so PNGs are read with the png crate directly and only palettes and low bit depths expanded.
+fn decode_png<R: io::Read>(reader: R, settings: &ImportSettings) -> Result<Heightmap, ImportError> {
+    let mut decoder = png::Decoder::new(reader);
+    decoder.set(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info().map_err(image::ImageError::from)?;
+    // Expanding leaves 16-bit samples as they are, but is reported as producing 8-bit ones regardless.
+    let bit_depth = match reader.info().bit_depth {
+        png::BitDepth::Sixteen => png::BitDepth::Sixteen,
+        _ => info.bit_depth,
+    };
+
+    let mut data = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut data).map_err(image::ImageError::from)?;
+    from_samples(info.width, info.height, (info.color_type, bit_depth).into(), DecodingResult::U8(data), settings)
+}
+
+pub fn load_heightmap(path: &path::Path, settings: &ImportSettings) -> Result<Heightmap, ImportError> {
+    debug!("loading heightmap from {:?}", path);
+
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_else(String::new);
+    let reader = io::BufReader::new(fs::File::open(path)?);
+
+    match extension.as_str() {
+        "png" => decode_png(reader, settings),
+        "tif" | "tiff" => decode(image::tiff::TIFFDecoder::new(reader)?, settings),
+        "bmp" => decode(image::bmp::BMPDecoder::new(reader), settings),
+        _ => Err(ImportError::UnsupportedFormat(extension.clone())),
+    }
+}
+
+pub fn read_metadata(path: &path::Path) -> Result<HeightmapMetadata, ImportError> {
+    let mut contents = String::new();
+    fs::File::open(sidecar_path(path))?.read_to_string(&mut contents)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+fn load_raw(path: &path::Path, metadata: &HeightmapMetadata) -> Result<Heightmap, ImportError> {
+    // The metadata is only JSON, and may have been truncated or edited by hand.
+    let (width, depth) = (metadata.width, metadata.depth);
+    if width < 2 || depth < 2 {
+        let saturate = |n: usize| n.min(::std::u32::MAX as usize) as u32;
+        return Err(ImportError::TooSmall(saturate(width), saturate(depth)));
+    }
+    check_spacing(metadata.spacing)?;
+    let bytes_per_sample = if metadata.format == HeightmapFormat::RawU16 { 2 } else { 4 };
+    let expected = match width.checked_mul(depth).and_then(|count| count.checked_mul(bytes_per_sample)) {
+        Some(expected) => expected,
+        None => return Err(ImportError::TooLarge(width, depth)),
+    };
+
+    let mut bytes = vec![];
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() != expected {
+        return Err(ImportError::WrongRawSize { expected: expected, actual: bytes.len() });
+    }
+
+    let heights = bytes
+        .chunks(bytes_per_sample)
+        .map(|sample| {
+            if metadata.format == HeightmapFormat::RawU16 {
+                let value = sample[0] as u16 | (sample[1] as u16) << 8;
+                metadata.vertical_offset + value as f32 / 65535.0 * metadata.vertical_scale
+            } else {
+                let bits = sample
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (i * 8));
+                f32::from_bits(bits)
+            }
+        })
+        .collect();
+    Ok(Heightmap::from_heights(width, depth, metadata.spacing, heights))
+}
+
+// Reads back anything written by the export module, using the sidecar metadata for scale and dimensions.
+pub fn load_exported(path: &path::Path) -> Result<Heightmap, ImportError> {
+    let metadata = read_metadata(path)?;
+    match metadata.format {
+        HeightmapFormat::Png16 => load_heightmap(path, &ImportSettings {
+            vertical_scale: metadata.vertical_scale,
+            vertical_offset: metadata.vertical_offset,
+            spacing: metadata.spacing,
+        }),
+        HeightmapFormat::RawU16 | HeightmapFormat::RawF32 => load_raw(path, &metadata),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ env, fs, path, process };
+
+    use terrain::Heightmap;
+    use terrain::export;
+    use super::*;
+
+    fn heightmap() -> Heightmap {
+        Heightmap::from_fn(9, 7, 2.0, |x, z| (x as f32 * 0.37).sin() * 10.0 + z as f32 * 1.5 - 4.0)
+    }
+
+    // Exports with `export`, reads the result back and checks every height is within `tolerance` of the original.
+    fn round_trip<F>(extension: &str, tolerance: f32, export: F)
+        where F: Fn(&Heightmap, &path::Path) -> Result<export::HeightmapMetadata, export::ExportError> {
+        let original = heightmap();
+        let directory = env::temp_dir().join(format!("terrain-generator-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(format!("round-trip.{}", extension));
+
+        export(&original, &path).unwrap();
+        let loaded = load_exported(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(export::sidecar_path(&path)).unwrap();
+
+        assert_eq!((loaded.width(), loaded.depth(), loaded.spacing()), (9, 7, 2.0));
+        for (&expected, &actual) in original.heights().iter().zip(loaded.heights()) {
+            assert!((expected - actual).abs() <= tolerance, "expected {}, got {}", expected, actual);
+        }
+    }
+
+    fn quantization_step() -> f32 {
+        let (min, max) = heightmap().range();
+        (max - min) / 65535.0
+    }
+
+    #[test]
+    fn png16_round_trips_within_one_step() {
+        round_trip("png", quantization_step(), |heightmap, path| export::export_png16(heightmap, path, None));
+    }
+
+    #[test]
+    fn raw_u16_round_trips_within_one_step() {
+        round_trip("r16", quantization_step(), |heightmap, path| {
+            export::export_raw(heightmap, path, export::HeightmapFormat::RawU16, None)
+        });
+    }
+
+    #[test]
+    fn raw_f32_round_trips_exactly() {
+        round_trip("r32", 0.0, |heightmap, path| {
+            export::export_raw(heightmap, path, export::HeightmapFormat::RawF32, Some(7))
+        });
+    }
+
+    // Exports a RAW heightmap, then overwrites its metadata with `edit` applied and tries to load it back.
+    fn load_with_edited_metadata<F>(name: &str, edit: F) -> Result<Heightmap, ImportError>
+        where F: Fn(&mut export::HeightmapMetadata) {
+        let directory = env::temp_dir().join(format!("terrain-generator-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(format!("{}.r32", name));
+
+        let mut metadata = export::export_raw(&heightmap(), &path, export::HeightmapFormat::RawF32, None).unwrap();
+        edit(&mut metadata);
+        fs::write(export::sidecar_path(&path), serde_json::to_string(&metadata).unwrap()).unwrap();
+        let result = load_exported(&path);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(export::sidecar_path(&path)).unwrap();
+        result
+    }
+
+    #[test]
+    fn raw_metadata_is_checked_before_use() {
+        match load_with_edited_metadata("zero-width", |metadata| metadata.width = 0) {
+            Err(ImportError::TooSmall(0, 7)) => {},
+            other => panic!("expected TooSmall, got {:?}", other.map(|_| ())),
+        }
+        match load_with_edited_metadata("zero-spacing", |metadata| metadata.spacing = 0.0) {
+            Err(ImportError::InvalidSpacing(spacing)) => assert_eq!(spacing, 0.0),
+            other => panic!("expected InvalidSpacing, got {:?}", other.map(|_| ())),
+        }
+        match load_with_edited_metadata("huge", |metadata| metadata.depth = ::std::usize::MAX / 2) {
+            Err(ImportError::TooLarge(9, _)) => {},
+            other => panic!("expected TooLarge, got {:?}", other.map(|_| ())),
+        }
+        match load_with_edited_metadata("truncated", |metadata| metadata.depth = 8) {
+            Err(ImportError::WrongRawSize { expected, actual }) => {
+                assert_eq!((expected, actual), (9 * 8 * 4, 9 * 7 * 4));
+            },
+            other => panic!("expected WrongRawSize, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn export_raw_refuses_png() {
+        let path = env::temp_dir().join("never-written.png");
+        match export::export_raw(&heightmap(), &path, export::HeightmapFormat::Png16, None) {
+            Err(export::ExportError::NotRaw(export::HeightmapFormat::Png16)) => {},
+            other => panic!("expected NotRaw, got {:?}", other.map(|_| ())),
+        }
+        assert!(!path.exists());
+    }
+}
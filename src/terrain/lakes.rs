@@ -0,0 +1,330 @@
+use std::cmp::Ordering;
+use std::collections::{ BinaryHeap, VecDeque };
+use glm;
+use wavefront_obj::mtl;
+
+use objects::MeshData;
+use terrain::{ Filter, Heightmap };
+
+lazy_static! {
+    // Still water; darker and glossier than rivers.
+    pub static ref LAKE_MATERIAL: mtl::Material = mtl::Material {
+        name: "lake".to_owned(),
+        specular_coefficient: 80.0,
+        color_ambient: mtl::Color { r: 0.01, g: 0.04, b: 0.08 },
+        color_diffuse: mtl::Color { r: 0.05, g: 0.2, b: 0.4 },
+        color_specular: mtl::Color { r: 0.8, g: 0.8, b: 0.8 },
+        color_emissive: Option::None,
+        optical_density: Option::None,
+        alpha: 1.0,
+        illumination: mtl::Illumination::AmbientDiffuseSpecular,
+        uv_map: Option::None,
+    };
+}
+
+const NEIGHBOR_OFFSETS: [(isize, isize); 8] = [
+    (-1, -1), (0, -1), (1, -1),
+    (-1, 0), (1, 0),
+    (-1, 1), (0, 1), (1, 1),
+];
+
+fn neighbor(heightmap: &Heightmap, x: usize, z: usize, offset: (isize, isize)) -> Option<(usize, usize)> {
+    let nx = x as isize + offset.0;
+    let nz = z as isize + offset.1;
+    if nx < 0 || nz < 0 || nx >= heightmap.width() as isize || nz >= heightmap.depth() as isize {
+        None
+    } else {
+        Some((nx as usize, nz as usize))
+    }
+}
+
+// An entry in the flood's queue. BinaryHeap pops the largest, so this orders lowest level first, then lowest index to
+// keep ties deterministic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FloodCell {
+    level: f32,
+    index: usize,
+}
+
+impl Eq for FloodCell {}
+
+impl Ord for FloodCell {
+    fn cmp(&self, other: &FloodCell) -> Ordering {
+        other.level.partial_cmp(&self.level).unwrap().then(other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for FloodCell {
+    fn partial_cmp(&self, other: &FloodCell) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Priority-flood (Barnes et al., 2014): floods inward from the edges of the map, lowest first, raising every pit up
+// to its spill point. Everything can then drain off the edge. With an `epsilon` above zero, filled areas get a tiny
+// slope toward their outlet instead of being flat, so that flow routing can cross them; it has to be big enough not to
+// get lost to rounding at the heightmap's heights.
+pub fn priority_flood(heightmap: &Heightmap, epsilon: f32) -> Heightmap {
+    let (width, depth) = (heightmap.width(), heightmap.depth());
+    let mut filled = heightmap.clone();
+    let mut queued = vec![false; width * depth];
+    let mut open = BinaryHeap::new();
+
+    for z in 0..depth {
+        for x in 0..width {
+            if x == 0 || z == 0 || x == width - 1 || z == depth - 1 {
+                let index = z * width + x;
+                queued[index] = true;
+                open.push(FloodCell { level: heightmap.get(x, z), index: index });
+            }
+        }
+    }
+
+    while let Some(cell) = open.pop() {
+        let (x, z) = (cell.index % width, cell.index / width);
+        for &offset in NEIGHBOR_OFFSETS.iter() {
+            if let Some((nx, nz)) = neighbor(heightmap, x, z, offset) {
+                let index = nz * width + nx;
+                if queued[index] {
+                    continue;
+                }
+                queued[index] = true;
+                let level = heightmap.get(nx, nz).max(cell.level + epsilon);
+                filled.set(nx, nz, level);
+                open.push(FloodCell { level: level, index: index });
+            }
+        }
+    }
+    filled
+}
+
+// Fills every depression, so that nothing is left to hold water.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DepressionFill {
+    pub epsilon: f32,
+}
+
+impl Default for DepressionFill {
+    fn default() -> DepressionFill {
+        DepressionFill {
+            epsilon: 0.0,
+        }
+    }
+}
+
+impl Filter for DepressionFill {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        *heightmap = priority_flood(heightmap, self.epsilon);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LakeSettings {
+    // Shallower puddles than this aren't worth drawing.
+    pub min_depth: f32,
+    // Neither are lakes with fewer points than this.
+    pub min_area: usize,
+}
+
+impl Default for LakeSettings {
+    fn default() -> LakeSettings {
+        LakeSettings {
+            min_depth: 0.05,
+            min_area: 12,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lake {
+    // Height of the water surface, which is where the depression spills over.
+    pub level: f32,
+    pub max_depth: f32,
+    // Grid coordinates of every point under water.
+    pub cells: Vec<(usize, usize)>,
+}
+
+// Every depression deep and wide enough to count, found as the connected areas that priority-flood raised.
+pub fn detect_lakes(heightmap: &Heightmap, settings: &LakeSettings) -> Vec<Lake> {
+    let (width, depth) = (heightmap.width(), heightmap.depth());
+    let filled = priority_flood(heightmap, 0.0);
+    let flooded: Vec<bool> = filled
+        .heights()
+        .iter()
+        .zip(heightmap.heights().iter())
+        .map(|(&surface, &ground)| surface > ground)
+        .collect();
+
+    let mut seen = vec![false; width * depth];
+    let mut lakes = vec![];
+    for start in 0..(width * depth) {
+        if !flooded[start] || seen[start] {
+            continue;
+        }
+
+        // Neighboring flooded points can belong to different depressions at different levels; only join equal ones.
+        let level = filled.heights()[start];
+        let mut cells = vec![];
+        let mut max_depth = 0.0f32;
+        let mut frontier = VecDeque::new();
+        seen[start] = true;
+        frontier.push_back(start);
+        while let Some(index) = frontier.pop_front() {
+            let (x, z) = (index % width, index / width);
+            cells.push((x, z));
+            max_depth = max_depth.max(level - heightmap.get(x, z));
+            for &offset in NEIGHBOR_OFFSETS.iter() {
+                if let Some((nx, nz)) = neighbor(heightmap, x, z, offset) {
+                    let next = nz * width + nx;
+                    if flooded[next] && !seen[next] && filled.heights()[next] == level {
+                        seen[next] = true;
+                        frontier.push_back(next);
+                    }
+                }
+            }
+        }
+
+        if max_depth >= settings.min_depth && cells.len() >= settings.min_area {
+            lakes.push(Lake {
+                level: level,
+                max_depth: max_depth,
+                cells: cells,
+            });
+        }
+    }
+    info!("found {} lakes", lakes.len());
+    lakes
+}
+
+// A flat quad at water level around every flooded point, in the heightmap's local space. Each reaches all the way to
+// its neighbors so that the shoreline disappears into the terrain rather than leaving gaps.
+pub fn lake_mesh(heightmap: &Heightmap, lakes: &[Lake]) -> MeshData {
+    let spacing = heightmap.spacing();
+    let mut positions = vec![];
+    let mut indices = vec![];
+    for lake in lakes {
+        for &(x, z) in lake.cells.iter() {
+            let center = glm::vec3(x as f32 * spacing, lake.level, z as f32 * spacing);
+            let first = positions.len() as u32;
+            positions.push(center + glm::vec3(-spacing, 0.0, -spacing));
+            positions.push(center + glm::vec3(spacing, 0.0, -spacing));
+            positions.push(center + glm::vec3(-spacing, 0.0, spacing));
+            positions.push(center + glm::vec3(spacing, 0.0, spacing));
+            // Wound like mesh::grid_indices.
+            indices.extend_from_slice(&[first, first + 2, first + 1, first + 1, first + 2, first + 3]);
+        }
+    }
+
+    let count = positions.len();
+    let up = glm::vec3(0.0, 1.0, 0.0);
+    MeshData::with_material(positions, vec![up; count], vec![glm::vec2(0.0, 0.0); count], indices, &LAKE_MATERIAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTER: f32 = 10.0;
+
+    fn distance_from_center(x: usize, z: usize) -> f32 {
+        let (dx, dz) = (x as f32 - CENTER, z as f32 - CENTER);
+        (dx * dx + dz * dz).sqrt()
+    }
+
+    // A crater 0.2 deep in a plane tilted down toward x = 0, inside a rim at 3 with a notch at 2 on its low side. The
+    // rim is two cells thick so that nothing leaks out diagonally.
+    fn crater() -> Heightmap {
+        Heightmap::from_fn(21, 21, 1.0, |x, z| {
+            let distance = distance_from_center(x, z);
+            if distance < 5.0 {
+                0.2
+            } else if distance < 7.0 {
+                if z == 10 && x < 10 { 2.0 } else { 3.0 }
+            } else {
+                1.0 + 0.1 * x as f32
+            }
+        })
+    }
+
+    // Whether every point can reach the edge of the map without going uphill.
+    fn drains_everywhere(heightmap: &Heightmap) -> bool {
+        let (width, depth) = (heightmap.width(), heightmap.depth());
+        let mut drains = vec![false; width * depth];
+        let mut frontier = VecDeque::new();
+        for z in 0..depth {
+            for x in 0..width {
+                if x == 0 || z == 0 || x == width - 1 || z == depth - 1 {
+                    drains[z * width + x] = true;
+                    frontier.push_back((x, z));
+                }
+            }
+        }
+        // Walk back up from the edges: anything at least as high as a point that drains can drain through it.
+        while let Some((x, z)) = frontier.pop_front() {
+            for &offset in NEIGHBOR_OFFSETS.iter() {
+                if let Some((nx, nz)) = neighbor(heightmap, x, z, offset) {
+                    if !drains[nz * width + nx] && heightmap.get(nx, nz) >= heightmap.get(x, z) {
+                        drains[nz * width + nx] = true;
+                        frontier.push_back((nx, nz));
+                    }
+                }
+            }
+        }
+        drains.iter().all(|&drains| drains)
+    }
+
+    #[test]
+    fn priority_flood_fills_pits_to_their_spill_point() {
+        let heightmap = crater();
+        assert!(!drains_everywhere(&heightmap));
+
+        let filled = priority_flood(&heightmap, 0.0);
+        assert!(drains_everywhere(&filled));
+        for z in 0..heightmap.depth() {
+            for x in 0..heightmap.width() {
+                let expected = if distance_from_center(x, z) < 5.0 { 2.0 } else { heightmap.get(x, z) };
+                assert_eq!(filled.get(x, z), expected, "at ({}, {})", x, z);
+            }
+        }
+    }
+
+    #[test]
+    fn priority_flood_slopes_filled_areas_toward_their_outlet() {
+        let filled = priority_flood(&crater(), 0.001);
+        // Every filled point has a neighbor strictly lower, so flow routing never gets stuck on a flat.
+        for z in 1..(filled.depth() - 1) {
+            for x in 1..(filled.width() - 1) {
+                if distance_from_center(x, z) < 5.0 {
+                    let lowest = NEIGHBOR_OFFSETS
+                        .iter()
+                        .filter_map(|&offset| neighbor(&filled, x, z, offset))
+                        .map(|(nx, nz)| filled.get(nx, nz))
+                        .fold(::std::f32::INFINITY, f32::min);
+                    assert!(lowest < filled.get(x, z), "({}, {}) is at {} with no lower neighbor", x, z,
+                            filled.get(x, z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detect_lakes_finds_the_crater_at_its_spill_height() {
+        let lakes = detect_lakes(&crater(), &Default::default());
+        assert_eq!(lakes.len(), 1);
+        let lake = &lakes[0];
+        assert_eq!(lake.level, 2.0);
+        assert!((lake.max_depth - 1.8).abs() < 1e-6);
+        let mut expected = vec![];
+        for z in 0..21 {
+            for x in 0..21 {
+                if distance_from_center(x, z) < 5.0 {
+                    expected.push((x, z));
+                }
+            }
+        }
+        let mut cells = lake.cells.clone();
+        cells.sort_by_key(|&(x, z)| (z, x));
+        assert_eq!(cells, expected);
+    }
+}
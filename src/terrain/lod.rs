@@ -0,0 +1,126 @@
+use gl::types::*;
+use glm;
+use wavefront_obj::mtl;
+
+use objects::MeshData;
+use terrain::Heightmap;
+use terrain::mesh;
+
+// Geomipmapping: one vertex buffer per patch at full resolution, with an index buffer that holds one triangulation per
+// level of detail (each skipping twice as many vertices as the last) laid end to end. Patches at different levels don't
+// share all of their edge vertices, so every edge also gets a skirt hanging below it that hides the cracks.
+pub struct LodMesh {
+    pub data: MeshData,
+    // (first index, index count) into `data.indices` for each level, finest first.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+// Colors for visualizing which level each patch is drawn at, finest first.
+pub static LOD_DEBUG_COLORS: [(f32, f32, f32); 6] = [
+    (0.1, 0.8, 0.1),
+    (0.9, 0.9, 0.1),
+    (1.0, 0.5, 0.0),
+    (0.9, 0.1, 0.1),
+    (0.8, 0.1, 0.8),
+    (0.2, 0.2, 0.9),
+];
+
+pub fn lod_debug_color(level: usize) -> (f32, f32, f32) {
+    LOD_DEBUG_COLORS[level.min(LOD_DEBUG_COLORS.len() - 1)]
+}
+
+// Pushes two triangles for the quad in both windings, since skirts have to be visible from either side.
+fn push_double_sided_quad(indices: &mut Vec<GLuint>, top_a: GLuint, top_b: GLuint, bottom_a: GLuint, bottom_b: GLuint) {
+    indices.extend_from_slice(&[top_a, bottom_a, top_b, top_b, bottom_a, bottom_b]);
+    indices.extend_from_slice(&[top_a, top_b, bottom_a, top_b, bottom_b, bottom_a]);
+}
+
+// `heightmap` minus its `border` must be (2^n + 1) vertices on a side, and `levels` can be at most n + 1.
+pub fn build_lod_mesh(
+    heightmap: &Heightmap,
+    border: usize,
+    levels: usize,
+    skirt_depth: f32,
+    material: &mtl::Material
+) -> LodMesh {
+    let mut data = mesh::build_mesh_data_inset(heightmap, border, material);
+    let size = heightmap.width() - 2 * border;
+    let cells = size - 1;
+    assert_eq!(size, heightmap.depth() - 2 * border, "LOD patches must be square");
+    assert!(cells.is_power_of_two(), "LOD patches must be 2^n + 1 vertices on a side, got {}", size);
+    assert!(levels >= 1 && (1 << (levels - 1)) <= cells, "can't fit {} levels of detail into {} cells", levels, cells);
+
+    // Each edge as a list of grid vertex indices. Its skirt vertices are appended in the same order.
+    let edges: Vec<Vec<usize>> = vec![
+        (0..size).collect(),
+        (0..size).map(|x| cells * size + x).collect(),
+        (0..size).map(|z| z * size).collect(),
+        (0..size).map(|z| z * size + cells).collect(),
+    ];
+    let mut skirt_starts = vec![];
+    for edge in edges.iter() {
+        skirt_starts.push(data.positions.len());
+        for &i in edge.iter() {
+            let position = data.positions[i];
+            let normal = data.normals[i];
+            let uv = data.uvs[i];
+            data.positions.push(position - glm::vec3(0.0, skirt_depth, 0.0));
+            data.normals.push(normal);
+            data.uvs.push(uv);
+        }
+    }
+    data.set_material(material);
+
+    let mut indices = vec![];
+    let mut ranges = vec![];
+    for level in 0..levels {
+        let step = 1 << level;
+        let first = indices.len();
+
+        let mut z = 0;
+        while z < cells {
+            let mut x = 0;
+            while x < cells {
+                let top_left = (z * size + x) as GLuint;
+                let top_right = top_left + step as GLuint;
+                let bottom_left = top_left + (step * size) as GLuint;
+                let bottom_right = bottom_left + step as GLuint;
+                indices.extend_from_slice(&[top_left, bottom_left, top_right, top_right, bottom_left, bottom_right]);
+                x += step;
+            }
+            z += step;
+        }
+
+        for (edge, &skirt_start) in edges.iter().zip(skirt_starts.iter()) {
+            let mut i = 0;
+            while i < cells {
+                push_double_sided_quad(
+                    &mut indices,
+                    edge[i] as GLuint,
+                    edge[i + step] as GLuint,
+                    (skirt_start + i) as GLuint,
+                    (skirt_start + i + step) as GLuint);
+                i += step;
+            }
+        }
+
+        ranges.push((first, indices.len() - first));
+    }
+    data.indices = indices;
+
+    LodMesh {
+        data: data,
+        ranges: ranges,
+    }
+}
+
+// Level 0 within `base_distance`, then one coarser level every time the distance doubles.
+pub fn select_level(distance: f32, base_distance: f32, levels: usize) -> usize {
+    let mut level = 0;
+    let mut threshold = base_distance;
+    while level + 1 < levels && distance > threshold {
+        level += 1;
+        threshold *= 2.0;
+    }
+    level
+}
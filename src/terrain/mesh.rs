@@ -0,0 +1,69 @@
+use gl::types::*;
+use glm;
+use wavefront_obj::mtl;
+
+use objects::MeshData;
+use terrain::Heightmap;
+
+// Central differences where we can, one-sided differences along the edges.
+pub fn vertex_normal(heightmap: &Heightmap, x: usize, z: usize) -> glm::Vec3 {
+    let x0 = if x > 0 { x - 1 } else { x };
+    let x1 = (x + 1).min(heightmap.width() - 1);
+    let z0 = if z > 0 { z - 1 } else { z };
+    let z1 = (z + 1).min(heightmap.depth() - 1);
+
+    let slope_x = (heightmap.get(x1, z) - heightmap.get(x0, z)) / ((x1 - x0) as f32 * heightmap.spacing());
+    let slope_z = (heightmap.get(x, z1) - heightmap.get(x, z0)) / ((z1 - z0) as f32 * heightmap.spacing());
+
+    glm::normalize(glm::vec3(-slope_x, 1.0, -slope_z))
+}
+
+// Two counter-clockwise (seen from above) triangles per cell, matching the winding the OBJ loader produces.
+pub fn grid_indices(width: usize, depth: usize) -> Vec<GLuint> {
+    let mut indices = Vec::with_capacity((width - 1) * (depth - 1) * 6);
+    for z in 0..(depth - 1) {
+        for x in 0..(width - 1) {
+            let top_left = (z * width + x) as GLuint;
+            let top_right = top_left + 1;
+            let bottom_left = top_left + width as GLuint;
+            let bottom_right = bottom_left + 1;
+
+            indices.push(top_left);
+            indices.push(bottom_left);
+            indices.push(top_right);
+
+            indices.push(top_right);
+            indices.push(bottom_left);
+            indices.push(bottom_right);
+        }
+    }
+    indices
+}
+
+pub fn build_mesh_data(heightmap: &Heightmap, material: &mtl::Material) -> MeshData {
+    build_mesh_data_inset(heightmap, 0, material)
+}
+
+// Meshes everything except a `border`-wide ring around the edge, which is only used to get the normals right. Vertex
+// (border, border) ends up at the local origin. Adjacent chunks generated this way light seamlessly.
+pub fn build_mesh_data_inset(heightmap: &Heightmap, border: usize, material: &mtl::Material) -> MeshData {
+    assert!(heightmap.width() > 2 * border + 1 && heightmap.depth() > 2 * border + 1, "border is too wide for the heightmap");
+
+    let width = heightmap.width() - 2 * border;
+    let depth = heightmap.depth() - 2 * border;
+    let spacing = heightmap.spacing();
+
+    let mut positions = Vec::with_capacity(width * depth);
+    let mut normals = Vec::with_capacity(width * depth);
+    let mut uvs = Vec::with_capacity(width * depth);
+
+    for z in 0..depth {
+        for x in 0..width {
+            positions.push(glm::vec3(x as f32 * spacing, heightmap.get(x + border, z + border), z as f32 * spacing));
+            normals.push(vertex_normal(heightmap, x + border, z + border));
+            uvs.push(glm::vec2(x as f32 / (width - 1) as f32, z as f32 / (depth - 1) as f32));
+        }
+    }
+
+    MeshData::with_material(positions, normals, uvs, grid_indices(width, depth), material)
+}
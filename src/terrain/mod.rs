@@ -0,0 +1,473 @@
+use glm;
+use num_traits::identities::One;
+use scoped_threadpool::Pool;
+use wavefront_obj::mtl;
+
+use objects;
+use shaders;
+
+pub mod biomes;
+pub mod chunks;
+pub mod coast;
+pub mod diamond_square;
+pub mod erosion;
+pub mod export;
+pub mod graph;
+pub mod history;
+pub mod hydrology;
+pub mod import;
+pub mod lakes;
+pub mod lod;
+pub mod mesh;
+pub mod noise;
+pub mod obj_export;
+pub mod parallel;
+pub mod query;
+pub mod random;
+pub mod scatter;
+pub mod sculpt;
+pub mod shaping;
+pub mod splat;
+pub mod voxel;
+
+lazy_static! {
+    pub static ref TERRAIN_MATERIAL: mtl::Material = mtl::Material {
+        name: "terrain".to_owned(),
+        specular_coefficient: 0.0,
+        color_ambient: mtl::Color { r: 0.08, g: 0.1, b: 0.06 },
+        color_diffuse: mtl::Color { r: 0.35, g: 0.5, b: 0.25 },
+        color_specular: mtl::Color { r: 0.0, g: 0.0, b: 0.0 },
+        color_emissive: Option::None,
+        optical_density: Option::None,
+        alpha: 1.0,
+        illumination: mtl::Illumination::AmbientDiffuse,
+        uv_map: Option::None,
+    };
+}
+
+// A regular grid of heights. Cell (x, z) sits at (x * spacing, height, z * spacing) in the heightmap's local space,
+// so `width` and `depth` count vertices, not cells.
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    width: usize,
+    depth: usize,
+    spacing: f32,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: usize, depth: usize, spacing: f32) -> Heightmap {
+        Heightmap::from_heights(width, depth, spacing, vec![0.0; width * depth])
+    }
+
+    pub fn from_heights(width: usize, depth: usize, spacing: f32, heights: Vec<f32>) -> Heightmap {
+        assert!(width >= 2 && depth >= 2, "heightmap must be at least 2x2, got {}x{}", width, depth);
+        assert!(spacing > 0.0, "heightmap spacing must be positive, got {}", spacing);
+        assert_eq!(heights.len(), width * depth, "wrong number of heights for a {}x{} heightmap", width, depth);
+
+        Heightmap {
+            width: width,
+            depth: depth,
+            spacing: spacing,
+            heights: heights,
+        }
+    }
+
+    pub fn from_fn<F: Fn(usize, usize) -> f32>(width: usize, depth: usize, spacing: f32, f: F) -> Heightmap {
+        let mut heights = Vec::with_capacity(width * depth);
+        for z in 0..depth {
+            for x in 0..width {
+                heights.push(f(x, z));
+            }
+        }
+        Heightmap::from_heights(width, depth, spacing, heights)
+    }
+
+    // from_fn, with rows split across `threads` threads. The result is the same for any number of them.
+    pub fn from_fn_parallel<F: Fn(usize, usize) -> f32 + Sync>(
+        width: usize,
+        depth: usize,
+        spacing: f32,
+        threads: usize,
+        f: F
+    ) -> Heightmap {
+        assert!(threads > 0, "need at least one thread");
+        let mut heights = vec![0.0; width * depth];
+        parallel::fill_rows(&mut Pool::new(threads as u32), &mut heights, width, f);
+        Heightmap::from_heights(width, depth, spacing, heights)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn spacing(&self) -> f32 {
+        self.spacing
+    }
+
+    // Size of the grid in local units along x and z.
+    pub fn extent(&self) -> (f32, f32) {
+        ((self.width - 1) as f32 * self.spacing, (self.depth - 1) as f32 * self.spacing)
+    }
+
+    pub fn get(&self, x: usize, z: usize) -> f32 {
+        self.heights[self.index(x, z)]
+    }
+
+    pub fn set(&mut self, x: usize, z: usize, height: f32) {
+        let i = self.index(x, z);
+        self.heights[i] = height;
+    }
+
+    // Bilinearly interpolated height at a point in local units, clamped to the edges of the grid.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let gx = (x / self.spacing).max(0.0).min((self.width - 1) as f32);
+        let gz = (z / self.spacing).max(0.0).min((self.depth - 1) as f32);
+        let (x0, z0) = (gx.floor() as usize, gz.floor() as usize);
+        let (x1, z1) = ((x0 + 1).min(self.width - 1), (z0 + 1).min(self.depth - 1));
+        let (fx, fz) = (gx - x0 as f32, gz - z0 as f32);
+        let top = self.get(x0, z0) * (1.0 - fx) + self.get(x1, z0) * fx;
+        let bottom = self.get(x0, z1) * (1.0 - fx) + self.get(x1, z1) * fx;
+        top * (1.0 - fz) + bottom * fz
+    }
+
+    pub fn heights(&self) -> &[f32] {
+        &self.heights
+    }
+
+    pub fn heights_mut(&mut self) -> &mut [f32] {
+        &mut self.heights
+    }
+
+    pub fn range(&self) -> (f32, f32) {
+        self.heights
+            .iter()
+            .fold((::std::f32::INFINITY, ::std::f32::NEG_INFINITY), |(min, max), &h| (min.min(h), max.max(h)))
+    }
+
+    fn index(&self, x: usize, z: usize) -> usize {
+        assert!(x < self.width && z < self.depth, "({}, {}) is outside of {}x{} heightmap", x, z, self.width, self.depth);
+        z * self.width + x
+    }
+}
+
+// Per-vertex material colors, in the same row-major order as the heightmap, for terrain that isn't all one material.
+#[derive(Debug, Clone)]
+pub struct VertexMaterials {
+    pub ambient: Vec<mtl::Color>,
+    pub diffuse: Vec<mtl::Color>,
+    pub specular: Vec<mtl::Color>,
+    pub specular_exponents: Vec<f32>,
+}
+
+impl VertexMaterials {
+    // Everything but the diffuse color comes from TERRAIN_MATERIAL.
+    pub fn from_diffuse_colors(colors: Vec<mtl::Color>) -> VertexMaterials {
+        let count = colors.len();
+        VertexMaterials {
+            ambient: vec![TERRAIN_MATERIAL.color_ambient; count],
+            diffuse: colors,
+            specular: vec![TERRAIN_MATERIAL.color_specular; count],
+            specular_exponents: vec![1.0; count],
+        }
+    }
+
+    fn apply_to(&self, data: &mut objects::MeshData) {
+        data.colors_ambient = self.ambient.clone();
+        data.colors_diffuse = self.diffuse.clone();
+        data.colors_specular = self.specular.clone();
+        data.specular_exponents = self.specular_exponents.clone();
+    }
+}
+
+// An in-place pass over a heightmap, so that generators can be followed by any number of these in sequence.
+pub trait Filter {
+    fn apply(&self, heightmap: &mut Heightmap);
+}
+
+pub struct RenderableTerrain<'a> {
+    heightmap: Heightmap,
+    origin: glm::Vec3,
+    program: &'a shaders::Program,
+    vertex_materials: Option<VertexMaterials>,
+    seed: Option<u64>,
+    splatting: Option<splat::Splatting<'a>>,
+    rivers: Option<hydrology::RiverNetwork>,
+    mesh: Option<objects::LoadedMesh>,
+    // Drawn on top of the terrain, in its local space; uploaded along with it.
+    pending_overlays: Vec<objects::MeshData>,
+    overlays: Vec<objects::LoadedMesh>,
+    // Models placed over the terrain, with instances in its local space.
+    scattered: Vec<objects::RenderableObject<'a>>,
+    // Vertices sculpted since the mesh was last updated.
+    dirty: Option<sculpt::Region>,
+    history: history::History,
+    sea: Option<coast::Sea<'a>>,
+}
+
+impl <'a> RenderableTerrain<'a> {
+    pub fn new(heightmap: Heightmap, origin: glm::Vec3, program: &'a shaders::Program) -> RenderableTerrain<'a> {
+        RenderableTerrain {
+            heightmap: heightmap,
+            origin: origin,
+            program: program,
+            vertex_materials: Option::None,
+            seed: Option::None,
+            splatting: Option::None,
+            rivers: Option::None,
+            mesh: Option::None,
+            pending_overlays: vec![],
+            overlays: vec![],
+            scattered: vec![],
+            dirty: Option::None,
+            history: Default::default(),
+            sea: Option::None,
+        }
+    }
+
+    // Records the seed this terrain was generated from, for exports.
+    pub fn with_seed(mut self, seed: u64) -> RenderableTerrain<'a> {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Overrides the material's diffuse color per vertex, in the same row-major order as the heightmap.
+    pub fn with_diffuse_colors(self, colors: Vec<mtl::Color>) -> RenderableTerrain<'a> {
+        self.with_vertex_materials(VertexMaterials::from_diffuse_colors(colors))
+    }
+
+    // Overrides every color of the material per vertex, e.g. from biomes::BiomeTable::vertex_materials.
+    pub fn with_vertex_materials(mut self, materials: VertexMaterials) -> RenderableTerrain<'a> {
+        assert_eq!(materials.diffuse.len(), self.heightmap.width() * self.heightmap.depth(), "need exactly one color per vertex");
+        self.vertex_materials = Some(materials);
+        self.mesh = Option::None;
+        self
+    }
+
+    // Draws with detail textures blended by slope and height instead of flat per-vertex colors, which are then ignored.
+    pub fn with_splatting(mut self, splatting: splat::Splatting<'a>) -> RenderableTerrain<'a> {
+        self.splatting = Some(splatting);
+        self.mesh = Option::None;
+        self
+    }
+
+    // Extra geometry, like water, drawn with the terrain's program and model matrix.
+    pub fn with_overlay(mut self, overlay: objects::MeshData) -> RenderableTerrain<'a> {
+        self.pending_overlays.push(overlay);
+        self
+    }
+
+    // Draws `object` at every instance, e.g. from scatter::scatter.
+    pub fn with_scattered(
+        mut self,
+        object: objects::RenderableObject<'a>,
+        instances: &[scatter::Instance]
+    ) -> RenderableTerrain<'a> {
+        self.scattered.push(object.with_instances(instances.iter().map(|i| i.transform()).collect()));
+        self
+    }
+
+    // Shows `network` (which should have been carved into this terrain already) as water, and keeps it for exports.
+    pub fn with_rivers(
+        self,
+        network: hydrology::RiverNetwork,
+        settings: &hydrology::RiverSettings
+    ) -> RenderableTerrain<'a> {
+        let water = hydrology::river_mesh(&self.heightmap, &network, settings);
+        let mut terrain = self.with_overlay(water);
+        terrain.rivers = Some(network);
+        terrain
+    }
+
+    // Surrounds the terrain with water at `settings.level`, drawn with `program` (water.vert and phong.frag). Biomes
+    // and scattering should be given the same level, so that nothing grows underwater.
+    pub fn with_sea(mut self, program: &'a shaders::Program, settings: coast::SeaSettings) -> RenderableTerrain<'a> {
+        self.sea = Some(coast::Sea::new(program, &self.heightmap, settings));
+        self
+    }
+
+    // Places the terrain so that its center is at the world origin.
+    pub fn centered(heightmap: Heightmap, program: &'a shaders::Program) -> RenderableTerrain<'a> {
+        let (extent_x, extent_z) = heightmap.extent();
+        RenderableTerrain::new(heightmap, glm::vec3(-extent_x / 2.0, 0.0, -extent_z / 2.0), program)
+    }
+
+    pub fn heightmap(&self) -> &Heightmap {
+        &self.heightmap
+    }
+
+    pub fn origin(&self) -> glm::Vec3 {
+        self.origin
+    }
+
+    pub fn sea_level(&self) -> Option<f32> {
+        self.sea.as_ref().map(|sea| sea.level())
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn rivers(&self) -> Option<&hydrology::RiverNetwork> {
+        self.rivers.as_ref()
+    }
+
+    pub fn diffuse_colors(&self) -> Option<&[mtl::Color]> {
+        self.vertex_materials.as_ref().map(|materials| materials.diffuse.as_slice())
+    }
+
+    // Height of the terrain's surface under a world-space point, clamped to its edges.
+    pub fn height_at(&self, x: f32, z: f32, interpolation: query::Interpolation) -> f32 {
+        self.origin.y + query::height_at(&self.heightmap, x - self.origin.x, z - self.origin.z, interpolation)
+    }
+
+    pub fn normal_at(&self, x: f32, z: f32) -> glm::Vec3 {
+        query::normal_at(&self.heightmap, x - self.origin.x, z - self.origin.z)
+    }
+
+    // Where a world-space ray, e.g. from the camera's position along its direction, first meets the terrain.
+    pub fn raycast(&self, origin: glm::Vec3, direction: glm::Vec3, max_distance: f32) -> Option<query::Hit> {
+        query::raycast(&self.heightmap, origin - self.origin, direction, max_distance).map(|hit| query::Hit {
+            position: hit.position + self.origin,
+            ..hit
+        })
+    }
+
+    fn mark_dirty(&mut self, region: sculpt::Region) {
+        self.dirty = Some(match self.dirty {
+            Some(ref dirty) => dirty.union(&region),
+            None => region,
+        });
+    }
+
+    // Re-sends the sculpted vertices, and the ring around them whose normals they affect, one row at a time. Colors and
+    // splat weights are left as they were.
+    fn update_mesh(&self, region: sculpt::Region) {
+        let mesh = match self.mesh {
+            Some(ref mesh) => mesh,
+            None => return,
+        };
+        let region = region.expanded(1, &self.heightmap);
+        let spacing = self.heightmap.spacing();
+        for z in region.min_z..(region.max_z + 1) {
+            let positions = (region.min_x..(region.max_x + 1))
+                .map(|x| glm::vec3(x as f32 * spacing, self.heightmap.get(x, z), z as f32 * spacing))
+                .collect();
+            let normals = (region.min_x..(region.max_x + 1))
+                .map(|x| mesh::vertex_normal(&self.heightmap, x, z))
+                .collect();
+            mesh.update_vertices(z * self.heightmap.width() + region.min_x, positions, normals);
+        }
+    }
+
+    fn light(&self) -> objects::Light {
+        // The phong shader attenuates by distance squared, so scale the power with the terrain to keep it lit evenly.
+        let (extent_x, extent_z) = self.heightmap.extent();
+        let (_, max_height) = self.heightmap.range();
+        let light_height = max_height + extent_x.max(extent_z);
+        objects::Light {
+            position: self.origin + glm::vec3(extent_x / 2.0, light_height, extent_z / 2.0),
+            color: glm::vec3(1.0, 1.0, 1.0),
+            power: light_height * light_height * 1.5,
+        }
+    }
+}
+
+impl <'a> objects::Renderable for RenderableTerrain<'a> {
+    fn terrain(&self) -> Option<&RenderableTerrain> {
+        Some(self)
+    }
+
+    fn sculpt(&mut self, brush: &sculpt::Brush, at: glm::Vec3, delta_t: f32) {
+        let local = at - self.origin;
+        if let Some(region) = sculpt::brush_region(&self.heightmap, brush, local.x, local.z) {
+            self.history.record(&format!("{:?} stroke", brush.kind), &self.heightmap, region);
+            sculpt::apply_brush(&mut self.heightmap, brush, local.x, local.z, delta_t);
+            self.mark_dirty(region);
+        }
+    }
+
+    fn end_stroke(&mut self) {
+        self.history.commit();
+    }
+
+    fn apply_filter(&mut self, label: &str, filter: &Filter) {
+        let whole = sculpt::Region::whole(&self.heightmap);
+        self.history.commit();
+        self.history.record(label, &self.heightmap, whole);
+        filter.apply(&mut self.heightmap);
+        self.history.commit();
+        self.mark_dirty(whole);
+    }
+
+    fn undo(&mut self) -> Option<String> {
+        let (label, region) = match self.history.undo(&mut self.heightmap) {
+            Some(change) => (change.label().to_string(), change.region()),
+            None => return None,
+        };
+        self.mark_dirty(region);
+        Some(label)
+    }
+
+    fn redo(&mut self) -> Option<String> {
+        let (label, region) = match self.history.redo(&mut self.heightmap) {
+            Some(change) => (change.label().to_string(), change.region()),
+            None => return None,
+        };
+        self.mark_dirty(region);
+        Some(label)
+    }
+
+    fn render(&mut self, view: glm::Mat4, projection: glm::Mat4) {
+        if self.mesh.is_none() {
+            self.mesh = Some(match self.splatting {
+                Some(ref mut splatting) => splatting.upload(&self.heightmap),
+                None => {
+                    let mut data = mesh::build_mesh_data(&self.heightmap, &TERRAIN_MATERIAL);
+                    if let Some(ref materials) = self.vertex_materials {
+                        materials.apply_to(&mut data);
+                    }
+                    objects::upload_mesh(self.program, data, objects::white_texture())
+                },
+            });
+        }
+
+        if let Some(region) = self.dirty.take() {
+            self.update_mesh(region);
+        }
+
+        for overlay in self.pending_overlays.drain(..) {
+            self.overlays.push(objects::upload_mesh(self.program, overlay, objects::white_texture()));
+        }
+
+        let model = glm::ext::translate(&glm::Mat4::one(), self.origin);
+        let light = self.light();
+        let mesh = self.mesh.as_ref().unwrap();
+        match self.splatting {
+            Some(ref splatting) => splatting.draw(mesh, model, view, projection, &light),
+            None => {
+                objects::use_program(self.program, model, view, projection, &light);
+                objects::draw_mesh(self.program, mesh);
+            },
+        }
+
+        if !self.overlays.is_empty() {
+            objects::use_program(self.program, model, view, projection, &light);
+            for overlay in self.overlays.iter() {
+                objects::draw_mesh(self.program, overlay);
+            }
+        }
+
+        for object in self.scattered.iter_mut() {
+            object.draw(model, view, projection, &light);
+        }
+
+        if let Some(ref mut sea) = self.sea {
+            sea.draw(model, view, projection, &light);
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use std::sync::Arc;
+
+use terrain::Heightmap;
+use terrain::random::Rng;
+
+// Everything in here sticks to arithmetic IEEE 754 rounds exactly: floor, addition, multiplication, division and sqrt
+// (for Worley's Euclidean distance). Transcendental functions like sin, cos and exp are left out, since their results
+// differ between platforms, so that a given seed gives bit-identical terrain everywhere.
+pub trait NoiseFn {
+    // Roughly in [-1, 1], sampled in world units along the x/z plane.
+    fn sample(&self, x: f64, z: f64) -> f64;
+}
+
+// Like NoiseFn, but through a volume: for density fields rather than heightfields.
+pub trait NoiseFn3 {
+    fn sample_3d(&self, x: f64, y: f64, z: f64) -> f64;
+}
+
+const GRADIENTS_2D: [(f64, f64); 8] = [
+    (1.0, 1.0), (-1.0, 1.0), (1.0, -1.0), (-1.0, -1.0),
+    (1.0, 0.0), (-1.0, 0.0), (0.0, 1.0), (0.0, -1.0),
+];
+
+// The twelve cube edge directions, with four repeated to make sixteen so a hash can pick one with a mask.
+const GRADIENTS_3D: [(f64, f64, f64); 16] = [
+    (1.0, 1.0, 0.0), (-1.0, 1.0, 0.0), (1.0, -1.0, 0.0), (-1.0, -1.0, 0.0),
+    (1.0, 0.0, 1.0), (-1.0, 0.0, 1.0), (1.0, 0.0, -1.0), (-1.0, 0.0, -1.0),
+    (0.0, 1.0, 1.0), (0.0, -1.0, 1.0), (0.0, 1.0, -1.0), (0.0, -1.0, -1.0),
+    (1.0, 1.0, 0.0), (-1.0, 1.0, 0.0), (0.0, -1.0, 1.0), (0.0, -1.0, -1.0),
+];
+
+// The classic 256-entry permutation, doubled up so lookups never need to wrap.
+#[derive(Clone)]
+struct PermutationTable {
+    values: Vec<u8>,
+}
+
+impl PermutationTable {
+    fn new(seed: u64) -> PermutationTable {
+        let mut permutation: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        Rng::new(seed).shuffle(&mut permutation);
+
+        let mut values = permutation.clone();
+        values.extend(permutation);
+        PermutationTable {
+            values: values,
+        }
+    }
+
+    fn hash(&self, x: i64, z: i64) -> usize {
+        let x = (x & 255) as usize;
+        let z = (z & 255) as usize;
+        self.values[self.values[x] as usize + z] as usize
+    }
+
+    fn gradient(&self, x: i64, z: i64) -> (f64, f64) {
+        GRADIENTS_2D[self.hash(x, z) & 7]
+    }
+
+    fn gradient_3d(&self, x: i64, y: i64, z: i64) -> (f64, f64, f64) {
+        let x = (x & 255) as usize;
+        let y = (y & 255) as usize;
+        let z = (z & 255) as usize;
+        let hash = self.values[self.values[self.values[x] as usize + y] as usize + z] as usize;
+        GRADIENTS_3D[hash & 15]
+    }
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+#[derive(Clone)]
+pub struct Perlin {
+    permutation: PermutationTable,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        Perlin {
+            permutation: PermutationTable::new(seed),
+        }
+    }
+
+    fn corner(&self, cell_x: i64, cell_z: i64, dx: f64, dz: f64) -> f64 {
+        let (gx, gz) = self.permutation.gradient(cell_x, cell_z);
+        gx * dx + gz * dz
+    }
+}
+
+impl NoiseFn for Perlin {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        let floor_x = x.floor();
+        let floor_z = z.floor();
+        let cell_x = floor_x as i64;
+        let cell_z = floor_z as i64;
+        let dx = x - floor_x;
+        let dz = z - floor_z;
+
+        let u = fade(dx);
+        let v = fade(dz);
+
+        let top = lerp(
+            self.corner(cell_x, cell_z, dx, dz),
+            self.corner(cell_x + 1, cell_z, dx - 1.0, dz),
+            u);
+        let bottom = lerp(
+            self.corner(cell_x, cell_z + 1, dx, dz - 1.0),
+            self.corner(cell_x + 1, cell_z + 1, dx - 1.0, dz - 1.0),
+            u);
+
+        lerp(top, bottom, v).max(-1.0).min(1.0)
+    }
+}
+
+// Perlin noise through a volume. Shared by every basis, since there's no 3D simplex here.
+fn perlin_3d(permutation: &PermutationTable, x: f64, y: f64, z: f64) -> f64 {
+    let (floor_x, floor_y, floor_z) = (x.floor(), y.floor(), z.floor());
+    let (cell_x, cell_y, cell_z) = (floor_x as i64, floor_y as i64, floor_z as i64);
+    let (dx, dy, dz) = (x - floor_x, y - floor_y, z - floor_z);
+
+    let corner = |ox: i64, oy: i64, oz: i64| {
+        let (gx, gy, gz) = permutation.gradient_3d(cell_x + ox, cell_y + oy, cell_z + oz);
+        gx * (dx - ox as f64) + gy * (dy - oy as f64) + gz * (dz - oz as f64)
+    };
+    let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+
+    let near = lerp(
+        lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+        lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+        v);
+    let far = lerp(
+        lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+        lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+        v);
+
+    lerp(near, far, w).max(-1.0).min(1.0)
+}
+
+impl NoiseFn3 for Perlin {
+    fn sample_3d(&self, x: f64, y: f64, z: f64) -> f64 {
+        perlin_3d(&self.permutation, x, y, z)
+    }
+}
+
+// (sqrt(3) - 1) / 2 and (3 - sqrt(3)) / 6, written out so they're constants.
+const SKEW_2D: f64 = 0.366_025_403_784_438_6;
+const UNSKEW_2D: f64 = 0.211_324_865_405_187_1;
+
+#[derive(Clone)]
+pub struct Simplex {
+    permutation: PermutationTable,
+}
+
+impl Simplex {
+    pub fn new(seed: u64) -> Simplex {
+        Simplex {
+            permutation: PermutationTable::new(seed),
+        }
+    }
+
+    fn corner(&self, cell_x: i64, cell_z: i64, dx: f64, dz: f64) -> f64 {
+        let t = 0.5 - dx * dx - dz * dz;
+        if t < 0.0 {
+            0.0
+        } else {
+            let (gx, gz) = self.permutation.gradient(cell_x, cell_z);
+            let t2 = t * t;
+            t2 * t2 * (gx * dx + gz * dz)
+        }
+    }
+}
+
+impl NoiseFn for Simplex {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        let skew = (x + z) * SKEW_2D;
+        let floor_x = (x + skew).floor();
+        let floor_z = (z + skew).floor();
+        let cell_x = floor_x as i64;
+        let cell_z = floor_z as i64;
+
+        let unskew = (floor_x + floor_z) * UNSKEW_2D;
+        let dx0 = x - (floor_x - unskew);
+        let dz0 = z - (floor_z - unskew);
+
+        // Which of the two triangles in the skewed cell are we in?
+        let (step_x, step_z) = if dx0 > dz0 { (1, 0) } else { (0, 1) };
+
+        let dx1 = dx0 - step_x as f64 + UNSKEW_2D;
+        let dz1 = dz0 - step_z as f64 + UNSKEW_2D;
+        let dx2 = dx0 - 1.0 + 2.0 * UNSKEW_2D;
+        let dz2 = dz0 - 1.0 + 2.0 * UNSKEW_2D;
+
+        let total =
+            self.corner(cell_x, cell_z, dx0, dz0) +
+            self.corner(cell_x + step_x, cell_z + step_z, dx1, dz1) +
+            self.corner(cell_x + 1, cell_z + 1, dx2, dz2);
+
+        // Scales the result into (approximately) [-1, 1].
+        (70.0 * total).max(-1.0).min(1.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NoiseBasis {
+    Perlin,
+    Simplex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FractalKind {
+    // Plain fractional Brownian motion: rolling hills.
+    Fbm,
+    // Inverted absolute value, squared, with each octave weighted by the last: sharp mountain ridges.
+    Ridged,
+    // Absolute value: puffy, rounded bumps.
+    Billow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FractalSettings {
+    pub seed: u64,
+    pub basis: NoiseBasis,
+    pub kind: FractalKind,
+    pub octaves: u32,
+    // Frequency of the first octave, in cycles per world unit.
+    pub frequency: f64,
+    // Frequency multiplier between octaves.
+    pub lacunarity: f64,
+    // Amplitude multiplier between octaves.
+    pub persistence: f64,
+}
+
+impl Default for FractalSettings {
+    fn default() -> FractalSettings {
+        FractalSettings {
+            seed: 0,
+            basis: NoiseBasis::Simplex,
+            kind: FractalKind::Fbm,
+            octaves: 6,
+            frequency: 0.02,
+            lacunarity: 2.0,
+            persistence: 0.5,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Octave {
+    Perlin(Perlin),
+    Simplex(Simplex),
+}
+
+impl Octave {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        match *self {
+            Octave::Perlin(ref p) => p.sample(x, z),
+            Octave::Simplex(ref s) => s.sample(x, z),
+        }
+    }
+
+    fn sample_3d(&self, x: f64, y: f64, z: f64) -> f64 {
+        match *self {
+            Octave::Perlin(ref p) => perlin_3d(&p.permutation, x, y, z),
+            Octave::Simplex(ref s) => perlin_3d(&s.permutation, x, y, z),
+        }
+    }
+}
+
+const RIDGE_GAIN: f64 = 2.0;
+
+#[derive(Clone)]
+pub struct Fractal {
+    settings: FractalSettings,
+    octaves: Vec<Octave>,
+    amplitude_sum: f64,
+}
+
+impl Fractal {
+    pub fn new(settings: FractalSettings) -> Fractal {
+        assert!(settings.octaves > 0, "fractal noise needs at least one octave");
+
+        // Every octave gets its own permutation so that the octaves don't line up at the origin.
+        let octaves = (0..settings.octaves)
+            .map(|i| {
+                let seed = Rng::derive(settings.seed, i as u64).next_u64();
+                match settings.basis {
+                    NoiseBasis::Perlin => Octave::Perlin(Perlin::new(seed)),
+                    NoiseBasis::Simplex => Octave::Simplex(Simplex::new(seed)),
+                }
+            })
+            .collect();
+
+        let mut amplitude_sum = 0.0;
+        let mut amplitude = 1.0;
+        for _ in 0..settings.octaves {
+            amplitude_sum += amplitude;
+            amplitude *= settings.persistence;
+        }
+
+        Fractal {
+            settings: settings,
+            octaves: octaves,
+            amplitude_sum: amplitude_sum,
+        }
+    }
+
+    pub fn settings(&self) -> &FractalSettings {
+        &self.settings
+    }
+
+    // Sums the octaves, each sampled by `sample` at its own frequency.
+    fn accumulate<F: Fn(&Octave, f64) -> f64>(&self, sample: F) -> f64 {
+        let mut total = 0.0;
+        let mut frequency = self.settings.frequency;
+        let mut amplitude = 1.0;
+        let mut ridge_weight = 1.0;
+
+        for octave in &self.octaves {
+            let n = sample(octave, frequency);
+            total += amplitude * match self.settings.kind {
+                FractalKind::Fbm => n,
+                FractalKind::Billow => 2.0 * n.abs() - 1.0,
+                FractalKind::Ridged => {
+                    let mut signal = 1.0 - n.abs();
+                    signal *= signal;
+                    signal *= ridge_weight;
+                    ridge_weight = (signal * RIDGE_GAIN).max(0.0).min(1.0);
+                    // Remap [0, 1] into [-1, 1] so all the kinds share a range.
+                    2.0 * signal - 1.0
+                },
+            };
+            frequency *= self.settings.lacunarity;
+            amplitude *= self.settings.persistence;
+        }
+
+        total / self.amplitude_sum
+    }
+}
+
+impl NoiseFn for Fractal {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        self.accumulate(|octave, frequency| octave.sample(x * frequency, z * frequency))
+    }
+}
+
+// The octaves are always Perlin in 3D; the basis only applies to 2D sampling.
+impl NoiseFn3 for Fractal {
+    fn sample_3d(&self, x: f64, y: f64, z: f64) -> f64 {
+        self.accumulate(|octave, frequency| octave.sample_3d(x * frequency, y * frequency, z * frequency))
+    }
+}
+
+impl <N: NoiseFn + ?Sized> NoiseFn for Box<N> {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        (**self).sample(x, z)
+    }
+}
+
+impl <N: NoiseFn + ?Sized> NoiseFn for Arc<N> {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        (**self).sample(x, z)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DistanceMetric {
+    // Round cells.
+    Euclidean,
+    // Diamond-shaped cells.
+    Manhattan,
+    // Square cells.
+    Chebyshev,
+}
+
+impl DistanceMetric {
+    fn distance(&self, dx: f64, dz: f64) -> f64 {
+        match *self {
+            DistanceMetric::Euclidean => (dx * dx + dz * dz).sqrt(),
+            DistanceMetric::Manhattan => dx.abs() + dz.abs(),
+            DistanceMetric::Chebyshev => dx.abs().max(dz.abs()),
+        }
+    }
+
+    // Roughly the largest F2 distance that comes up with full jitter, for scaling into [-1, 1].
+    fn typical_max(&self) -> f64 {
+        match *self {
+            DistanceMetric::Euclidean => 1.2,
+            DistanceMetric::Manhattan => 1.6,
+            DistanceMetric::Chebyshev => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WorleyKind {
+    // Distance to the nearest feature point: rounded pits, or mesas when inverted and clamped.
+    F1,
+    // Distance to the second nearest: lumpy, faceted cells.
+    F2,
+    // Their difference, which is zero along cell borders: cracked mud and ridges.
+    F2MinusF1,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorleySettings {
+    pub seed: u64,
+    // Cells per world unit.
+    pub frequency: f64,
+    pub metric: DistanceMetric,
+    pub kind: WorleyKind,
+    // How far feature points can stray from a regular grid, from 0 (not at all) to 1 (anywhere in their cell).
+    pub jitter: f64,
+}
+
+impl Default for WorleySettings {
+    fn default() -> WorleySettings {
+        WorleySettings {
+            seed: 0,
+            frequency: 0.05,
+            metric: DistanceMetric::Euclidean,
+            kind: WorleyKind::F1,
+            jitter: 1.0,
+        }
+    }
+}
+
+// Cellular noise: one feature point scattered in every unit cell, valued by the distance to the nearest ones.
+#[derive(Clone)]
+pub struct Worley {
+    settings: WorleySettings,
+}
+
+impl Worley {
+    pub fn new(settings: WorleySettings) -> Worley {
+        assert!(settings.jitter >= 0.0 && settings.jitter <= 1.0,
+            "Worley jitter must be in [0, 1], got {}", settings.jitter);
+        Worley {
+            settings: settings,
+        }
+    }
+
+    pub fn settings(&self) -> &WorleySettings {
+        &self.settings
+    }
+
+    fn feature_point(&self, cell_x: i64, cell_z: i64) -> (f64, f64) {
+        // Hashing the cell rather than storing points keeps this infinite and lets any thread sample it.
+        let stream = (cell_x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^
+            (cell_z as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        let mut rng = Rng::derive(self.settings.seed, stream);
+        let jitter = self.settings.jitter;
+        (
+            cell_x as f64 + 0.5 + jitter * (rng.next_f64() - 0.5),
+            cell_z as f64 + 0.5 + jitter * (rng.next_f64() - 0.5),
+        )
+    }
+}
+
+impl NoiseFn for Worley {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        let x = x * self.settings.frequency;
+        let z = z * self.settings.frequency;
+        let cell_x = x.floor() as i64;
+        let cell_z = z.floor() as i64;
+
+        // Points can't stray out of their own cell, so the point in this cell and the one in whichever edge neighbor
+        // is better placed are both within 2 by any metric, and any point outside the surrounding 5x5 is at least 2
+        // away. A 3x3 search isn't enough: with enough jitter, even the nearest point can be two cells over.
+        let mut f1 = ::std::f64::INFINITY;
+        let mut f2 = ::std::f64::INFINITY;
+        for dz in -2..3 {
+            for dx in -2..3 {
+                let (px, pz) = self.feature_point(cell_x + dx, cell_z + dz);
+                let d = self.settings.metric.distance(px - x, pz - z);
+                if d < f1 {
+                    f2 = f1;
+                    f1 = d;
+                } else if d < f2 {
+                    f2 = d;
+                }
+            }
+        }
+
+        let value = match self.settings.kind {
+            WorleyKind::F1 => f1,
+            WorleyKind::F2 => f2,
+            WorleyKind::F2MinusF1 => f2 - f1,
+        };
+        (2.0 * value / self.settings.metric.typical_max() - 1.0).max(-1.0).min(1.0)
+    }
+}
+
+// Samples `source` at coordinates pushed around by `warp`, which turns straight features into swirls. The x and z
+// offsets come from `warp` sampled at two far-apart places, so they're independent.
+#[derive(Clone)]
+pub struct DomainWarp<S, W> {
+    pub source: S,
+    pub warp: W,
+    // Largest offset in world units.
+    pub strength: f64,
+}
+
+const WARP_DECORRELATION_OFFSET: (f64, f64) = (1013.7, 727.3);
+
+impl <S: NoiseFn, W: NoiseFn> DomainWarp<S, W> {
+    pub fn new(source: S, warp: W, strength: f64) -> DomainWarp<S, W> {
+        DomainWarp {
+            source: source,
+            warp: warp,
+            strength: strength,
+        }
+    }
+}
+
+impl <S: NoiseFn, W: NoiseFn> NoiseFn for DomainWarp<S, W> {
+    fn sample(&self, x: f64, z: f64) -> f64 {
+        let offset_x = self.warp.sample(x, z);
+        let offset_z = self.warp.sample(x + WARP_DECORRELATION_OFFSET.0, z + WARP_DECORRELATION_OFFSET.1);
+        self.source.sample(x + self.strength * offset_x, z + self.strength * offset_z)
+    }
+}
+
+fn sample_vertex<N: NoiseFn + ?Sized>(noise: &N, x: usize, z: usize, spacing: f32, origin: (f64, f64)) -> f64 {
+    noise.sample(origin.0 + x as f64 * spacing as f64, origin.1 + z as f64 * spacing as f64)
+}
+
+// Samples `noise` at every vertex, with vertex (0, 0) at `origin` in world units.
+pub fn heightmap_from_noise<N: NoiseFn + ?Sized>(
+    noise: &N,
+    width: usize,
+    depth: usize,
+    spacing: f32,
+    origin: (f64, f64),
+    amplitude: f32
+) -> Heightmap {
+    Heightmap::from_fn(width, depth, spacing, |x, z| sample_vertex(noise, x, z, spacing, origin) as f32 * amplitude)
+}
+
+// heightmap_from_noise, with rows split across `threads` threads.
+pub fn heightmap_from_noise_parallel<N: NoiseFn + Sync + ?Sized>(
+    noise: &N,
+    width: usize,
+    depth: usize,
+    spacing: f32,
+    origin: (f64, f64),
+    amplitude: f32,
+    threads: usize
+) -> Heightmap {
+    Heightmap::from_fn_parallel(width, depth, spacing, threads, |x, z| {
+        sample_vertex(noise, x, z, spacing, origin) as f32 * amplitude
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_heightmaps_are_the_same_on_any_number_of_threads() {
+        let noise = Fractal::new(FractalSettings {
+            seed: 5,
+            frequency: 0.1,
+            ..Default::default()
+        });
+        // An odd number of rows, so they don't split evenly between threads.
+        let bits = |heightmap: Heightmap| heightmap.heights().iter().map(|h| h.to_bits()).collect::<Vec<u32>>();
+        let serial = bits(heightmap_from_noise(&noise, 33, 13, 0.5, (3.0, -7.0), 10.0));
+        for &threads in [1, 2, 3, 7].iter() {
+            let parallel = bits(heightmap_from_noise_parallel(&noise, 33, 13, 0.5, (3.0, -7.0), 10.0, threads));
+            assert_eq!(parallel, serial, "{} threads", threads);
+        }
+    }
+
+    // Stored from an earlier run, so that any change to the hashing, the permutation shuffle or how octave seeds are
+    // derived shows up here rather than as different terrain for a seed that's already been shared.
+    #[test]
+    fn fractals_match_stored_values() {
+        let expected = [
+            (NoiseBasis::Perlin, FractalKind::Fbm, [0xbfc9cfc63cafe201, 0x3fcf457e49a49249, 0xbf932842342f0e9f]),
+            (NoiseBasis::Perlin, FractalKind::Ridged, [0x3fc233dd77f6cfd1, 0xbfe0bdf6d8663d8e, 0x3fedc095057dfd90]),
+            (NoiseBasis::Perlin, FractalKind::Billow, [0xbfe03f2536d05fd6, 0xbfc8d27944145145, 0xbfeec453e97e7dac]),
+            (NoiseBasis::Simplex, FractalKind::Fbm, [0x3fb7e907f2d97019, 0x3fd5a65f9ccc5a79, 0xbfa0e07bc8ae3fd1]),
+            (NoiseBasis::Simplex, FractalKind::Ridged, [0x3fe20723ed5df891, 0xbfd57c6cdac7acb6, 0x3fe9f93fd01824bb]),
+            (NoiseBasis::Simplex, FractalKind::Billow, [0xbfe862615b3343ed, 0xbfd49b4bbe87e8d9, 0xbfec9ef123410ffd]),
+        ];
+        let points = [(3.7, -12.2), (101.25, 47.5), (-0.5, 0.0)];
+        for &(basis, kind, bits) in expected.iter() {
+            let noise = Fractal::new(FractalSettings {
+                seed: 1234,
+                basis: basis,
+                kind: kind,
+                frequency: 0.05,
+                ..Default::default()
+            });
+            for (&(x, z), &expected) in points.iter().zip(bits.iter()) {
+                let actual = noise.sample(x, z);
+                assert_eq!(actual.to_bits(), expected, "{:?} {:?} at ({}, {}): got {}, expected {}", basis, kind, x, z,
+                           actual, f64::from_bits(expected));
+            }
+        }
+    }
+
+    // The nearest and second nearest feature points to (x, z) by checking a much wider area than Worley::sample does.
+    fn brute_force_f1_f2(worley: &Worley, x: f64, z: f64) -> (f64, f64) {
+        let mut distances = vec![];
+        for dz in -4..5 {
+            for dx in -4..5 {
+                let (px, pz) = worley.feature_point(x.floor() as i64 + dx, z.floor() as i64 + dz);
+                distances.push(worley.settings.metric.distance(px - x, pz - z));
+            }
+        }
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        (distances[0], distances[1])
+    }
+
+    #[test]
+    fn worley_finds_the_nearest_points() {
+        let metrics = [DistanceMetric::Euclidean, DistanceMetric::Manhattan, DistanceMetric::Chebyshev];
+        let kinds = [WorleyKind::F1, WorleyKind::F2, WorleyKind::F2MinusF1];
+        for &metric in metrics.iter() {
+            for &kind in kinds.iter() {
+                let worley = Worley::new(WorleySettings {
+                    seed: 11,
+                    frequency: 1.0,
+                    metric: metric,
+                    kind: kind,
+                    jitter: 1.0,
+                });
+                for i in 0..2000 {
+                    let (x, z) = ((i % 50) as f64 * 0.173 - 4.0, (i / 50) as f64 * 0.191 - 4.0);
+                    let (f1, f2) = brute_force_f1_f2(&worley, x, z);
+                    let value = match kind {
+                        WorleyKind::F1 => f1,
+                        WorleyKind::F2 => f2,
+                        WorleyKind::F2MinusF1 => f2 - f1,
+                    };
+                    let expected = (2.0 * value / metric.typical_max() - 1.0).max(-1.0).min(1.0);
+                    assert_eq!(worley.sample(x, z), expected, "{:?} {:?} at ({}, {})", metric, kind, x, z);
+                }
+            }
+        }
+    }
+}
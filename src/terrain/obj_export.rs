@@ -0,0 +1,156 @@
+use std::{ fs, io, path };
+use std::io::Write;
+use image;
+use wavefront_obj::mtl;
+
+use objects::MeshData;
+use terrain::{ Heightmap, TERRAIN_MATERIAL };
+use terrain::export::ExportError;
+use terrain::mesh;
+
+fn color_byte(value: f64) -> u8 {
+    (value.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+// One texel per vertex, in the same row-major order as the heightmap.
+fn write_diffuse_texture(width: usize, depth: usize, colors: &[mtl::Color], path: &path::Path) -> Result<(), ExportError> {
+    let mut bytes = Vec::with_capacity(colors.len() * 3);
+    for color in colors {
+        bytes.push(color_byte(color.r));
+        bytes.push(color_byte(color.g));
+        bytes.push(color_byte(color.b));
+    }
+
+    let file = io::BufWriter::new(fs::File::create(path)?);
+    image::png::PNGEncoder::new(file).encode(&bytes, width as u32, depth as u32, image::ColorType::RGB(8))?;
+    Ok(())
+}
+
+// The diffuse color lives in the texture, so Kd is white and Ka is however much of the diffuse color the material
+// wanted as ambient.
+fn write_material(material: &mtl::Material, texture_name: &str, path: &path::Path) -> Result<(), ExportError> {
+    let ratio = |ambient: f64, diffuse: f64| if diffuse > 0.0 { (ambient / diffuse).min(1.0) } else { 0.0 };
+    let ambient = mtl::Color {
+        r: ratio(material.color_ambient.r, material.color_diffuse.r),
+        g: ratio(material.color_ambient.g, material.color_diffuse.g),
+        b: ratio(material.color_ambient.b, material.color_diffuse.b),
+    };
+    let specular = &material.color_specular;
+    let illumination = match material.illumination {
+        mtl::Illumination::Ambient => 0,
+        mtl::Illumination::AmbientDiffuse => 1,
+        mtl::Illumination::AmbientDiffuseSpecular => 2,
+    };
+
+    // wavefront_obj wants these statements in exactly this order.
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    writeln!(file, "newmtl {}", material.name)?;
+    // phong.frag raises the specular term to this power even when Ks is black, and pow(0, 0) is undefined in GLSL.
+    writeln!(file, "Ns {:.4}", material.specular_coefficient.max(1.0))?;
+    writeln!(file, "Ka {:.4} {:.4} {:.4}", ambient.r, ambient.g, ambient.b)?;
+    writeln!(file, "Kd 1.0000 1.0000 1.0000")?;
+    writeln!(file, "Ks {:.4} {:.4} {:.4}", specular.r, specular.g, specular.b)?;
+    writeln!(file, "d {:.1}", material.alpha)?;
+    writeln!(file, "illum {}", illumination)?;
+    writeln!(file, "map_Kd ./{}", texture_name)?;
+    Ok(())
+}
+
+fn write_mesh(data: &MeshData, material_library: &str, material_name: &str, path: &path::Path) -> Result<(), ExportError> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    writeln!(file, "# {} vertices, {} triangles", data.positions.len(), data.indices.len() / 3)?;
+    writeln!(file, "mtllib {}", material_library)?;
+    writeln!(file, "o terrain")?;
+    for p in data.positions.iter() {
+        writeln!(file, "v {:.6} {:.6} {:.6}", p.x, p.y, p.z)?;
+    }
+    // The OBJ loader looks texture coordinates up by vertex index, so there's exactly one of these per vertex. OBJ
+    // counts v up from the bottom of the image, and textures are uploaded top row first, so v is flipped.
+    for uv in data.uvs.iter() {
+        writeln!(file, "vt {:.6} {:.6}", uv.x, 1.0 - uv.y)?;
+    }
+    for n in data.normals.iter() {
+        writeln!(file, "vn {:.6} {:.6} {:.6}", n.x, n.y, n.z)?;
+    }
+    writeln!(file, "usemtl {}", material_name)?;
+    writeln!(file, "s off")?;
+    for triangle in data.indices.chunks(3) {
+        // OBJ indices are 1-based.
+        let (a, b, c) = (triangle[0] + 1, triangle[1] + 1, triangle[2] + 1);
+        writeln!(file, "f {}/{}/{} {}/{}/{} {}/{}/{}", a, a, a, b, b, b, c, c, c)?;
+    }
+    Ok(())
+}
+
+// Writes <stem>.obj, <stem>.mtl and the diffuse texture <stem>-diffuse.png, which load back through
+// objects::RenderableObject. Vertex (0, 0) is at the origin. `diffuse_colors` overrides the terrain material's diffuse
+// color per vertex, like RenderableTerrain::with_diffuse_colors.
+pub fn export_obj(
+    heightmap: &Heightmap,
+    diffuse_colors: Option<&[mtl::Color]>,
+    directory: &path::Path,
+    stem: &str
+) -> Result<(), ExportError> {
+    fs::create_dir_all(directory)?;
+
+    let mut data = mesh::build_mesh_data(heightmap, &TERRAIN_MATERIAL);
+    if let Some(colors) = diffuse_colors {
+        data.colors_diffuse = colors.to_vec();
+    }
+
+    // Texel centers rather than texture edges, so that every vertex gets exactly its own color back. Like the rest of
+    // the renderer, v counts image rows from the top here; write_mesh flips it to count from the bottom, as OBJ does.
+    let (width, depth) = (heightmap.width(), heightmap.depth());
+    for z in 0..depth {
+        for x in 0..width {
+            data.uvs[z * width + x].x = (x as f32 + 0.5) / width as f32;
+            data.uvs[z * width + x].y = (z as f32 + 0.5) / depth as f32;
+        }
+    }
+
+    let texture_name = format!("{}-diffuse.png", stem);
+    let material_library = format!("{}.mtl", stem);
+    write_diffuse_texture(width, depth, &data.colors_diffuse, &directory.join(&texture_name))?;
+    write_material(&TERRAIN_MATERIAL, &texture_name, &directory.join(&material_library))?;
+    write_mesh(&data, &material_library, &TERRAIN_MATERIAL.name, &directory.join(format!("{}.obj", stem)))?;
+
+    info!("exported {}x{} terrain mesh to {:?}", width, depth, directory.join(format!("{}.obj", stem)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    #[test]
+    fn material_loads_back_as_it_was_written() {
+        let directory = env::temp_dir().join("terrain-obj-export-test");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("terrain.mtl");
+        write_material(&TERRAIN_MATERIAL, "terrain-diffuse.png", &path).unwrap();
+
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let materials = mtl::parse(contents).unwrap().materials;
+        assert_eq!(materials.len(), 1);
+        let loaded = &materials[0];
+
+        assert_eq!(loaded.name, TERRAIN_MATERIAL.name);
+        assert_eq!(loaded.illumination, TERRAIN_MATERIAL.illumination);
+        assert!(loaded.specular_coefficient >= 1.0);
+        // The texture holds the diffuse color, so white Kd and the written Ka scale it back to the source's colors.
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-3;
+        let (source, diffuse) = (&*TERRAIN_MATERIAL, TERRAIN_MATERIAL.color_diffuse);
+        assert!(close(loaded.color_diffuse.r, 1.0));
+        assert!(close(loaded.color_diffuse.g, 1.0));
+        assert!(close(loaded.color_diffuse.b, 1.0));
+        assert!(close(loaded.color_ambient.r * diffuse.r, source.color_ambient.r));
+        assert!(close(loaded.color_ambient.g * diffuse.g, source.color_ambient.g));
+        assert!(close(loaded.color_ambient.b * diffuse.b, source.color_ambient.b));
+        assert!(close(loaded.color_specular.r, source.color_specular.r));
+        assert!(close(loaded.color_specular.g, source.color_specular.g));
+        assert!(close(loaded.color_specular.b, source.color_specular.b));
+    }
+}
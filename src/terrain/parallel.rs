@@ -0,0 +1,39 @@
+use num_cpus;
+use scoped_threadpool::Pool;
+
+// Everything that splits work across threads gives exactly the same result for any number of them: each value is
+// computed by the same code from the same inputs, and only which thread does it changes.
+pub fn default_threads() -> usize {
+    num_cpus::get()
+}
+
+// Splits `values`, a row-major grid `width` wide, into one band of whole rows per thread in `pool` and calls
+// `f(first_row, band)` for each band on its own thread.
+pub fn for_each_row_band<T, F>(pool: &mut Pool, values: &mut [T], width: usize, f: F)
+    where T: Send, F: Fn(usize, &mut [T]) + Sync {
+    assert!(width > 0 && values.len() % width == 0, "{} values don't make rows of {}", values.len(), width);
+    let rows = values.len() / width;
+    let bands = pool.thread_count() as usize;
+    let rows_per_band = ((rows + bands - 1) / bands).max(1);
+    if bands == 1 || rows_per_band >= rows {
+        f(0, values);
+        return;
+    }
+
+    let f = &f;
+    pool.scoped(|scope| {
+        for (i, band) in values.chunks_mut(rows_per_band * width).enumerate() {
+            scope.execute(move || f(i * rows_per_band, band));
+        }
+    });
+}
+
+// Like for_each_row_band, but calls `f(x, z)` for every value.
+pub fn fill_rows<T, F>(pool: &mut Pool, values: &mut [T], width: usize, f: F)
+    where T: Send, F: Fn(usize, usize) -> T + Sync {
+    for_each_row_band(pool, values, width, |first_row, band| {
+        for (i, value) in band.iter_mut().enumerate() {
+            *value = f(i % width, first_row + i / width);
+        }
+    });
+}
@@ -0,0 +1,333 @@
+use glm;
+
+use terrain::Heightmap;
+use terrain::mesh;
+
+// Everything here is in the heightmap's local space; RenderableTerrain has world-space versions.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Bilinear,
+    // Catmull-Rom through the surrounding 4x4 vertices: smooth across cell edges, but can overshoot a little.
+    Bicubic,
+}
+
+fn clamped_get(heightmap: &Heightmap, x: isize, z: isize) -> f32 {
+    let x = x.max(0).min(heightmap.width() as isize - 1) as usize;
+    let z = z.max(0).min(heightmap.depth() as isize - 1) as usize;
+    heightmap.get(x, z)
+}
+
+fn catmull_rom(p0: f32, p1: f32, p2: f32, p3: f32, t: f32) -> f32 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+// Clamped to the edges of the grid, like Heightmap::height_at.
+pub fn height_at(heightmap: &Heightmap, x: f32, z: f32, interpolation: Interpolation) -> f32 {
+    match interpolation {
+        Interpolation::Bilinear => heightmap.height_at(x, z),
+        Interpolation::Bicubic => {
+            let spacing = heightmap.spacing();
+            let gx = (x / spacing).max(0.0).min((heightmap.width() - 1) as f32);
+            let gz = (z / spacing).max(0.0).min((heightmap.depth() - 1) as f32);
+            let (x1, z1) = (gx.floor() as isize, gz.floor() as isize);
+            let (fx, fz) = (gx - x1 as f32, gz - z1 as f32);
+
+            let mut rows = [0.0; 4];
+            for (i, row) in rows.iter_mut().enumerate() {
+                let z = z1 - 1 + i as isize;
+                *row = catmull_rom(
+                    clamped_get(heightmap, x1 - 1, z),
+                    clamped_get(heightmap, x1, z),
+                    clamped_get(heightmap, x1 + 1, z),
+                    clamped_get(heightmap, x1 + 2, z),
+                    fx);
+            }
+            catmull_rom(rows[0], rows[1], rows[2], rows[3], fz)
+        },
+    }
+}
+
+// The vertex normals around the point, blended bilinearly, so that it agrees with how the terrain is lit.
+pub fn normal_at(heightmap: &Heightmap, x: f32, z: f32) -> glm::Vec3 {
+    let spacing = heightmap.spacing();
+    let gx = (x / spacing).max(0.0).min((heightmap.width() - 1) as f32);
+    let gz = (z / spacing).max(0.0).min((heightmap.depth() - 1) as f32);
+    let (x0, z0) = (gx.floor() as usize, gz.floor() as usize);
+    let (x1, z1) = ((x0 + 1).min(heightmap.width() - 1), (z0 + 1).min(heightmap.depth() - 1));
+    let (fx, fz) = (gx - x0 as f32, gz - z0 as f32);
+
+    let top = mesh::vertex_normal(heightmap, x0, z0) * (1.0 - fx) + mesh::vertex_normal(heightmap, x1, z0) * fx;
+    let bottom = mesh::vertex_normal(heightmap, x0, z1) * (1.0 - fx) + mesh::vertex_normal(heightmap, x1, z1) * fx;
+    glm::normalize(top * (1.0 - fz) + bottom * fz)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub position: glm::Vec3,
+    pub normal: glm::Vec3,
+    // Along the ray, which is in units of its (normalized) direction.
+    pub distance: f32,
+}
+
+// Möller-Trumbore. Returns the distance along the ray, if it crosses the triangle from either side.
+fn intersect_triangle(
+    origin: glm::Vec3,
+    direction: glm::Vec3,
+    a: glm::Vec3,
+    b: glm::Vec3,
+    c: glm::Vec3
+) -> Option<f32> {
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = glm::cross(direction, edge2);
+    let determinant = glm::dot(edge1, p);
+    if determinant.abs() < 1e-8 {
+        return None;
+    }
+    let inverse = 1.0 / determinant;
+    let s = origin - a;
+    let u = glm::dot(s, p) * inverse;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+    let q = glm::cross(s, edge1);
+    let v = glm::dot(direction, q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = glm::dot(edge2, q) * inverse;
+    if t >= 0.0 { Some(t) } else { None }
+}
+
+// Where the ray meets one cell's two triangles, split the same way as mesh::grid_indices.
+fn intersect_cell(heightmap: &Heightmap, x: usize, z: usize, origin: glm::Vec3, direction: glm::Vec3) -> Option<f32> {
+    let spacing = heightmap.spacing();
+    let vertex = |vx: usize, vz: usize| glm::vec3(vx as f32 * spacing, heightmap.get(vx, vz), vz as f32 * spacing);
+    let (top_left, top_right) = (vertex(x, z), vertex(x + 1, z));
+    let (bottom_left, bottom_right) = (vertex(x, z + 1), vertex(x + 1, z + 1));
+
+    let first = intersect_triangle(origin, direction, top_left, bottom_left, top_right);
+    let second = intersect_triangle(origin, direction, top_right, bottom_left, bottom_right);
+    match (first, second) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// The ray's entry and exit distances through an axis-aligned box, if it passes through at all.
+fn clip_to_box(origin: glm::Vec3, direction: glm::Vec3, low: glm::Vec3, high: glm::Vec3) -> Option<(f32, f32)> {
+    let mut enter = 0.0f32;
+    let mut exit = ::std::f32::INFINITY;
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            if origin[axis] < low[axis] || origin[axis] > high[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t0 = (low[axis] - origin[axis]) / direction[axis];
+        let t1 = (high[axis] - origin[axis]) / direction[axis];
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
+    }
+    if enter <= exit { Some((enter, exit)) } else { None }
+}
+
+// The first point where the ray meets the terrain's triangles within `max_distance`. Walks the cells the ray passes
+// over in order (Amanatides and Woo), skipping any the ray stays above, so the cost grows with the length of the ray
+// across the terrain rather than with the terrain's size.
+pub fn raycast(heightmap: &Heightmap, origin: glm::Vec3, direction: glm::Vec3, max_distance: f32) -> Option<Hit> {
+    let length = glm::length(direction);
+    assert!(length > 0.0, "can't cast a ray with no direction");
+    let direction = direction / length;
+
+    let spacing = heightmap.spacing();
+    let (extent_x, extent_z) = heightmap.extent();
+    let (min_height, max_height) = heightmap.range();
+    let (enter, exit) = match clip_to_box(
+        origin, direction, glm::vec3(0.0, min_height, 0.0), glm::vec3(extent_x, max_height, extent_z)) {
+        Some((enter, exit)) if enter <= max_distance => (enter, exit.min(max_distance)),
+        _ => return None,
+    };
+
+    let start = origin + direction * enter;
+    let last_x = heightmap.width() as isize - 2;
+    let last_z = heightmap.depth() as isize - 2;
+    let mut cell_x = ((start.x / spacing).floor() as isize).max(0).min(last_x);
+    let mut cell_z = ((start.z / spacing).floor() as isize).max(0).min(last_z);
+
+    // Distance along the ray to the next cell boundary on each axis, and between boundaries.
+    let axis = |position: f32, cell: isize, direction: f32| -> (isize, f32, f32) {
+        if direction > 0.0 {
+            (1, ((cell + 1) as f32 * spacing - position) / direction, spacing / direction)
+        } else if direction < 0.0 {
+            (-1, (cell as f32 * spacing - position) / direction, -spacing / direction)
+        } else {
+            (0, ::std::f32::INFINITY, ::std::f32::INFINITY)
+        }
+    };
+    let (step_x, mut next_x, delta_x) = axis(origin.x, cell_x, direction.x);
+    let (step_z, mut next_z, delta_z) = axis(origin.z, cell_z, direction.z);
+
+    let mut cell_enter = enter;
+    loop {
+        let cell_exit = next_x.min(next_z).min(exit);
+        let (x, z) = (cell_x as usize, cell_z as usize);
+
+        let highest = heightmap.get(x, z)
+            .max(heightmap.get(x + 1, z))
+            .max(heightmap.get(x, z + 1))
+            .max(heightmap.get(x + 1, z + 1));
+        let ray_low = (origin.y + direction.y * cell_enter).min(origin.y + direction.y * cell_exit);
+        if ray_low <= highest {
+            if let Some(t) = intersect_cell(heightmap, x, z, origin, direction) {
+                if t >= enter && t <= exit {
+                    let position = origin + direction * t;
+                    return Some(Hit {
+                        position: position,
+                        normal: normal_at(heightmap, position.x, position.z),
+                        distance: t,
+                    });
+                }
+            }
+        }
+
+        if cell_exit >= exit {
+            return None;
+        }
+        cell_enter = cell_exit;
+        if next_x < next_z {
+            cell_x += step_x;
+            next_x += delta_x;
+        } else {
+            cell_z += step_z;
+            next_z += delta_z;
+        }
+        if cell_x < 0 || cell_z < 0 || cell_x > last_x || cell_z > last_z {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bumpy() -> Heightmap {
+        Heightmap::from_fn(17, 13, 0.5, |x, z| (x as f32 * 0.7).sin() * 2.0 + (z as f32 * 0.45).cos() * 1.5)
+    }
+
+    fn plane() -> Heightmap {
+        Heightmap::from_fn(17, 13, 0.5, |x, z| x as f32 * 0.2 + z as f32 * 0.1 - 1.0)
+    }
+
+    // Every cell, in no particular order, to check raycast's traversal against.
+    fn brute_force(heightmap: &Heightmap, origin: glm::Vec3, direction: glm::Vec3, max_distance: f32) -> Option<f32> {
+        let direction = glm::normalize(direction);
+        let mut nearest: Option<f32> = None;
+        for z in 0..(heightmap.depth() - 1) {
+            for x in 0..(heightmap.width() - 1) {
+                if let Some(t) = intersect_cell(heightmap, x, z, origin, direction) {
+                    if t <= max_distance && nearest.map_or(true, |nearest| t < nearest) {
+                        nearest = Some(t);
+                    }
+                }
+            }
+        }
+        nearest
+    }
+
+    #[test]
+    fn vertical_rays_hit_at_the_surface_height() {
+        // A plane is flat across each cell's triangles, so bilinear interpolation lands on them too.
+        let heightmap = plane();
+        for &(x, z) in [(0.3, 0.2), (2.25, 4.1), (7.9, 5.95), (4.0, 3.0)].iter() {
+            let hit = raycast(&heightmap, glm::vec3(x, 50.0, z), glm::vec3(0.0, -1.0, 0.0), 100.0).unwrap();
+            let expected = height_at(&heightmap, x, z, Interpolation::Bilinear);
+            assert!((hit.position.y - expected).abs() < 1e-4, "hit at {} over ({}, {}), expected {}",
+                    hit.position.y, x, z, expected);
+            assert!((hit.distance - (50.0 - expected)).abs() < 1e-4);
+        }
+
+        // Over bumpy terrain, the triangles only agree with it at the vertices, including those on the far edges.
+        let heightmap = bumpy();
+        for &(x, z) in [(0, 0), (3, 7), (16, 5), (9, 12), (16, 12)].iter() {
+            let (px, pz) = (x as f32 * 0.5, z as f32 * 0.5);
+            let hit = raycast(&heightmap, glm::vec3(px, 50.0, pz), glm::vec3(0.0, -2.0, 0.0), 100.0).unwrap();
+            assert!((hit.position.y - heightmap.get(x, z)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn grazing_rays_find_the_first_hit() {
+        let heightmap = bumpy();
+        let (extent_x, extent_z) = heightmap.extent();
+        // Low, shallow rays from just inside and well outside the terrain, in every direction across it, cross many
+        // cells before they hit anything (if they do).
+        let mut hits = 0;
+        for i in 0..24 {
+            let angle = i as f32 / 24.0 * 2.0 * ::std::f32::consts::PI;
+            let direction = glm::vec3(angle.cos(), -0.12, angle.sin());
+            for &(origin, max_distance) in [
+                (glm::vec3(extent_x / 2.0 - angle.cos() * 3.0, 2.0, extent_z / 2.0 - angle.sin() * 3.0), 100.0),
+                (glm::vec3(extent_x / 2.0 - angle.cos() * 12.0, 3.0, extent_z / 2.0 - angle.sin() * 12.0), 100.0),
+            ].iter() {
+                let expected = brute_force(&heightmap, origin, direction, max_distance);
+                let actual = raycast(&heightmap, origin, direction, max_distance).map(|hit| hit.distance);
+                match (expected, actual) {
+                    (Some(expected), Some(actual)) => {
+                        assert!((expected - actual).abs() < 1e-4, "expected {}, got {}", expected, actual);
+                        hits += 1;
+                    },
+                    (None, None) => {},
+                    _ => panic!("from {:?} along {:?}: expected {:?}, got {:?}", origin, direction, expected, actual),
+                }
+            }
+        }
+        assert!(hits > 24, "only {} rays hit; the test isn't exercising much", hits);
+    }
+
+    #[test]
+    fn rays_that_never_reach_the_terrain_miss() {
+        let heightmap = bumpy();
+        let (extent_x, extent_z) = heightmap.extent();
+        let center = glm::vec3(extent_x / 2.0, 10.0, extent_z / 2.0);
+        // Straight up, from above.
+        assert!(raycast(&heightmap, center, glm::vec3(0.0, 1.0, 0.0), 100.0).is_none());
+        // From outside the box, away from it.
+        assert!(raycast(&heightmap, glm::vec3(-5.0, 0.0, 3.0), glm::vec3(-1.0, -0.1, 0.0), 100.0).is_none());
+        // From outside the box, past it.
+        assert!(raycast(&heightmap, glm::vec3(-5.0, 0.0, -5.0), glm::vec3(-1.0, 0.0, 1.0), 100.0).is_none());
+        // Above the box, level.
+        assert!(raycast(&heightmap, glm::vec3(-5.0, 10.0, 3.0), glm::vec3(1.0, 0.0, 0.0), 100.0).is_none());
+    }
+
+    #[test]
+    fn max_distance_cuts_the_ray_off() {
+        let heightmap = bumpy();
+        let origin = glm::vec3(4.0, 20.0, 3.0);
+        let down = glm::vec3(0.0, -1.0, 0.0);
+        let distance = raycast(&heightmap, origin, down, 100.0).unwrap().distance;
+        assert!(raycast(&heightmap, origin, down, distance - 0.01).is_none());
+        assert_eq!(raycast(&heightmap, origin, down, distance + 0.01).unwrap().distance, distance);
+        // Also when the box is entered beyond the limit.
+        assert!(raycast(&heightmap, glm::vec3(-30.0, 0.0, 3.0), glm::vec3(1.0, 0.0, 0.0), 20.0).is_none());
+        assert!(raycast(&heightmap, glm::vec3(-30.0, 0.0, 3.0), glm::vec3(1.0, 0.0, 0.0), 40.0).is_some());
+    }
+
+    #[test]
+    fn bicubic_heights_pass_through_the_vertices() {
+        let heightmap = bumpy();
+        for z in 0..heightmap.depth() {
+            for x in 0..heightmap.width() {
+                let (px, pz) = (x as f32 * 0.5, z as f32 * 0.5);
+                let h = height_at(&heightmap, px, pz, Interpolation::Bicubic);
+                assert!((h - heightmap.get(x, z)).abs() < 1e-5, "at ({}, {}): {} vs {}", x, z, h, heightmap.get(x, z));
+            }
+        }
+    }
+}
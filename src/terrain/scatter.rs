@@ -0,0 +1,194 @@
+use std::f32::consts::PI;
+use glm;
+use num_traits::identities::One;
+
+use terrain::Heightmap;
+use terrain::mesh;
+use terrain::random::Rng;
+
+#[derive(Debug, Clone)]
+pub struct ScatterSettings {
+    pub seed: u64,
+    // No two instances are closer than this, in local units.
+    pub min_distance: f32,
+    // Candidates tried around each point before giving up on it; Bridson suggests 30.
+    pub max_attempts: u32,
+    // Where instances may go: slope in degrees, and elevation between the lowest (0) and highest (1) point, or with a
+    // sea level, between that and the highest, as for biomes::Climate.
+    pub slope: (f32, f32),
+    pub elevation: (f32, f32),
+    // Nothing goes below this height, when it's set. Should match the terrain's sea, if it has one.
+    pub sea_level: Option<f32>,
+    // Indices into the biome table of the biomes instances may go in. Empty allows all of them.
+    pub biomes: Vec<usize>,
+    // Uniform scale is picked from this range.
+    pub scale: (f32, f32),
+    // Raises (or with a negative value, sinks) every instance, in local units before scaling.
+    pub lift: f32,
+}
+
+impl Default for ScatterSettings {
+    fn default() -> ScatterSettings {
+        ScatterSettings {
+            seed: 0,
+            min_distance: 4.0,
+            max_attempts: 30,
+            slope: (0.0, 90.0),
+            elevation: (0.0, 1.0),
+            sea_level: None,
+            biomes: vec![],
+            scale: (1.0, 1.0),
+            lift: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instance {
+    // In the heightmap's local space, on its surface.
+    pub position: glm::Vec3,
+    // Rotation about the vertical axis, in radians.
+    pub yaw: f32,
+    pub scale: f32,
+}
+
+impl Instance {
+    pub fn transform(&self) -> glm::Mat4 {
+        let translated = glm::ext::translate(&glm::Mat4::one(), self.position);
+        let rotated = glm::ext::rotate(&translated, self.yaw, glm::vec3(0.0, 1.0, 0.0));
+        glm::ext::scale(&rotated, glm::vec3(self.scale, self.scale, self.scale))
+    }
+}
+
+// Bridson's algorithm: points spread evenly over a width x depth rectangle, none closer than `min_distance`, but
+// without the regularity of a grid.
+pub fn poisson_disk(width: f32, depth: f32, min_distance: f32, max_attempts: u32, rng: &mut Rng) -> Vec<(f32, f32)> {
+    assert!(min_distance > 0.0, "poisson disk spacing must be positive, got {}", min_distance);
+
+    // Cells small enough that each can hold at most one point.
+    let cell_size = min_distance / 2.0f32.sqrt();
+    let columns = (width / cell_size).ceil() as usize + 1;
+    let rows = (depth / cell_size).ceil() as usize + 1;
+    let mut grid: Vec<Option<usize>> = vec![None; columns * rows];
+    let cell = |x: f32, z: f32| ((x / cell_size) as usize, (z / cell_size) as usize);
+
+    let mut points = vec![];
+    let mut active = vec![];
+    let first = (rng.next_f32() * width, rng.next_f32() * depth);
+    let (column, row) = cell(first.0, first.1);
+    grid[row * columns + column] = Some(0);
+    points.push(first);
+    active.push(0);
+
+    while !active.is_empty() {
+        let which = rng.below(active.len());
+        let (px, pz) = points[active[which]];
+        let mut placed = false;
+        for _ in 0..max_attempts {
+            // Uniform in the annulus between one and two times the minimum distance.
+            let angle = rng.next_f32() * 2.0 * PI;
+            let radius = min_distance * (1.0 + 3.0 * rng.next_f32()).sqrt();
+            let (x, z) = (px + radius * angle.cos(), pz + radius * angle.sin());
+            if x < 0.0 || z < 0.0 || x > width || z > depth {
+                continue;
+            }
+
+            let (column, row) = cell(x, z);
+            let mut too_close = false;
+            for nz in row.saturating_sub(2)..(row + 3).min(rows) {
+                for nx in column.saturating_sub(2)..(column + 3).min(columns) {
+                    if let Some(other) = grid[nz * columns + nx] {
+                        let (ox, oz) = points[other];
+                        if (ox - x) * (ox - x) + (oz - z) * (oz - z) < min_distance * min_distance {
+                            too_close = true;
+                        }
+                    }
+                }
+            }
+            if !too_close {
+                grid[row * columns + column] = Some(points.len());
+                active.push(points.len());
+                points.push((x, z));
+                placed = true;
+                break;
+            }
+        }
+        if !placed {
+            active.swap_remove(which);
+        }
+    }
+    points
+}
+
+// Poisson-disk points over the heightmap that pass the slope, elevation and biome masks, snapped to its surface.
+// `biomes` holds each vertex's biome, as from biomes::classify, and is only needed if `settings.biomes` isn't empty.
+pub fn scatter(heightmap: &Heightmap, biomes: Option<&[usize]>, settings: &ScatterSettings) -> Vec<Instance> {
+    let (extent_x, extent_z) = heightmap.extent();
+    let points = poisson_disk(
+        extent_x, extent_z, settings.min_distance, settings.max_attempts, &mut Rng::derive(settings.seed, 0));
+    let candidates = points.len();
+    let mut rng = Rng::derive(settings.seed, 1);
+    let (lowest, max) = heightmap.range();
+    let min = settings.sea_level.map_or(lowest, |level| level.min(max));
+
+    let mut instances = vec![];
+    for (x, z) in points {
+        // Drawn before filtering so that changing the masks doesn't reshuffle the instances that remain.
+        let yaw = rng.next_f32() * 2.0 * PI;
+        let scale = settings.scale.0 + (settings.scale.1 - settings.scale.0) * rng.next_f32();
+
+        let spacing = heightmap.spacing();
+        let nearest_x = ((x / spacing).round() as usize).min(heightmap.width() - 1);
+        let nearest_z = ((z / spacing).round() as usize).min(heightmap.depth() - 1);
+
+        let height = heightmap.height_at(x, z);
+        if settings.sea_level.map_or(false, |level| height < level) {
+            continue;
+        }
+        let elevation = if max > min { ((height - min) / (max - min)).max(0.0) } else { 0.0 };
+        if elevation < settings.elevation.0 || elevation > settings.elevation.1 {
+            continue;
+        }
+        let slope = mesh::vertex_normal(heightmap, nearest_x, nearest_z).y.max(-1.0).min(1.0).acos().to_degrees();
+        if slope < settings.slope.0 || slope > settings.slope.1 {
+            continue;
+        }
+        if !settings.biomes.is_empty() {
+            let biomes = biomes.expect("scattering by biome needs the terrain's biomes");
+            if !settings.biomes.contains(&biomes[nearest_z * heightmap.width() + nearest_x]) {
+                continue;
+            }
+        }
+
+        instances.push(Instance {
+            position: glm::vec3(x, height + settings.lift * scale, z),
+            yaw: yaw,
+            scale: scale,
+        });
+    }
+    info!("scattered {} instances from {} candidates", instances.len(), candidates);
+    instances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elevation_is_measured_from_the_sea() {
+        // A ramp from -10 to 10 along x.
+        let heightmap = Heightmap::from_fn(65, 65, 0.5, |x, _| x as f32 * 20.0 / 64.0 - 10.0);
+        let instances = scatter(&heightmap, None, &ScatterSettings {
+            min_distance: 1.0,
+            elevation: (0.0, 0.5),
+            sea_level: Some(0.0),
+            ..Default::default()
+        });
+        assert!(!instances.is_empty());
+        // Halfway from the sea to the top, not from the bottom to the top.
+        for instance in instances.iter() {
+            assert!(instance.position.y >= 0.0 && instance.position.y <= 5.0, "{:?}", instance);
+        }
+        assert!(instances.iter().any(|instance| instance.position.y > 4.0));
+    }
+}
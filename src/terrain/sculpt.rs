@@ -0,0 +1,212 @@
+use terrain::Heightmap;
+use terrain::noise::{ Fractal, FractalSettings, NoiseFn };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrushKind {
+    Raise,
+    Lower,
+    // Pulls every point toward the average of its neighbors.
+    Smooth,
+    // Pulls every point toward the height under the brush's center.
+    Flatten,
+    // Adds fractal noise, the same pattern every time, so that repeated strokes build it up.
+    Noise,
+}
+
+impl BrushKind {
+    // For cycling through the brushes from the keyboard.
+    pub fn next(self) -> BrushKind {
+        match self {
+            BrushKind::Raise => BrushKind::Lower,
+            BrushKind::Lower => BrushKind::Smooth,
+            BrushKind::Smooth => BrushKind::Flatten,
+            BrushKind::Flatten => BrushKind::Noise,
+            BrushKind::Noise => BrushKind::Raise,
+        }
+    }
+}
+
+// How the brush's effect fades from its center (1) to its edge (0).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff {
+    Constant,
+    Linear,
+    Smooth,
+}
+
+impl Falloff {
+    pub fn next(self) -> Falloff {
+        match self {
+            Falloff::Constant => Falloff::Linear,
+            Falloff::Linear => Falloff::Smooth,
+            Falloff::Smooth => Falloff::Constant,
+        }
+    }
+
+    // `distance` is from the center, as a fraction of the radius.
+    fn weight(self, distance: f32) -> f32 {
+        let d = distance.max(0.0).min(1.0);
+        match self {
+            Falloff::Constant => 1.0,
+            Falloff::Linear => 1.0 - d,
+            Falloff::Smooth => 1.0 - d * d * (3.0 - 2.0 * d),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub kind: BrushKind,
+    // In local units.
+    pub radius: f32,
+    // Per second of brushing: height units for raise, lower and noise, and the fraction of the way to their targets
+    // for smooth and flatten.
+    pub strength: f32,
+    pub falloff: Falloff,
+    pub noise: FractalSettings,
+}
+
+impl Default for Brush {
+    fn default() -> Brush {
+        Brush {
+            kind: BrushKind::Raise,
+            radius: 4.0,
+            strength: 3.0,
+            falloff: Falloff::Smooth,
+            noise: FractalSettings {
+                octaves: 4,
+                frequency: 0.2,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+// An inclusive rectangle of vertices.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub min_x: usize,
+    pub min_z: usize,
+    pub max_x: usize,
+    pub max_z: usize,
+}
+
+impl Region {
+    pub fn union(&self, other: &Region) -> Region {
+        Region {
+            min_x: self.min_x.min(other.min_x),
+            min_z: self.min_z.min(other.min_z),
+            max_x: self.max_x.max(other.max_x),
+            max_z: self.max_z.max(other.max_z),
+        }
+    }
+
+    pub fn whole(heightmap: &Heightmap) -> Region {
+        Region {
+            min_x: 0,
+            min_z: 0,
+            max_x: heightmap.width() - 1,
+            max_z: heightmap.depth() - 1,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.max_x - self.min_x + 1
+    }
+
+    pub fn depth(&self) -> usize {
+        self.max_z - self.min_z + 1
+    }
+
+    // Grown by `by` vertices on every side, without leaving the heightmap.
+    pub fn expanded(&self, by: usize, heightmap: &Heightmap) -> Region {
+        Region {
+            min_x: self.min_x.saturating_sub(by),
+            min_z: self.min_z.saturating_sub(by),
+            max_x: (self.max_x + by).min(heightmap.width() - 1),
+            max_z: (self.max_z + by).min(heightmap.depth() - 1),
+        }
+    }
+}
+
+// The vertices a dab of the brush centered on (x, z), in local units, may change, if it touches the heightmap at all.
+pub fn brush_region(heightmap: &Heightmap, brush: &Brush, x: f32, z: f32) -> Option<Region> {
+    let spacing = heightmap.spacing();
+    let max_x = (heightmap.width() - 1) as f32;
+    let max_z = (heightmap.depth() - 1) as f32;
+    let low_x = ((x - brush.radius) / spacing).ceil().max(0.0);
+    let low_z = ((z - brush.radius) / spacing).ceil().max(0.0);
+    let high_x = ((x + brush.radius) / spacing).floor().min(max_x);
+    let high_z = ((z + brush.radius) / spacing).floor().min(max_z);
+    if low_x > high_x || low_z > high_z {
+        return None;
+    }
+    Some(Region {
+        min_x: low_x as usize,
+        min_z: low_z as usize,
+        max_x: high_x as usize,
+        max_z: high_z as usize,
+    })
+}
+
+// One dab of the brush, centered on (x, z) in local units, for `delta_t` seconds. Returns the vertices it may have
+// changed, as brush_region does.
+pub fn apply_brush(heightmap: &mut Heightmap, brush: &Brush, x: f32, z: f32, delta_t: f32) -> Option<Region> {
+    let region = match brush_region(heightmap, brush, x, z) {
+        Some(region) => region,
+        None => return None,
+    };
+    let spacing = heightmap.spacing();
+
+    let amount = brush.strength * delta_t;
+    let target = heightmap.height_at(x, z);
+    let noise = match brush.kind {
+        BrushKind::Noise => Some(Fractal::new(brush.noise.clone())),
+        _ => None,
+    };
+    // Smoothing reads the neighbors as they were before this dab, not as it leaves them, so keep a copy of the region
+    // and the ring of vertices around it.
+    let around = region.expanded(1, heightmap);
+    let around_width = around.max_x - around.min_x + 1;
+    let before: Vec<f32> = match brush.kind {
+        BrushKind::Smooth => (around.min_z..(around.max_z + 1))
+            .flat_map(|vz| (around.min_x..(around.max_x + 1)).map(move |vx| (vx, vz)))
+            .map(|(vx, vz)| heightmap.get(vx, vz))
+            .collect(),
+        _ => vec![],
+    };
+
+    for vz in region.min_z..(region.max_z + 1) {
+        for vx in region.min_x..(region.max_x + 1) {
+            let (px, pz) = (vx as f32 * spacing, vz as f32 * spacing);
+            let distance = ((px - x) * (px - x) + (pz - z) * (pz - z)).sqrt() / brush.radius;
+            if distance > 1.0 {
+                continue;
+            }
+            let weight = brush.falloff.weight(distance);
+            let height = heightmap.get(vx, vz);
+
+            let new_height = match brush.kind {
+                BrushKind::Raise => height + amount * weight,
+                BrushKind::Lower => height - amount * weight,
+                BrushKind::Smooth => {
+                    let (mut total, mut count) = (0.0, 0.0);
+                    for nz in vz.saturating_sub(1).max(around.min_z)..(vz + 2).min(around.max_z + 1) {
+                        for nx in vx.saturating_sub(1).max(around.min_x)..(vx + 2).min(around.max_x + 1) {
+                            total += before[(nz - around.min_z) * around_width + (nx - around.min_x)];
+                            count += 1.0;
+                        }
+                    }
+                    height + (total / count - height) * (amount * weight).min(1.0)
+                },
+                BrushKind::Flatten => height + (target - height) * (amount * weight).min(1.0),
+                BrushKind::Noise => {
+                    let sample = noise.as_ref().unwrap().sample(px as f64, pz as f64) as f32;
+                    height + sample * amount * weight
+                },
+            };
+            heightmap.set(vx, vz, new_height);
+        }
+    }
+    Some(region)
+}
@@ -0,0 +1,292 @@
+use terrain::{ Filter, Heightmap };
+
+// Blended this way rather than as low + t * (high - low), so that 0 and 1 land exactly on `low` and `high`.
+fn lerp(low: f32, high: f32, t: f32) -> f32 {
+    low * (1.0 - t) + high * t
+}
+
+// Runs `f` over every height mapped into [0, 1] between the heightmap's lowest and highest point, and maps the result
+// back, so that shapes don't depend on how tall the terrain happens to be.
+fn remap_normalized<F: Fn(f32) -> f32>(heightmap: &mut Heightmap, f: F) {
+    let (min, max) = heightmap.range();
+    if max <= min {
+        return;
+    }
+    for h in heightmap.heights_mut() {
+        *h = lerp(min, max, f((*h - min) / (max - min)));
+    }
+}
+
+// Flattens the terrain into `steps` plateaus.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Terrace {
+    pub steps: u32,
+    // 0 leaves the terrain alone, 1 gives perfectly flat steps with vertical risers.
+    pub sharpness: f32,
+}
+
+impl Default for Terrace {
+    fn default() -> Terrace {
+        Terrace {
+            steps: 8,
+            sharpness: 0.8,
+        }
+    }
+}
+
+impl Filter for Terrace {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        assert!(self.steps > 0, "terracing needs at least one step");
+        let steps = self.steps as f32;
+        let sharpness = self.sharpness.max(0.0).min(1.0);
+        remap_normalized(heightmap, |t| {
+            let scaled = t * steps;
+            let step = scaled.floor().min(steps - 1.0);
+            let within = scaled - step;
+            // Raise each step's slope to a power, which flattens its bottom and steepens its top.
+            let shaped = within.powf(1.0 + sharpness * 15.0);
+            (step + shaped) / steps
+        });
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Clamp {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Filter for Clamp {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        for h in heightmap.heights_mut() {
+            *h = h.max(self.min).min(self.max);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    Linear,
+    // Monotone cubic (Fritsch-Carlson): smooth through every point, without overshooting between them, so a curve
+    // whose points only ever rise never makes the terrain fold over.
+    Spline,
+}
+
+impl Default for Interpolation {
+    fn default() -> Interpolation {
+        Interpolation::Linear
+    }
+}
+
+// Remaps heights through a curve given as (input, output) points on the same [0, 1] scale as `remap_normalized`,
+// flat beyond the ends. Inputs have to be strictly increasing; TerrainGraph::validate rejects curves whose aren't.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Curve {
+    pub points: Vec<(f32, f32)>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+impl Curve {
+    // Slope of the spline at every point.
+    fn tangents(&self) -> Vec<f32> {
+        let points = &self.points;
+        let n = points.len();
+        let secants: Vec<f32> = points
+            .windows(2)
+            .map(|pair| {
+                let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+                if x1 > x0 { (y1 - y0) / (x1 - x0) } else { 0.0 }
+            })
+            .collect();
+
+        let mut tangents = vec![0.0; n];
+        if n < 2 {
+            return tangents;
+        }
+        tangents[0] = secants[0];
+        tangents[n - 1] = secants[n - 2];
+        for i in 1..(n - 1) {
+            // Flat at peaks and valleys, otherwise the average of the neighboring secants.
+            tangents[i] = if secants[i - 1] * secants[i] <= 0.0 { 0.0 } else { (secants[i - 1] + secants[i]) * 0.5 };
+        }
+        // Limit the tangents so that no segment overshoots.
+        for i in 0..(n - 1) {
+            if secants[i] == 0.0 {
+                tangents[i] = 0.0;
+                tangents[i + 1] = 0.0;
+                continue;
+            }
+            let a = tangents[i] / secants[i];
+            let b = tangents[i + 1] / secants[i];
+            let length = (a * a + b * b).sqrt();
+            if length > 3.0 {
+                tangents[i] = 3.0 * a / length * secants[i];
+                tangents[i + 1] = 3.0 * b / length * secants[i];
+            }
+        }
+        tangents
+    }
+
+    pub fn evaluate(&self, t: f32) -> f32 {
+        let tangents = match self.interpolation {
+            Interpolation::Linear => vec![],
+            Interpolation::Spline => self.tangents(),
+        };
+        self.evaluate_with(t, &tangents)
+    }
+
+    // `tangents` is empty for linear interpolation.
+    fn evaluate_with(&self, t: f32, tangents: &[f32]) -> f32 {
+        assert!(!self.points.is_empty(), "curve needs at least one point");
+        let points = &self.points;
+        if t <= points[0].0 {
+            return points[0].1;
+        }
+        for (i, pair) in points.windows(2).enumerate() {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if t > x1 {
+                continue;
+            }
+            if x1 <= x0 {
+                return y1;
+            }
+            let width = x1 - x0;
+            let u = (t - x0) / width;
+            if tangents.is_empty() {
+                return y0 + (y1 - y0) * u;
+            }
+            // Cubic Hermite basis.
+            let (u2, u3) = (u * u, u * u * u);
+            return (2.0 * u3 - 3.0 * u2 + 1.0) * y0 +
+                (u3 - 2.0 * u2 + u) * width * tangents[i] +
+                (-2.0 * u3 + 3.0 * u2) * y1 +
+                (u3 - u2) * width * tangents[i + 1];
+        }
+        points[points.len() - 1].1
+    }
+}
+
+impl Filter for Curve {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        let tangents = match self.interpolation {
+            Interpolation::Linear => vec![],
+            Interpolation::Spline => self.tangents(),
+        };
+        remap_normalized(heightmap, |t| self.evaluate_with(t, &tangents));
+    }
+}
+
+// Raises normalized heights to `exponent`: above 1 pushes the terrain down into wide valleys with a few sharp peaks,
+// below 1 lifts it into broad plateaus.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Power {
+    pub exponent: f32,
+}
+
+impl Filter for Power {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        assert!(self.exponent > 0.0, "power exponent has to be positive, got {}", self.exponent);
+        remap_normalized(heightmap, |t| t.powf(self.exponent));
+    }
+}
+
+// Stretches the heights to run exactly from `min` to `max`. Flat terrain ends up at `min`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Normalize {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Filter for Normalize {
+    fn apply(&self, heightmap: &mut Heightmap) {
+        let (low, high) = heightmap.range();
+        for h in heightmap.heights_mut() {
+            let t = if high > low { (*h - low) / (high - low) } else { 0.0 };
+            *h = lerp(self.min, self.max, t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Heights from -3 to 5 along x, so that every filter sees the whole [0, 1] range in order.
+    fn ramp() -> Heightmap {
+        Heightmap::from_fn(801, 2, 1.0, |x, _| x as f32 / 100.0 - 3.0)
+    }
+
+    fn normalized(heightmap: &Heightmap) -> Vec<f32> {
+        let (min, max) = heightmap.range();
+        heightmap.heights()[..heightmap.width()].iter().map(|&h| (h - min) / (max - min)).collect()
+    }
+
+    #[test]
+    fn terrace_without_sharpness_changes_nothing() {
+        let mut heightmap = ramp();
+        Terrace { steps: 5, sharpness: 0.0 }.apply(&mut heightmap);
+        for (&expected, &actual) in ramp().heights().iter().zip(heightmap.heights()) {
+            assert!((expected - actual).abs() < 1e-5, "expected {}, got {}", expected, actual);
+        }
+    }
+
+    #[test]
+    fn sharp_terraces_are_flat() {
+        let mut heightmap = ramp();
+        Terrace { steps: 4, sharpness: 1.0 }.apply(&mut heightmap);
+        for (t, actual) in normalized(&ramp()).into_iter().zip(normalized(&heightmap)) {
+            // Over the lower half of each step, the 16th power leaves less than 1e-4 of its rise.
+            let step = (t * 4.0).floor().min(3.0);
+            if t * 4.0 - step <= 0.5 {
+                assert!((actual - step / 4.0).abs() < 1e-4 / 4.0, "{} became {}, off step {}", t, actual, step);
+            }
+        }
+    }
+
+    #[test]
+    fn splines_stay_between_their_points_and_are_flat_past_the_ends() {
+        let curve = Curve {
+            points: vec![(0.2, 0.1), (0.3, 0.8), (0.5, 0.8), (0.6, 0.85), (0.9, 1.0)],
+            interpolation: Interpolation::Spline,
+        };
+        assert_eq!(curve.evaluate(0.0), 0.1);
+        assert_eq!(curve.evaluate(0.15), 0.1);
+        assert_eq!(curve.evaluate(0.95), 1.0);
+        assert_eq!(curve.evaluate(1.0), 1.0);
+
+        // Outputs only ever rise, so the curve has to as well, without overshooting the flat stretch from 0.3 to 0.5.
+        let mut last = curve.evaluate(0.2);
+        for i in 0..1001 {
+            let t = 0.2 + 0.7 * i as f32 / 1000.0;
+            let value = curve.evaluate(t);
+            assert!(value >= last - 1e-6, "fell from {} to {} at {}", last, value, t);
+            assert!(value >= 0.1 - 1e-6 && value <= 1.0 + 1e-6, "{} at {} is outside the points", value, t);
+            if t >= 0.3 && t <= 0.5 {
+                assert!((value - 0.8).abs() < 1e-6, "{} at {} overshoots the flat stretch", value, t);
+            }
+            last = value;
+        }
+    }
+
+    #[test]
+    fn normalize_hits_its_range_exactly() {
+        for &(min, max) in [(0.0, 1.0), (-7.3, 12.9), (100.1, 100.2)].iter() {
+            let mut heightmap = ramp();
+            Normalize { min: min, max: max }.apply(&mut heightmap);
+            assert_eq!(heightmap.range(), (min, max));
+        }
+    }
+
+    #[test]
+    fn power_keeps_the_lowest_and_highest_points() {
+        for &exponent in [0.3, 1.0, 2.5].iter() {
+            let mut heightmap = ramp();
+            Power { exponent: exponent }.apply(&mut heightmap);
+            assert_eq!(heightmap.range(), ramp().range());
+            assert_eq!(heightmap.get(0, 0), -3.0);
+            assert_eq!(heightmap.get(800, 1), 5.0);
+        }
+    }
+}
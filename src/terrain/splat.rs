@@ -0,0 +1,243 @@
+use gl;
+use gl::types::*;
+use glm;
+use image;
+use wavefront_obj::mtl;
+
+use objects;
+use shaders;
+use terrain::Heightmap;
+use terrain::mesh;
+use terrain::noise::{ Fractal, FractalSettings, NoiseFn };
+use terrain::random::Rng;
+use util::assert_no_gl_error;
+
+// The splat shader takes its weights as two vec4 attributes.
+pub const MAX_LAYERS: usize = 8;
+
+lazy_static! {
+    // Color comes from the detail textures, so the lighting terms are left neutral.
+    pub static ref SPLAT_MATERIAL: mtl::Material = mtl::Material {
+        name: "splatted terrain".to_owned(),
+        specular_coefficient: 0.0,
+        color_ambient: mtl::Color { r: 0.2, g: 0.2, b: 0.2 },
+        color_diffuse: mtl::Color { r: 1.0, g: 1.0, b: 1.0 },
+        color_specular: mtl::Color { r: 0.0, g: 0.0, b: 0.0 },
+        color_emissive: Option::None,
+        optical_density: Option::None,
+        alpha: 1.0,
+        illumination: mtl::Illumination::AmbientDiffuse,
+        uv_map: Option::None,
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct SplatLayer {
+    pub name: String,
+    // The procedural detail texture blends between these two colors.
+    pub dark: (f32, f32, f32),
+    pub light: (f32, f32, f32),
+    // Of the texture's noise, in cycles per texture.
+    pub grain: f64,
+    // Where the layer shows up, as elevation between the lowest (0) and highest (1) point, and slope in degrees.
+    pub elevation: (f32, f32),
+    pub slope: (f32, f32),
+}
+
+#[derive(Debug, Clone)]
+pub struct SplatSettings {
+    pub seed: u64,
+    // At most MAX_LAYERS.
+    pub layers: Vec<SplatLayer>,
+    // How far past the ends of its ranges each layer fades out.
+    pub elevation_blend: f32,
+    pub slope_blend: f32,
+    // Side length of each detail texture in pixels.
+    pub texture_size: u32,
+    // Detail texture repeats per world unit.
+    pub detail_scale: f32,
+}
+
+fn layer(
+    name: &str,
+    dark: (f32, f32, f32),
+    light: (f32, f32, f32),
+    grain: f64,
+    elevation: (f32, f32),
+    slope: (f32, f32)
+) -> SplatLayer {
+    SplatLayer {
+        name: name.to_owned(),
+        dark: dark,
+        light: light,
+        grain: grain,
+        elevation: elevation,
+        slope: slope,
+    }
+}
+
+impl Default for SplatSettings {
+    fn default() -> SplatSettings {
+        SplatSettings {
+            seed: 0,
+            layers: vec![
+                layer("grass", (0.18, 0.32, 0.1), (0.35, 0.52, 0.2), 16.0, (0.0, 0.65), (0.0, 28.0)),
+                layer("dirt", (0.3, 0.22, 0.14), (0.45, 0.35, 0.22), 24.0, (0.0, 0.75), (22.0, 38.0)),
+                layer("rock", (0.28, 0.27, 0.26), (0.5, 0.48, 0.45), 8.0, (0.0, 1.0), (34.0, 90.0)),
+                layer("snow", (0.78, 0.8, 0.85), (0.95, 0.95, 0.97), 4.0, (0.7, 1.0), (0.0, 40.0)),
+            ],
+            elevation_blend: 0.05,
+            slope_blend: 4.0,
+            texture_size: 256,
+            detail_scale: 0.25,
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// 1 inside the range, fading to 0 over `blend` on either side.
+fn membership(value: f32, range: (f32, f32), blend: f32) -> f32 {
+    smoothstep(range.0 - blend, range.0, value) * (1.0 - smoothstep(range.1, range.1 + blend, value))
+}
+
+// One weight per layer for every vertex, in the same row-major order as the heightmap. Weights sum to 1; vertices no
+// layer claims go entirely to the first.
+pub fn splat_weights(heightmap: &Heightmap, settings: &SplatSettings) -> Vec<[f32; MAX_LAYERS]> {
+    assert!(!settings.layers.is_empty() && settings.layers.len() <= MAX_LAYERS,
+        "need between 1 and {} splat layers, got {}", MAX_LAYERS, settings.layers.len());
+
+    let (min, max) = heightmap.range();
+    let mut weights = Vec::with_capacity(heightmap.width() * heightmap.depth());
+    for z in 0..heightmap.depth() {
+        for x in 0..heightmap.width() {
+            let elevation = if max > min { (heightmap.get(x, z) - min) / (max - min) } else { 0.0 };
+            let slope = mesh::vertex_normal(heightmap, x, z).y.max(-1.0).min(1.0).acos().to_degrees();
+
+            let mut w = [0.0; MAX_LAYERS];
+            for (i, layer) in settings.layers.iter().enumerate() {
+                w[i] = membership(elevation, layer.elevation, settings.elevation_blend) *
+                    membership(slope, layer.slope, settings.slope_blend);
+            }
+            let total: f32 = w.iter().sum();
+            if total > 0.0 {
+                for value in w.iter_mut() {
+                    *value /= total;
+                }
+            } else {
+                w[0] = 1.0;
+            }
+            weights.push(w);
+        }
+    }
+    weights
+}
+
+// Tileable noise: four samples a texture's width apart, blended so opposite edges agree.
+fn tileable_sample(noise: &Fractal, x: f64, y: f64, size: f64) -> f64 {
+    let (fx, fy) = (x / size, y / size);
+    noise.sample(x, y) * (1.0 - fx) * (1.0 - fy) +
+        noise.sample(x - size, y) * fx * (1.0 - fy) +
+        noise.sample(x, y - size) * (1.0 - fx) * fy +
+        noise.sample(x - size, y - size) * fx * fy
+}
+
+// We don't ship any artwork, so detail textures are generated from noise.
+pub fn layer_texture(layer: &SplatLayer, size: u32, seed: u64) -> image::RgbImage {
+    let noise = Fractal::new(FractalSettings {
+        seed: seed,
+        octaves: 4,
+        frequency: layer.grain / size as f64,
+        ..Default::default()
+    });
+
+    image::ImageBuffer::from_fn(size, size, |x, y| {
+        let t = (0.5 + 0.5 * tileable_sample(&noise, x as f64, y as f64, size as f64)).max(0.0).min(1.0) as f32;
+        let channel = |dark: f32, light: f32| ((dark + (light - dark) * t) * 255.0).round() as u8;
+        image::Rgb([
+            channel(layer.dark.0, layer.light.0),
+            channel(layer.dark.1, layer.light.1),
+            channel(layer.dark.2, layer.light.2),
+        ])
+    })
+}
+
+// Draws a heightmap with the splat shaders instead of the phong ones: per-vertex weights pick between tiled detail
+// textures stored in one texture array.
+pub struct Splatting<'a> {
+    program: &'a shaders::Program,
+    settings: SplatSettings,
+    texture_array: Option<GLuint>,
+}
+
+impl <'a> Splatting<'a> {
+    pub fn new(program: &'a shaders::Program, settings: SplatSettings) -> Splatting<'a> {
+        Splatting {
+            program: program,
+            settings: settings,
+            texture_array: Option::None,
+        }
+    }
+
+    pub fn settings(&self) -> &SplatSettings {
+        &self.settings
+    }
+
+    pub fn upload(&mut self, heightmap: &Heightmap) -> objects::LoadedMesh {
+        if self.texture_array.is_none() {
+            let images: Vec<image::RgbImage> = self.settings.layers
+                .iter()
+                .enumerate()
+                .map(|(i, layer)| {
+                    let seed = Rng::derive(self.settings.seed, i as u64).next_u64();
+                    layer_texture(layer, self.settings.texture_size, seed)
+                })
+                .collect();
+            self.texture_array = Some(objects::create_texture_array(&images));
+        }
+
+        let weights = splat_weights(heightmap, &self.settings);
+        let low: Vec<glm::Vec4> = weights.iter().map(|w| glm::vec4(w[0], w[1], w[2], w[3])).collect();
+        let high: Vec<glm::Vec4> = weights.iter().map(|w| glm::vec4(w[4], w[5], w[6], w[7])).collect();
+
+        objects::upload_mesh_with_attributes(
+            self.program,
+            mesh::build_mesh_data(heightmap, &SPLAT_MATERIAL),
+            objects::white_texture(),
+            vec![("in_SplatWeights0", low), ("in_SplatWeights1", high)])
+    }
+
+    pub fn draw(
+        &self,
+        mesh: &objects::LoadedMesh,
+        model: glm::Mat4,
+        view: glm::Mat4,
+        projection: glm::Mat4,
+        light: &objects::Light
+    ) {
+        objects::use_program(self.program, model, view, projection, light);
+        unsafe {
+            gl::Uniform1f(self.program.get_uniform("u_DetailScale"), self.settings.detail_scale);
+            gl::Uniform1i(self.program.get_uniform("u_LayerCount"), self.settings.layers.len() as GLint);
+            gl::ActiveTexture(gl::TEXTURE1);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.texture_array.expect("splat textures weren't uploaded"));
+            gl::Uniform1i(self.program.get_uniform("u_DetailTextures"), 1);
+            assert_no_gl_error();
+        }
+        objects::draw_mesh(self.program, mesh);
+    }
+}
+
+impl <'a> Drop for Splatting<'a> {
+    fn drop(&mut self) {
+        if let Some(texture_array) = self.texture_array.take() {
+            unsafe {
+                gl::DeleteTextures(1, &texture_array);
+                assert_no_gl_error();
+            }
+        }
+    }
+}
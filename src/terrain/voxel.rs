@@ -0,0 +1,527 @@
+use glm;
+use num_traits::identities::One;
+use wavefront_obj::mtl;
+
+use objects;
+use shaders;
+use terrain::TERRAIN_MATERIAL;
+use terrain::noise::{ Fractal, FractalKind, FractalSettings, NoiseFn, NoiseFn3 };
+use terrain::random::Rng;
+
+// Terrain as a density field rather than a heightfield, so it can have caves, arches and overhangs. Positive density
+// is solid, negative is air, and the surface is wherever it crosses zero. Densities are roughly the distance to the
+// surface in world units, which keeps their gradients (and so the normals) well behaved where shapes meet.
+
+// Sample (x, y, z) sits at (x, y, z) * spacing in the field's local space, so the dimensions count samples, not cells.
+#[derive(Debug, Clone)]
+pub struct DensityField {
+    width: usize,
+    height: usize,
+    depth: usize,
+    spacing: f32,
+    values: Vec<f32>,
+}
+
+impl DensityField {
+    pub fn from_fn<F: Fn(glm::Vec3) -> f32>(
+        width: usize,
+        height: usize,
+        depth: usize,
+        spacing: f32,
+        f: F
+    ) -> DensityField {
+        assert!(width > 1 && height > 1 && depth > 1, "a density field needs at least two samples along each axis");
+        let mut values = Vec::with_capacity(width * height * depth);
+        for z in 0..depth {
+            for y in 0..height {
+                for x in 0..width {
+                    values.push(f(glm::vec3(x as f32 * spacing, y as f32 * spacing, z as f32 * spacing)));
+                }
+            }
+        }
+        DensityField {
+            width: width,
+            height: height,
+            depth: depth,
+            spacing: spacing,
+            values: values,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn spacing(&self) -> f32 {
+        self.spacing
+    }
+
+    // Size of the volume in world units.
+    pub fn extent(&self) -> glm::Vec3 {
+        glm::vec3(
+            (self.width - 1) as f32 * self.spacing,
+            (self.height - 1) as f32 * self.spacing,
+            (self.depth - 1) as f32 * self.spacing)
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[(z * self.height + y) * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, density: f32) {
+        let i = (z * self.height + y) * self.width + x;
+        self.values[i] = density;
+    }
+
+    // Everything outside the field counts as air, so that meshes come out closed where the solid meets its edges.
+    fn padded_get(&self, x: isize, y: isize, z: isize) -> f32 {
+        let outside = x < 0 || y < 0 || z < 0
+            || x >= self.width as isize || y >= self.height as isize || z >= self.depth as isize;
+        if outside {
+            -self.spacing
+        } else {
+            self.get(x as usize, y as usize, z as usize)
+        }
+    }
+
+    // Central differences, pointing toward increasing density (into the solid).
+    fn gradient(&self, x: isize, y: isize, z: isize) -> glm::Vec3 {
+        glm::vec3(
+            self.padded_get(x + 1, y, z) - self.padded_get(x - 1, y, z),
+            self.padded_get(x, y + 1, z) - self.padded_get(x, y - 1, z),
+            self.padded_get(x, y, z + 1) - self.padded_get(x, y, z - 1)) / (2.0 * self.spacing)
+    }
+
+    // The outward surface normal at a point in local units: the density gradient, reversed, blended trilinearly from
+    // the samples around the point.
+    pub fn normal_at(&self, position: glm::Vec3) -> glm::Vec3 {
+        let grid = position / self.spacing;
+        let (x0, y0, z0) = (grid.x.floor(), grid.y.floor(), grid.z.floor());
+        let (fx, fy, fz) = (grid.x - x0, grid.y - y0, grid.z - z0);
+        let (x0, y0, z0) = (x0 as isize, y0 as isize, z0 as isize);
+
+        let mut gradient = glm::vec3(0.0, 0.0, 0.0);
+        for corner in 0..8 {
+            let (ox, oy, oz) = (corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+            let weight = (if ox == 1 { fx } else { 1.0 - fx })
+                * (if oy == 1 { fy } else { 1.0 - fy })
+                * (if oz == 1 { fz } else { 1.0 - fz });
+            gradient = gradient + self.gradient(x0 + ox, y0 + oy, z0 + oz) * weight;
+        }
+        if glm::length(gradient) > 0.0 {
+            glm::normalize(gradient) * -1.0
+        } else {
+            glm::vec3(0.0, 1.0, 0.0)
+        }
+    }
+
+    pub fn apply(&mut self, operation: &Csg) {
+        for z in 0..self.depth {
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    let position = glm::vec3(x as f32, y as f32, z as f32) * self.spacing;
+                    let shape = operation.shape().density(position);
+                    let density = self.get(x, y, z);
+                    self.set(x, y, z, match *operation {
+                        Csg::Union(_) => density.max(shape),
+                        Csg::Subtract(_) => density.min(-shape),
+                        Csg::Intersect(_) => density.min(shape),
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Solids for CSG, in the field's local space.
+#[derive(Debug, Clone, Copy)]
+pub enum Shape {
+    Sphere { center: glm::Vec3, radius: f32 },
+    Box { min: glm::Vec3, max: glm::Vec3 },
+    // A cylinder with rounded ends, from `start` to `end`; good for tunnels.
+    Capsule { start: glm::Vec3, end: glm::Vec3, radius: f32 },
+}
+
+impl Shape {
+    // The signed distance to the surface, positive inside, like the field's densities.
+    pub fn density(&self, position: glm::Vec3) -> f32 {
+        match *self {
+            Shape::Sphere { center, radius } => radius - glm::length(position - center),
+            Shape::Box { min, max } => {
+                let center = (min + max) * 0.5;
+                let half = (max - min) * 0.5;
+                // Per axis, how far outside the box's slab the point is (negative inside).
+                let q = glm::vec3(
+                    (position.x - center.x).abs() - half.x,
+                    (position.y - center.y).abs() - half.y,
+                    (position.z - center.z).abs() - half.z);
+                let outside = glm::length(glm::vec3(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)));
+                let inside = q.x.max(q.y).max(q.z).min(0.0);
+                -(outside + inside)
+            },
+            Shape::Capsule { start, end, radius } => {
+                let axis = end - start;
+                let length_squared = glm::dot(axis, axis);
+                let t = if length_squared > 0.0 {
+                    (glm::dot(position - start, axis) / length_squared).max(0.0).min(1.0)
+                } else {
+                    0.0
+                };
+                radius - glm::length(position - (start + axis * t))
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Csg {
+    // Adds the shape to the solid.
+    Union(Shape),
+    // Carves the shape out of the solid.
+    Subtract(Shape),
+    // Keeps only the solid inside the shape.
+    Intersect(Shape),
+}
+
+impl Csg {
+    fn shape(&self) -> &Shape {
+        match *self {
+            Csg::Union(ref shape) | Csg::Subtract(ref shape) | Csg::Intersect(ref shape) => shape,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VoxelSettings {
+    pub seed: u64,
+    // Samples along each axis.
+    pub width: usize,
+    pub height: usize,
+    pub depth: usize,
+    pub spacing: f32,
+    // The rolling ground underneath everything: its height above the bottom of the volume, and how far noise moves it
+    // up and down.
+    pub ground_level: f32,
+    pub ground_amplitude: f32,
+    pub ground: FractalSettings,
+    // 3D noise added to the ground's density, which pushes out overhangs and leaves floating rocks and arches.
+    pub overhang_amplitude: f32,
+    pub overhang: FractalSettings,
+    // Caves run where two 3D noise fields are both within `cave_width` of zero. Each is near zero on a winding sheet,
+    // and the sheets cross along winding tunnels.
+    pub caves: FractalSettings,
+    pub cave_width: f32,
+}
+
+impl Default for VoxelSettings {
+    fn default() -> VoxelSettings {
+        VoxelSettings {
+            seed: 0,
+            width: 65,
+            height: 33,
+            depth: 65,
+            spacing: 0.5,
+            ground_level: 9.0,
+            ground_amplitude: 4.0,
+            ground: FractalSettings {
+                octaves: 4,
+                frequency: 0.04,
+                ..Default::default()
+            },
+            overhang_amplitude: 3.0,
+            overhang: FractalSettings {
+                octaves: 3,
+                frequency: 0.08,
+                ..Default::default()
+            },
+            caves: FractalSettings {
+                octaves: 2,
+                frequency: 0.06,
+                kind: FractalKind::Fbm,
+                ..Default::default()
+            },
+            cave_width: 0.12,
+        }
+    }
+}
+
+pub fn generate(settings: &VoxelSettings) -> DensityField {
+    // Every layer gets its own seed, so that none of them line up.
+    let seeded = |fractal: &FractalSettings, stream: u64| Fractal::new(FractalSettings {
+        seed: Rng::derive(settings.seed, stream).next_u64(),
+        ..fractal.clone()
+    });
+    let ground = seeded(&settings.ground, 0);
+    let overhang = seeded(&settings.overhang, 1);
+    let caves = (seeded(&settings.caves, 2), seeded(&settings.caves, 3));
+    // Noise changes by about one per wavelength, so scaling by the wavelength puts the caves in roughly world units.
+    let cave_scale = (1.0 / settings.caves.frequency) as f32;
+
+    DensityField::from_fn(settings.width, settings.height, settings.depth, settings.spacing, |p| {
+        let (x, y, z) = (p.x as f64, p.y as f64, p.z as f64);
+        let surface = settings.ground_level + ground.sample(x, z) as f32 * settings.ground_amplitude;
+        let solid = surface - p.y + overhang.sample_3d(x, y, z) as f32 * settings.overhang_amplitude;
+        let tunnel = caves.0.sample_3d(x, y, z).abs().max(caves.1.sample_3d(x, y, z).abs()) as f32;
+        solid.min((tunnel - settings.cave_width) * cave_scale)
+    })
+}
+
+// Polygonises the field with surface nets, a simple form of dual contouring: one vertex per cell the surface passes
+// through, at the average of where it crosses the cell's edges, and one quad for every sample edge it crosses, joining
+// the four cells around that edge. Normals come from the density gradient. Unlike marching cubes, this needs no case
+// tables and shares every vertex between the faces around it.
+pub fn build_mesh_data(field: &DensityField, material: &mtl::Material) -> objects::MeshData {
+    // The field is padded with a layer of air on every side (see padded_get), so cells run from -1 to the last sample.
+    let (cells_x, cells_y, cells_z) = (field.width + 1, field.height + 1, field.depth + 1);
+    let cell_index = |x: usize, y: usize, z: usize| (z * cells_y + y) * cells_x + x;
+    let sample = |x: usize, y: usize, z: usize| field.padded_get(x as isize - 1, y as isize - 1, z as isize - 1);
+
+    let mut positions = vec![];
+    let mut vertex_of_cell = vec![None; cells_x * cells_y * cells_z];
+    for z in 0..cells_z {
+        for y in 0..cells_y {
+            for x in 0..cells_x {
+                let corners: Vec<f32> = (0..8)
+                    .map(|corner| sample(x + (corner & 1), y + ((corner >> 1) & 1), z + ((corner >> 2) & 1)))
+                    .collect();
+                let solid = corners.iter().filter(|&&density| density > 0.0).count();
+                if solid == 0 || solid == 8 {
+                    continue;
+                }
+
+                let mut total = glm::vec3(0.0, 0.0, 0.0);
+                let mut crossings = 0.0;
+                for &(a, b) in CELL_EDGES.iter() {
+                    let (da, db) = (corners[a], corners[b]);
+                    if (da > 0.0) == (db > 0.0) {
+                        continue;
+                    }
+                    let t = da / (da - db);
+                    total = total + corner_offset(a) * (1.0 - t) + corner_offset(b) * t;
+                    crossings += 1.0;
+                }
+                // Less one for the padding.
+                let cell = glm::vec3(x as f32 - 1.0, y as f32 - 1.0, z as f32 - 1.0);
+                vertex_of_cell[cell_index(x, y, z)] = Some(positions.len() as u32);
+                positions.push((cell + total / crossings) * field.spacing);
+            }
+        }
+    }
+
+    // Each sample edge the surface crosses is surrounded by four cells; with the edge along axis a and the other two
+    // axes b and c in right-handed order, going (b, c) = (-1, -1), (0, -1), (0, 0), (-1, 0) winds counterclockwise
+    // seen from +a. That faces +a, which is outward when the solid is at the low end of the edge.
+    let mut indices = vec![];
+    let limits = [cells_x, cells_y, cells_z];
+    for z in 0..(cells_z + 1) {
+        for y in 0..(cells_y + 1) {
+            for x in 0..(cells_x + 1) {
+                let here = [x, y, z];
+                let solid_here = sample(x, y, z) > 0.0;
+                for axis in 0..3 {
+                    let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+                    // Edges along the padding's outer faces have no cells on one side, but are all air anyway.
+                    if here[axis] == limits[axis] || here[b] == 0 || here[b] == limits[b]
+                        || here[c] == 0 || here[c] == limits[c] {
+                        continue;
+                    }
+                    let mut next = here;
+                    next[axis] += 1;
+                    if solid_here == (sample(next[0], next[1], next[2]) > 0.0) {
+                        continue;
+                    }
+
+                    let cell = |db: usize, dc: usize| {
+                        let mut coordinates = here;
+                        coordinates[b] -= db;
+                        coordinates[c] -= dc;
+                        vertex_of_cell[cell_index(coordinates[0], coordinates[1], coordinates[2])]
+                    };
+                    let quad = match (cell(1, 1), cell(0, 1), cell(0, 0), cell(1, 0)) {
+                        (Some(v0), Some(v1), Some(v2), Some(v3)) => [v0, v1, v2, v3],
+                        _ => continue,
+                    };
+                    let quad = if solid_here { quad } else { [quad[0], quad[3], quad[2], quad[1]] };
+                    indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+                }
+            }
+        }
+    }
+
+    let extent = field.extent();
+    let normals = positions.iter().map(|&position| field.normal_at(position)).collect();
+    let uvs = positions.iter().map(|position| glm::vec2(position.x / extent.x, position.z / extent.z)).collect();
+    objects::MeshData::with_material(positions, normals, uvs, indices, material)
+}
+
+// The cube's corners are numbered by their offsets as bits: x is 1, y is 2 and z is 4.
+const CELL_EDGES: [(usize, usize); 12] = [
+    (0, 1), (2, 3), (4, 5), (6, 7),
+    (0, 2), (1, 3), (4, 6), (5, 7),
+    (0, 4), (1, 5), (2, 6), (3, 7),
+];
+
+fn corner_offset(corner: usize) -> glm::Vec3 {
+    glm::vec3((corner & 1) as f32, ((corner >> 1) & 1) as f32, ((corner >> 2) & 1) as f32)
+}
+
+// Where the surface faces less upward than this, it's drawn as bare rock: cliffs, overhangs and cave ceilings.
+const GRASS_MIN_NORMAL_Y: f32 = 0.6;
+const ROCK_COLOR: mtl::Color = mtl::Color { r: 0.4, g: 0.37, b: 0.33 };
+
+pub struct RenderableVoxels<'a> {
+    field: DensityField,
+    origin: glm::Vec3,
+    program: &'a shaders::Program,
+    mesh: Option<objects::LoadedMesh>,
+}
+
+impl <'a> RenderableVoxels<'a> {
+    pub fn new(field: DensityField, origin: glm::Vec3, program: &'a shaders::Program) -> RenderableVoxels<'a> {
+        RenderableVoxels {
+            field: field,
+            origin: origin,
+            program: program,
+            mesh: Option::None,
+        }
+    }
+
+    // Centered on the origin horizontally, with the bottom of the volume at y = 0.
+    pub fn centered(field: DensityField, program: &'a shaders::Program) -> RenderableVoxels<'a> {
+        let extent = field.extent();
+        RenderableVoxels::new(field, glm::vec3(-extent.x / 2.0, 0.0, -extent.z / 2.0), program)
+    }
+
+    fn light(&self) -> objects::Light {
+        let extent = self.field.extent();
+        let light_height = extent.y + extent.x.max(extent.z);
+        objects::Light {
+            position: self.origin + glm::vec3(extent.x / 2.0, light_height, extent.z / 2.0),
+            color: glm::vec3(1.0, 1.0, 1.0),
+            power: light_height * light_height * 1.5,
+        }
+    }
+}
+
+impl <'a> objects::Renderable for RenderableVoxels<'a> {
+    fn render(&mut self, view: glm::Mat4, projection: glm::Mat4) {
+        if self.mesh.is_none() {
+            let mut data = build_mesh_data(&self.field, &TERRAIN_MATERIAL);
+            data.colors_diffuse = data.normals
+                .iter()
+                .map(|normal| if normal.y >= GRASS_MIN_NORMAL_Y { TERRAIN_MATERIAL.color_diffuse } else { ROCK_COLOR })
+                .collect();
+            info!("meshed density field into {} vertices and {} triangles",
+                  data.positions.len(), data.indices.len() / 3);
+            self.mesh = Some(objects::upload_mesh(self.program, data, objects::white_texture()));
+        }
+
+        let model = glm::ext::translate(&glm::Mat4::one(), self.origin);
+        objects::use_program(self.program, model, view, projection, &self.light());
+        objects::draw_mesh(self.program, self.mesh.as_ref().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const EPSILON: f32 = 1e-5;
+
+    #[test]
+    fn shape_densities_are_positive_inside_and_negative_outside() {
+        let shapes = [
+            Shape::Sphere { center: glm::vec3(1.0, 2.0, 3.0), radius: 2.0 },
+            Shape::Box { min: glm::vec3(-1.0, 0.0, 1.0), max: glm::vec3(3.0, 4.0, 5.0) },
+            Shape::Capsule { start: glm::vec3(1.0, 2.0, 1.0), end: glm::vec3(1.0, 2.0, 5.0), radius: 2.0 },
+        ];
+        // Every shape contains (1, 2, 3), has (3, 2, 3) on its surface and leaves (1, 2, 8) outside.
+        for shape in shapes.iter() {
+            assert!(shape.density(glm::vec3(1.0, 2.0, 3.0)) > 0.0, "{:?} should contain its middle", shape);
+            assert!(shape.density(glm::vec3(3.0, 2.0, 3.0)).abs() < EPSILON, "{:?} should have a surface point", shape);
+            assert!(shape.density(glm::vec3(1.0, 2.0, 8.0)) < 0.0, "{:?} should leave far points outside", shape);
+        }
+    }
+
+    #[test]
+    fn shape_densities_are_distances() {
+        let sphere = Shape::Sphere { center: glm::vec3(0.0, 0.0, 0.0), radius: 2.0 };
+        assert!((sphere.density(glm::vec3(0.0, 0.5, 0.0)) - 1.5).abs() < EPSILON);
+        assert!((sphere.density(glm::vec3(0.0, 0.0, -5.0)) + 3.0).abs() < EPSILON);
+
+        // Inside a box, the distance to the nearest face; outside a corner, the distance to the corner.
+        let cube = Shape::Box { min: glm::vec3(0.0, 0.0, 0.0), max: glm::vec3(4.0, 4.0, 4.0) };
+        assert!((cube.density(glm::vec3(1.0, 2.0, 2.0)) - 1.0).abs() < EPSILON);
+        assert!((cube.density(glm::vec3(7.0, 8.0, 4.0)) + 5.0).abs() < EPSILON);
+
+        // Past the ends, a capsule is rounded like a sphere.
+        let capsule = Shape::Capsule { start: glm::vec3(0.0, 0.0, 0.0), end: glm::vec3(4.0, 0.0, 0.0), radius: 1.0 };
+        assert!((capsule.density(glm::vec3(2.0, 0.5, 0.0)) - 0.5).abs() < EPSILON);
+        assert!((capsule.density(glm::vec3(7.0, 0.0, 0.0)) + 2.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn csg_combines_densities() {
+        let sphere = Shape::Sphere { center: glm::vec3(2.0, 2.0, 2.0), radius: 1.5 };
+
+        let mut union = DensityField::from_fn(5, 5, 5, 1.0, |_| -1.0);
+        union.apply(&Csg::Union(sphere));
+        assert!(union.get(2, 2, 2) > 0.0);
+        assert!(union.get(0, 0, 0) < 0.0);
+
+        let mut subtracted = DensityField::from_fn(5, 5, 5, 1.0, |_| 1.0);
+        subtracted.apply(&Csg::Subtract(sphere));
+        assert!(subtracted.get(2, 2, 2) < 0.0);
+        assert!(subtracted.get(0, 0, 0) > 0.0);
+
+        let mut intersected = DensityField::from_fn(5, 5, 5, 1.0, |_| 1.0);
+        intersected.apply(&Csg::Intersect(sphere));
+        assert!(intersected.get(2, 2, 2) > 0.0);
+        assert!(intersected.get(0, 0, 0) < 0.0);
+    }
+
+    #[test]
+    fn sphere_meshes_into_a_closed_surface_facing_out() {
+        let center = glm::vec3(4.0, 4.0, 4.0);
+        let sphere = Shape::Sphere { center: center, radius: 2.7 };
+        let field = DensityField::from_fn(17, 17, 17, 0.5, |p| sphere.density(p));
+        let mesh = build_mesh_data(&field, &TERRAIN_MATERIAL);
+        assert!(!mesh.indices.is_empty());
+
+        // On a closed surface with consistent winding, every edge belongs to exactly two triangles, which run along
+        // it in opposite directions.
+        let mut edges = HashMap::new();
+        for triangle in mesh.indices.chunks(3) {
+            for i in 0..3 {
+                *edges.entry((triangle[i], triangle[(i + 1) % 3])).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in edges.iter() {
+            assert_eq!(count, 1, "edge {} -> {} is used {} times", a, b, count);
+            assert!(edges.contains_key(&(b, a)), "edge {} -> {} has no twin, so the surface has a hole", a, b);
+        }
+
+        // Counterclockwise triangles face away from the center, and so do the normals.
+        for triangle in mesh.indices.chunks(3) {
+            let (a, b, c) = (
+                mesh.positions[triangle[0] as usize],
+                mesh.positions[triangle[1] as usize],
+                mesh.positions[triangle[2] as usize]);
+            let facing = glm::cross(b - a, c - a);
+            let outward = (a + b + c) / 3.0 - center;
+            assert!(glm::dot(facing, outward) > 0.0, "triangle {:?} faces inward", triangle);
+        }
+        for (&position, &normal) in mesh.positions.iter().zip(mesh.normals.iter()) {
+            assert!(glm::dot(normal, glm::normalize(position - center)) > 0.9, "normal {:?} at {:?}", normal, position);
+        }
+    }
+}